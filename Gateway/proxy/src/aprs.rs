@@ -0,0 +1,240 @@
+use crate::counter::RainWindow;
+use crate::error::Result;
+use crate::reading::{Quality, Reading};
+use crate::sink::Sink;
+use crate::units::Units;
+use crate::util::{
+    APRS_DESTINATION, APRS_INTERVAL, APRS_SERVER, APRS_SOFTWARE, HTTP_TIMEOUT, RAIN_DAY_WINDOW,
+    RAIN_HOUR_WINDOW,
+};
+use async_std::io::{self, BufReader};
+use async_std::net::TcpStream;
+use async_std::prelude::*;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+/// APRS weather report of one station, CWOP stations without a license use passcode -1.
+///
+/// `latitude` and `longitude` are in decimal degrees, negative to the south and west.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Aprs {
+    pub callsign: String,
+    #[serde(default = "default_aprs_passcode")]
+    pub passcode: i32,
+    pub latitude: f64,
+    pub longitude: f64,
+    #[serde(default)]
+    pub output: AprsOutput,
+    #[serde(default)]
+    pub node: Option<u8>,
+    #[serde(default = "default_aprs_interval")]
+    pub interval: u64,
+}
+
+/// `AprsIs` logs into an APRS-IS server for every report, `File` appends TNC2 formatted
+/// packets for a TNC.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum AprsOutput {
+    AprsIs {
+        #[serde(default = "default_aprs_server")]
+        server: String,
+    },
+    File {
+        path: String,
+    },
+}
+
+impl Default for AprsOutput {
+    fn default() -> Self {
+        AprsOutput::AprsIs {
+            server: default_aprs_server(),
+        }
+    }
+}
+
+fn default_aprs_passcode() -> i32 {
+    -1
+}
+
+fn default_aprs_interval() -> u64 {
+    APRS_INTERVAL
+}
+
+fn default_aprs_server() -> String {
+    APRS_SERVER.to_string()
+}
+
+/// Sends APRS weather reports, at most one per `interval`.
+///
+/// Values the node does not measure are sent as dots, the APRS-IS connection is opened
+/// only for the duration of a report as CWOP recommends.
+pub struct AprsReporter {
+    name: String,
+    conf: Aprs,
+    next_report: Option<Instant>,
+    rain_hour: RainWindow,
+    rain_day: RainWindow,
+}
+
+impl AprsReporter {
+    pub fn new(name: String, conf: &Aprs) -> Self {
+        AprsReporter {
+            name,
+            conf: conf.clone(),
+            next_report: None,
+            rain_hour: RainWindow::new(RAIN_HOUR_WINDOW),
+            rain_day: RainWindow::new(RAIN_DAY_WINDOW),
+        }
+    }
+
+    /// Complete weather report with position and time stamp, without the header.
+    fn report(&self, reading: &Reading, rain_hour: Option<f64>, rain_day: Option<f64>) -> String {
+        let units = Units::imperial();
+        let (speed, gust) = match &reading.wind {
+            Some(wind) => (
+                number(Some(units.convert("wind_speed", wind.speed)), 3),
+                number(Some(units.convert("wind_gust", wind.gust)), 3),
+            ),
+            None => (number(None, 3), number(None, 3)),
+        };
+        let direction = reading
+            .wind_direction
+            .map(|direction| direction.round().rem_euclid(360.));
+        // Rain is reported in hundredths of an inch.
+        let rain = |value: Option<f64>| number(value.map(|mm| mm / 25.4 * 100.), 3);
+        let humidity = reading.humidity.round().clamp(1., 100.) as u32 % 100;
+        format!(
+            "@{}{}/{}_{}/{}g{}t{}r{}p{}P{}h{:02}b{}",
+            reading.timestamp.format("%d%H%Mz"),
+            coordinate(self.conf.latitude, 2, 'N', 'S'),
+            coordinate(self.conf.longitude, 3, 'E', 'W'),
+            number(direction, 3),
+            speed,
+            gust,
+            number(Some(units.convert("temperature", reading.temperature)), 3),
+            rain(rain_hour),
+            rain(rain_day),
+            rain(reading.rain.as_ref().map(|rain| rain.daily)),
+            humidity,
+            number(Some(reading.pressure * 10.), 5)
+        )
+    }
+
+    async fn send(&self, report: &str) -> Result<()> {
+        match &self.conf.output {
+            AprsOutput::AprsIs { server } => {
+                let packet = format!(
+                    "{}>{},TCPIP*:{}\r\n",
+                    self.conf.callsign, APRS_DESTINATION, report
+                );
+                let timeout = Duration::from_secs(HTTP_TIMEOUT);
+                io::timeout(timeout, self.send_aprs_is(server, &packet)).await?;
+            }
+            AprsOutput::File { path } => {
+                let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+                writeln!(
+                    file,
+                    "{}>{}:{}",
+                    self.conf.callsign, APRS_DESTINATION, report
+                )?;
+                file.sync_data()?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn send_aprs_is(&self, server: &str, packet: &str) -> io::Result<()> {
+        let stream = TcpStream::connect(server).await?;
+        let mut lines = BufReader::new(&stream).lines();
+        let login = format!(
+            "user {} pass {} vers {} {}\r\n",
+            self.conf.callsign,
+            self.conf.passcode,
+            APRS_SOFTWARE,
+            env!("CARGO_PKG_VERSION")
+        );
+        (&stream).write_all(login.as_bytes()).await?;
+        // The server confirms the login before it accepts packets.
+        while let Some(line) = lines.next().await {
+            let line = line?;
+            debug!("APRS-IS: {}", line);
+            if line.starts_with("# logresp") {
+                break;
+            }
+        }
+        (&stream).write_all(packet.as_bytes()).await?;
+        (&stream).flush().await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Sink for AprsReporter {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn start(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn publish(&mut self, reading: &Reading) -> Result<()> {
+        match self.conf.node {
+            Some(node) if node != reading.node => return Ok(()),
+            _ => (),
+        }
+        let rain_hour = self.rain_hour.update(reading.rain.as_ref());
+        let rain_day = self.rain_day.update(reading.rain.as_ref());
+        if reading.quality != Quality::Good {
+            return Ok(());
+        }
+        if let Some(next_report) = self.next_report {
+            if Instant::now() < next_report {
+                return Ok(());
+            }
+        }
+
+        self.next_report = Some(Instant::now() + Duration::from_secs(self.conf.interval));
+        let report = self.report(reading, rain_hour, rain_day);
+        debug!("APRS report {}", report);
+        self.send(&report).await
+    }
+
+    async fn shutdown(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Degrees and decimal minutes, `DDMM.mmN` for latitude and `DDDMM.mmE` for longitude.
+fn coordinate(value: f64, degree_digits: usize, positive: char, negative: char) -> String {
+    let hemisphere = if value < 0. { negative } else { positive };
+    let minutes = (value.abs() * 60. * 100.).round() / 100.;
+    let degrees = (minutes / 60.).floor();
+    format!(
+        "{:0width$}{:05.2}{}",
+        degrees as u32,
+        minutes - degrees * 60.,
+        hemisphere,
+        width = degree_digits
+    )
+}
+
+/// Zero padded integer of a fixed width, dots when the value is unknown.
+fn number(value: Option<f64>, width: usize) -> String {
+    match value {
+        Some(value) => {
+            let max = 10_i64.pow(width as u32) - 1;
+            let min = -(10_i64.pow(width as u32 - 1) - 1);
+            format!(
+                "{:0width$}",
+                (value.round() as i64).max(min).min(max),
+                width = width
+            )
+        }
+        None => ".".repeat(width),
+    }
+}
@@ -0,0 +1,244 @@
+use crate::config::Battery;
+use crate::util::{ADC_MAX_VALUE, ADC_REFERENCE, BATTERY_DIVIDER, BATTERY_TREND_WINDOW};
+use std::collections::VecDeque;
+use std::time::Instant;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BatteryLevel {
+    Normal,
+    Low,
+    Critical,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChargeState {
+    Charging,
+    Discharging,
+    Steady,
+}
+
+#[derive(Debug, Clone)]
+pub struct BatteryState {
+    pub voltage: f64,
+    pub percent: f64,
+    pub level: BatteryLevel,
+    pub charge: Option<ChargeState>,
+}
+
+impl BatteryState {
+    pub fn is_low(&self) -> bool {
+        self.level != BatteryLevel::Normal
+    }
+
+    pub fn is_critical(&self) -> bool {
+        self.level == BatteryLevel::Critical
+    }
+
+    pub fn is_charging(&self) -> bool {
+        self.charge == Some(ChargeState::Charging)
+    }
+}
+
+pub struct BatteryMonitor {
+    samples: VecDeque<(Instant, f64)>,
+    level: BatteryLevel,
+}
+
+impl BatteryMonitor {
+    pub fn new() -> Self {
+        BatteryMonitor {
+            samples: VecDeque::with_capacity(BATTERY_TREND_WINDOW),
+            level: BatteryLevel::Normal,
+        }
+    }
+
+    pub fn update(&mut self, conf: &Battery, voltage: f64) -> BatteryState {
+        self.update_at(conf, voltage, Instant::now())
+    }
+
+    fn update_at(&mut self, conf: &Battery, voltage: f64, now: Instant) -> BatteryState {
+        let percent = conf.percent(voltage);
+        let level = self.level(conf, percent);
+        if level != self.level {
+            match level {
+                BatteryLevel::Critical => {
+                    warn!("Battery critical: {:.3} V ({:.0} %)", voltage, percent)
+                }
+                BatteryLevel::Low => warn!("Battery low: {:.3} V ({:.0} %)", voltage, percent),
+                BatteryLevel::Normal => {
+                    info!("Battery recovered: {:.3} V ({:.0} %)", voltage, percent)
+                }
+            }
+            self.level = level;
+        }
+
+        let charge = if conf.solar {
+            Some(self.charge_state(now, voltage, conf.trend_threshold))
+        } else {
+            None
+        };
+
+        BatteryState {
+            voltage,
+            percent,
+            level,
+            charge,
+        }
+    }
+
+    /// Level for the charge, a lower level is left only once the charge rises above it
+    /// by the recovery margin, so a voltage dipping under load does not flap the alerts.
+    fn level(&self, conf: &Battery, percent: f64) -> BatteryLevel {
+        let margin = |level| {
+            if self.level == level {
+                conf.recovery_margin
+            } else {
+                0.
+            }
+        };
+        let low_margin = margin(BatteryLevel::Low).max(margin(BatteryLevel::Critical));
+        if percent <= conf.critical_level + margin(BatteryLevel::Critical) {
+            BatteryLevel::Critical
+        } else if percent <= conf.low_level + low_margin {
+            BatteryLevel::Low
+        } else {
+            BatteryLevel::Normal
+        }
+    }
+
+    /// Estimates the voltage trend in V/h with least squares over the recent samples.
+    fn charge_state(&mut self, now: Instant, voltage: f64, threshold: f64) -> ChargeState {
+        if self.samples.len() == BATTERY_TREND_WINDOW {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((now, voltage));
+        if self.samples.len() < 3 {
+            return ChargeState::Steady;
+        }
+
+        let start = self.samples[0].0;
+        let count = self.samples.len() as f64;
+        let points = self
+            .samples
+            .iter()
+            .map(|(time, voltage)| (time.duration_since(start).as_secs_f64() / 3600., *voltage));
+        let (sum_t, sum_v, sum_tt, sum_tv) = points.fold(
+            (0., 0., 0., 0.),
+            |(sum_t, sum_v, sum_tt, sum_tv), (t, v)| {
+                (sum_t + t, sum_v + v, sum_tt + t * t, sum_tv + t * v)
+            },
+        );
+        let denominator = count * sum_tt - sum_t * sum_t;
+        if denominator.abs() < f64::EPSILON {
+            return ChargeState::Steady;
+        }
+        let slope = (count * sum_tv - sum_t * sum_v) / denominator;
+        debug!("Battery trend {:.4} V/h", slope);
+        if slope > threshold {
+            ChargeState::Charging
+        } else if slope < -threshold {
+            ChargeState::Discharging
+        } else {
+            ChargeState::Steady
+        }
+    }
+}
+
+pub fn raw_to_voltage(raw: u16) -> f64 {
+    raw as f64 * ADC_REFERENCE / ADC_MAX_VALUE / BATTERY_DIVIDER
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{CurvePoint, DischargeCurve};
+    use std::time::Duration;
+
+    fn conf(yaml: &str) -> Battery {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn linear() -> Battery {
+        let mut conf = conf("low_level: 20\ncritical_level: 5\nrecovery_margin: 5");
+        conf.curve = DischargeCurve::Custom {
+            points: vec![CurvePoint::new(3., 0.), CurvePoint::new(4., 100.)],
+        };
+        conf
+    }
+
+    #[test]
+    fn percent_interpolates_the_curve() {
+        let conf = conf("cells: 2");
+        assert_eq!(conf.percent(2. * 3.27), 0.);
+        assert_eq!(conf.percent(2. * 4.2), 100.);
+        let percent = conf.percent(2. * (3.61 + 3.69) / 2.);
+        assert!((percent - 7.5).abs() < 1e-9, "{}", percent);
+        assert_eq!(conf.percent(2. * 2.5), 0.);
+        assert_eq!(conf.percent(2. * 4.5), 100.);
+    }
+
+    #[test]
+    fn percent_of_custom_curve() {
+        let conf = linear();
+        assert!((conf.percent(3.25) - 25.).abs() < 1e-9);
+        assert!((conf.percent(3.999) - 99.9).abs() < 1e-9);
+    }
+
+    #[test]
+    fn levels_recover_only_above_the_margin() {
+        let conf = linear();
+        let mut monitor = BatteryMonitor::new();
+        let mut level = |percent: f64| monitor.update(&conf, 3. + percent / 100.).level;
+        assert_eq!(level(50.), BatteryLevel::Normal);
+        assert_eq!(level(19.), BatteryLevel::Low);
+        assert_eq!(level(24.), BatteryLevel::Low);
+        assert_eq!(level(26.), BatteryLevel::Normal);
+        assert_eq!(level(21.), BatteryLevel::Normal);
+        assert_eq!(level(4.), BatteryLevel::Critical);
+        assert_eq!(level(9.), BatteryLevel::Critical);
+        assert_eq!(level(11.), BatteryLevel::Low);
+        assert_eq!(level(3.), BatteryLevel::Critical);
+        assert_eq!(level(24.), BatteryLevel::Low);
+        assert_eq!(level(30.), BatteryLevel::Normal);
+    }
+
+    #[test]
+    fn trend_follows_the_voltage_slope() {
+        let mut conf = linear();
+        conf.solar = true;
+        let start = Instant::now();
+        let charge = |slope: f64| {
+            let mut monitor = BatteryMonitor::new();
+            (0..6)
+                .map(|num| {
+                    let now = start + Duration::from_secs(600 * num);
+                    let voltage = 3.7 + slope * num as f64 / 6.;
+                    monitor.update_at(&conf, voltage, now).charge
+                })
+                .collect::<Vec<_>>()
+        };
+        let charging = charge(0.1);
+        assert_eq!(charging[1], Some(ChargeState::Steady));
+        assert_eq!(charging[5], Some(ChargeState::Charging));
+        assert_eq!(charge(-0.1)[5], Some(ChargeState::Discharging));
+        assert_eq!(charge(0.01)[5], Some(ChargeState::Steady));
+    }
+
+    #[test]
+    fn trend_is_steady_without_elapsed_time() {
+        let mut conf = linear();
+        conf.solar = true;
+        let mut monitor = BatteryMonitor::new();
+        let now = Instant::now();
+        for voltage in &[3.5, 3.6, 3.7, 3.8] {
+            let state = monitor.update_at(&conf, *voltage, now);
+            assert_eq!(state.charge, Some(ChargeState::Steady));
+        }
+    }
+
+    #[test]
+    fn trend_is_off_without_solar() {
+        let mut monitor = BatteryMonitor::new();
+        assert_eq!(monitor.update(&linear(), 3.7).charge, None);
+    }
+}
@@ -0,0 +1,211 @@
+use crate::error::{Error, Result};
+use crate::export::Export;
+use crate::noaa::{Period, Report};
+use crate::query::Query;
+use crate::util::{utc_day_start, CONF_PATH};
+use chrono::{DateTime, Datelike, Duration, Local, NaiveDate, NaiveDateTime, TimeZone, Utc};
+
+pub const USAGE: &str = "Usage:
+    proxy [run]
+    proxy query [--conf PATH] [--from TIME] [--to TIME] [--bucket DURATION]
+                [--function min,max,mean,sum,last] [--measurement NAME,...]
+                [--node ADDR] [--tier NAME]
+    proxy export [--conf PATH] [--from TIME] [--to TIME] [--format csv|jsonl|parquet]
+                 [--output PATH] [--node ADDR,...] [--columns NAME,...]
+                 [--units metric|imperial|QUANTITY=UNIT,...] [--tier NAME]
+    proxy report [--conf PATH] [--month YYYY-MM | --year YYYY] [--output PATH]
+                 [--units metric|imperial|QUANTITY=UNIT,...]
+
+TIME is RFC 3339 or YYYY-MM-DD[THH:MM:SS] in UTC, DURATION is a number with
+an optional s, m, h or d suffix. Both commands default to the last day,
+export writes to the standard output unless --output is set. Units override
+the config, e.g. temperature=F, pressure=inHg, rain=in, wind=km/h. report
+prints the NOAA summary of the current month unless --month or --year is set.";
+
+pub enum Command {
+    Run,
+    Query {
+        conf: String,
+        query: Query,
+    },
+    Export {
+        conf: String,
+        output: Option<String>,
+        export: Export,
+    },
+    Report {
+        conf: String,
+        output: Option<String>,
+        report: Report,
+    },
+}
+
+pub fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Command> {
+    match args.next().as_deref() {
+        None | Some("run") => Ok(Command::Run),
+        Some("query") => parse_query(args),
+        Some("export") => parse_export(args),
+        Some("report") => parse_report(args),
+        Some(command) => Err(Error::new_argument(format!(
+            "Unknown command '{}'\n{}",
+            command, USAGE
+        ))),
+    }
+}
+
+fn parse_query(mut args: impl Iterator<Item = String>) -> Result<Command> {
+    let to = Utc::now();
+    let mut query = Query::new(to - Duration::days(1), to);
+    let mut conf = CONF_PATH.to_string();
+    while let Some(arg) = args.next() {
+        let value = args
+            .next()
+            .ok_or_else(|| Error::new_argument(format!("Missing value of {}", arg)))?;
+        match arg.as_str() {
+            "--conf" => conf = value,
+            "--from" => query.from = parse_time(&value)?,
+            "--to" => query.to = parse_time(&value)?,
+            "--bucket" => query.bucket = Some(parse_duration(&value)?),
+            "--function" => {
+                query.functions = split(&value)
+                    .map(|name| name.parse())
+                    .collect::<Result<_>>()?
+            }
+            "--measurement" => query.measurements = split(&value).map(String::from).collect(),
+            "--node" => {
+                query.node = Some(
+                    value
+                        .parse()
+                        .map_err(|_| Error::new_argument(format!("Invalid node '{}'", value)))?,
+                )
+            }
+            "--tier" => query.tier = Some(value),
+            _ => {
+                return Err(Error::new_argument(format!(
+                    "Unknown option '{}'\n{}",
+                    arg, USAGE
+                )))
+            }
+        }
+    }
+    Ok(Command::Query { conf, query })
+}
+
+fn parse_export(mut args: impl Iterator<Item = String>) -> Result<Command> {
+    let to = Utc::now();
+    let mut export = Export::new(to - Duration::days(1), to);
+    let mut conf = CONF_PATH.to_string();
+    let mut output = None;
+    while let Some(arg) = args.next() {
+        let value = args
+            .next()
+            .ok_or_else(|| Error::new_argument(format!("Missing value of {}", arg)))?;
+        match arg.as_str() {
+            "--conf" => conf = value,
+            "--output" => output = Some(value),
+            "--from" => export.from = parse_time(&value)?,
+            "--to" => export.to = parse_time(&value)?,
+            "--format" => export.format = value.parse()?,
+            "--node" => {
+                export.nodes = split(&value)
+                    .map(|node| {
+                        node.parse()
+                            .map_err(|_| Error::new_argument(format!("Invalid node '{}'", node)))
+                    })
+                    .collect::<Result<_>>()?
+            }
+            "--columns" => export.columns = split(&value).map(String::from).collect(),
+            "--units" => export.units = split(&value).map(String::from).collect(),
+            "--tier" => export.tier = Some(value),
+            _ => {
+                return Err(Error::new_argument(format!(
+                    "Unknown option '{}'\n{}",
+                    arg, USAGE
+                )))
+            }
+        }
+    }
+    Ok(Command::Export {
+        conf,
+        output,
+        export,
+    })
+}
+
+fn parse_report(mut args: impl Iterator<Item = String>) -> Result<Command> {
+    let today = Local::now().date_naive();
+    let mut report = Report::new(Period::Month(today.year(), today.month()));
+    let mut conf = CONF_PATH.to_string();
+    let mut output = None;
+    while let Some(arg) = args.next() {
+        let value = args
+            .next()
+            .ok_or_else(|| Error::new_argument(format!("Missing value of {}", arg)))?;
+        match arg.as_str() {
+            "--conf" => conf = value,
+            "--output" => output = Some(value),
+            "--month" => {
+                let month = NaiveDate::parse_from_str(&format!("{}-01", value), "%Y-%m-%d")
+                    .map_err(|_| Error::new_argument(format!("Invalid month '{}'", value)))?;
+                report.period = Period::Month(month.year(), month.month());
+            }
+            "--year" => {
+                let year = value
+                    .parse()
+                    .map_err(|_| Error::new_argument(format!("Invalid year '{}'", value)))?;
+                report.period = Period::Year(year);
+            }
+            "--units" => report.units = split(&value).map(String::from).collect(),
+            _ => {
+                return Err(Error::new_argument(format!(
+                    "Unknown option '{}'\n{}",
+                    arg, USAGE
+                )))
+            }
+        }
+    }
+    Ok(Command::Report {
+        conf,
+        output,
+        report,
+    })
+}
+
+fn split(value: &str) -> impl Iterator<Item = &str> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+}
+
+pub fn parse_time(value: &str) -> Result<DateTime<Utc>> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Ok(time.with_timezone(&Utc));
+    }
+    if let Ok(time) = NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M:%S") {
+        return Ok(Utc.from_utc_datetime(&time));
+    }
+    NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .map(utc_day_start)
+        .map_err(|_| Error::new_argument(format!("Invalid time '{}'", value)))
+}
+
+pub fn parse_duration(value: &str) -> Result<Duration> {
+    let (number, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(pos) => value.split_at(pos),
+        None => (value, "s"),
+    };
+    let number: i64 = number
+        .parse()
+        .map_err(|_| Error::new_argument(format!("Invalid duration '{}'", value)))?;
+    match unit {
+        "s" => Ok(Duration::seconds(number)),
+        "m" => Ok(Duration::minutes(number)),
+        "h" => Ok(Duration::hours(number)),
+        "d" => Ok(Duration::days(number)),
+        _ => Err(Error::new_argument(format!(
+            "Invalid duration unit '{}'",
+            unit
+        ))),
+    }
+}
@@ -0,0 +1,552 @@
+use crate::config::{Agriculture, Climate};
+use crate::error::Result;
+use crate::frame::{self, read_frames};
+use crate::indices::{DayIndices, Indices};
+use crate::reading::{Quality, Reading};
+use crate::util::{local_time, write_atomic, CLIMATE_EXTENSION, CLIMATE_TODAY};
+use chrono::{Datelike, NaiveDate, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+
+/// Minimum, maximum and sum of the values of a day, times are milliseconds since the
+/// Unix epoch.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Stat {
+    pub min: f64,
+    pub min_time: i64,
+    pub max: f64,
+    pub max_time: i64,
+    pub sum: f64,
+    pub count: u32,
+}
+
+impl Stat {
+    pub fn new(value: f64, time: i64) -> Self {
+        Stat {
+            min: value,
+            min_time: time,
+            max: value,
+            max_time: time,
+            sum: value,
+            count: 1,
+        }
+    }
+
+    pub fn add(&mut self, value: f64, time: i64) {
+        if value < self.min {
+            self.min = value;
+            self.min_time = time;
+        }
+        if value > self.max {
+            self.max = value;
+            self.max_time = time;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    pub fn mean(&self) -> f64 {
+        self.sum / self.count.max(1) as f64
+    }
+
+    /// Minimum and maximum with their times, in the order of `STATISTICS_EXTREMES`.
+    pub fn extremes(&self) -> [(f64, i64); 2] {
+        [(self.min, self.min_time), (self.max, self.max_time)]
+    }
+
+    /// Combines the values of a later period, ties keep the earlier time.
+    pub fn merge(&mut self, other: &Stat) {
+        if other.min < self.min {
+            self.min = other.min;
+            self.min_time = other.min_time;
+        }
+        if other.max > self.max {
+            self.max = other.max;
+            self.max_time = other.max_time;
+        }
+        self.sum += other.sum;
+        self.count += other.count;
+    }
+}
+
+/// Extremes and the total rainfall of a group of days.
+#[derive(Debug, Clone, Copy)]
+pub struct Extremes {
+    pub temperature: Stat,
+    pub pressure: Stat,
+    pub humidity: Stat,
+    pub rain: Option<f64>,
+}
+
+impl Extremes {
+    /// Statistics in the order of `STATISTICS_QUANTITIES`.
+    pub fn quantities(&self) -> [&Stat; 3] {
+        [&self.temperature, &self.pressure, &self.humidity]
+    }
+
+    fn merge(&mut self, other: &Extremes) {
+        self.temperature.merge(&other.temperature);
+        self.pressure.merge(&other.pressure);
+        self.humidity.merge(&other.humidity);
+        if let Some(rain) = other.rain {
+            *self.rain.get_or_insert(0.) += rain;
+        }
+    }
+}
+
+/// Extremes of the current day, month and of all the recorded days.
+#[derive(Debug, Clone, Copy)]
+pub struct Statistics {
+    pub today: Extremes,
+    pub month: Extremes,
+    pub all_time: Extremes,
+}
+
+impl Statistics {
+    /// Extremes in the order of `STATISTICS_PERIODS`.
+    pub fn periods(&self) -> [&Extremes; 3] {
+        [&self.today, &self.month, &self.all_time]
+    }
+}
+
+/// Summary of the good readings of one local day.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DailySummary {
+    /// Days from the Common Era, see `NaiveDate::num_days_from_ce`.
+    day: i32,
+    pub node: u8,
+    pub temperature: Stat,
+    pub pressure: Stat,
+    pub humidity: Stat,
+    pub wind: Option<Stat>,
+    pub gust: Option<Stat>,
+    /// Sum of the wind vectors, east and north components weighted by the speed.
+    pub wind_vector: Option<(f64, f64)>,
+    /// Rainfall of the day in mm.
+    pub rain: Option<f64>,
+}
+
+impl DailySummary {
+    fn new(date: NaiveDate, reading: &Reading) -> Self {
+        let time = reading.timestamp.timestamp_millis();
+        let mut summary = DailySummary {
+            day: date.num_days_from_ce(),
+            node: reading.node,
+            temperature: Stat::new(reading.temperature, time),
+            pressure: Stat::new(reading.pressure, time),
+            humidity: Stat::new(reading.humidity, time),
+            wind: None,
+            gust: None,
+            wind_vector: None,
+            rain: None,
+        };
+        summary.add_counters(reading);
+        summary
+    }
+
+    pub fn date(&self) -> NaiveDate {
+        NaiveDate::from_num_days_from_ce_opt(self.day).unwrap_or(NaiveDate::MIN)
+    }
+
+    pub fn extremes(&self) -> Extremes {
+        Extremes {
+            temperature: self.temperature,
+            pressure: self.pressure,
+            humidity: self.humidity,
+            rain: self.rain,
+        }
+    }
+
+    fn add(&mut self, reading: &Reading) {
+        let time = reading.timestamp.timestamp_millis();
+        self.temperature.add(reading.temperature, time);
+        self.pressure.add(reading.pressure, time);
+        self.humidity.add(reading.humidity, time);
+        self.add_counters(reading);
+    }
+
+    fn add_counters(&mut self, reading: &Reading) {
+        let time = reading.timestamp.timestamp_millis();
+        if let Some(wind) = &reading.wind {
+            add_option(&mut self.wind, wind.speed, time);
+            add_option(&mut self.gust, wind.gust, time);
+        }
+        if let Some(direction) = reading.wind_direction {
+            // Without an anemometer every direction sample has the same weight.
+            let speed = reading.wind.as_ref().map_or(1., |wind| wind.speed);
+            let (east, north) = self.wind_vector.get_or_insert((0., 0.));
+            *east += speed * direction.to_radians().sin();
+            *north += speed * direction.to_radians().cos();
+        }
+        if let Some(rain) = &reading.rain {
+            // Summed from the amounts, the daily total of the counter resets at the system
+            // midnight rather than in the configured timezone.
+            *self.rain.get_or_insert(0.) += rain.amount;
+        }
+    }
+}
+
+fn add_option(stat: &mut Option<Stat>, value: f64, time: i64) {
+    match stat {
+        Some(stat) => stat.add(value, time),
+        None => *stat = Some(Stat::new(value, time)),
+    }
+}
+
+/// Daily summaries in one file per year, appended when the day ends.
+///
+/// The summary of the current day is replaced after every reading, so a restart
+/// continues it. A day appended twice, after a power loss between the two writes, is
+/// read once. The extremes of the finished days of the month and of all the days, and
+/// the indices of the finished days of the season, are rebuilt from the files on open
+/// and kept up to date in memory.
+pub struct ClimateStore {
+    dir: PathBuf,
+    node: Option<u8>,
+    timezone: Option<Tz>,
+    agriculture: Option<Agriculture>,
+    today: Option<DailySummary>,
+    month: Option<Extremes>,
+    all_time: Option<Extremes>,
+    season: DayIndices,
+}
+
+impl ClimateStore {
+    pub fn open(conf: &Climate) -> Result<Self> {
+        fs::create_dir_all(&conf.path)?;
+        let mut store = Self::open_read_only(conf)?;
+        store.repair()?;
+        store.load_extremes()?;
+        store.load_season()?;
+        Ok(store)
+    }
+
+    /// Store for the reports, it never touches the files.
+    pub fn open_read_only(conf: &Climate) -> Result<Self> {
+        let dir = PathBuf::from(&conf.path);
+        let path = dir.join(CLIMATE_TODAY).with_extension(CLIMATE_EXTENSION);
+        let today = if path.exists() {
+            read_frames::<DailySummary>(&path)?.0.pop()
+        } else {
+            None
+        };
+        Ok(ClimateStore {
+            dir,
+            node: conf.node,
+            timezone: conf.timezone,
+            agriculture: conf.agriculture.clone(),
+            today,
+            month: None,
+            all_time: None,
+            season: DayIndices::default(),
+        })
+    }
+
+    /// Adds a reading to the summary of its local day, returns the summary of the
+    /// previous day on the first reading of a new day.
+    pub fn update(&mut self, reading: &Reading) -> Result<Option<DailySummary>> {
+        let other_node = matches!(self.node, Some(node) if node != reading.node);
+        if reading.quality != Quality::Good || other_node {
+            return Ok(None);
+        }
+        let date = local_time(reading.timestamp, self.timezone).date();
+        let finished = match &mut self.today {
+            Some(today) if today.date() == date => {
+                today.add(reading);
+                None
+            }
+            today => today.replace(DailySummary::new(date, reading)),
+        };
+        if let Some(finished) = &finished {
+            self.append(finished)?;
+            let extremes = finished.extremes();
+            merge_option(&mut self.all_time, &extremes);
+            if same_month(finished.date(), date) {
+                merge_option(&mut self.month, &extremes);
+            } else {
+                self.month = None;
+            }
+            self.load_season()?;
+        }
+        if let Some(today) = &self.today {
+            let path = self
+                .dir
+                .join(CLIMATE_TODAY)
+                .with_extension(CLIMATE_EXTENSION);
+            write_atomic(&path, &frame::encode(today)?)?;
+        }
+        Ok(finished)
+    }
+
+    /// Extremes including the current day, none before the first good reading.
+    pub fn statistics(&self) -> Option<Statistics> {
+        let today = self.today.as_ref()?.extremes();
+        let mut month = today;
+        let mut all_time = today;
+        if let Some(extremes) = &self.month {
+            month = *extremes;
+            month.merge(&today);
+        }
+        if let Some(extremes) = &self.all_time {
+            all_time = *extremes;
+            all_time.merge(&today);
+        }
+        Some(Statistics {
+            today,
+            month,
+            all_time,
+        })
+    }
+
+    /// Agricultural indices of the current day and of the season, none without their
+    /// configuration or before the first good reading.
+    pub fn indices(&self) -> Option<Indices> {
+        let conf = self.agriculture.as_ref()?;
+        let today = self.today.as_ref()?;
+        let today = DayIndices::new(conf, today.date(), &today.temperature);
+        let mut season = self.season;
+        season.add(&today);
+        Some(Indices { today, season })
+    }
+
+    /// Summaries of the days within `[from, to]` sorted by date, including the current
+    /// day.
+    pub fn read(&self, from: NaiveDate, to: NaiveDate) -> Result<Vec<DailySummary>> {
+        let mut days = BTreeMap::new();
+        for year in from.year()..=to.year() {
+            let path = self.year_path(year);
+            if !path.exists() {
+                continue;
+            }
+            for summary in read_frames::<DailySummary>(&path)?.0 {
+                days.insert(summary.date(), summary);
+            }
+        }
+        if let Some(today) = &self.today {
+            days.insert(today.date(), today.clone());
+        }
+        Ok(days
+            .into_iter()
+            .filter(|(date, _)| *date >= from && *date <= to)
+            .map(|(_, summary)| summary)
+            .collect())
+    }
+
+    fn append(&self, summary: &DailySummary) -> Result<()> {
+        let path = self.year_path(summary.date().year());
+        let created = !path.exists();
+        let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+        file.write_all(&frame::encode(summary)?)?;
+        file.sync_data()?;
+        if created {
+            frame::sync_dir(&self.dir)?;
+        }
+        Ok(())
+    }
+
+    /// Merges the finished days before the current one, which is still in progress.
+    fn load_extremes(&mut self) -> Result<()> {
+        let current = self.current_date();
+        let mut days = BTreeMap::new();
+        for path in self.year_paths()? {
+            for summary in read_frames::<DailySummary>(&path)?.0 {
+                days.insert(summary.date(), summary);
+            }
+        }
+        for (date, summary) in days.range(..current) {
+            let extremes = summary.extremes();
+            merge_option(&mut self.all_time, &extremes);
+            if same_month(*date, current) {
+                merge_option(&mut self.month, &extremes);
+            }
+        }
+        Ok(())
+    }
+
+    /// Sums the indices of the finished days of the season of the current day.
+    fn load_season(&mut self) -> Result<()> {
+        let conf = match &self.agriculture {
+            Some(conf) => conf,
+            None => return Ok(()),
+        };
+        let current = self.current_date();
+        let mut season = DayIndices::default();
+        for summary in self.read(conf.season_start(current), current)? {
+            if summary.date() < current {
+                season.add(&DayIndices::new(conf, summary.date(), &summary.temperature));
+            }
+        }
+        self.season = season;
+        Ok(())
+    }
+
+    fn current_date(&self) -> NaiveDate {
+        match &self.today {
+            Some(today) => today.date(),
+            None => local_time(Utc::now(), self.timezone).date(),
+        }
+    }
+
+    /// Drops an incomplete frame left behind by a power loss during the last append.
+    fn repair(&self) -> Result<()> {
+        for path in self.year_paths()? {
+            let (_, valid) = read_frames::<DailySummary>(&path)?;
+            let file = OpenOptions::new().write(true).open(&path)?;
+            if file.metadata()?.len() > valid {
+                warn!("Truncating torn climate file {:?} to {} bytes", path, valid);
+                file.set_len(valid)?;
+                file.sync_all()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn year_path(&self, year: i32) -> PathBuf {
+        self.dir
+            .join(year.to_string())
+            .with_extension(CLIMATE_EXTENSION)
+    }
+
+    fn year_paths(&self) -> Result<Vec<PathBuf>> {
+        let mut paths = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            let is_year = path.extension().and_then(|ext| ext.to_str()) == Some(CLIMATE_EXTENSION)
+                && path.file_stem().and_then(|stem| stem.to_str()) != Some(CLIMATE_TODAY);
+            if is_year {
+                paths.push(path);
+            }
+        }
+        Ok(paths)
+    }
+}
+
+fn merge_option(extremes: &mut Option<Extremes>, other: &Extremes) {
+    match extremes {
+        Some(extremes) => extremes.merge(other),
+        None => *extremes = Some(*other),
+    }
+}
+
+fn same_month(date: NaiveDate, other: NaiveDate) -> bool {
+    date.year() == other.year() && date.month() == other.month()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::test_dir;
+
+    fn conf(name: &str) -> Climate {
+        let path = test_dir(name);
+        serde_yaml::from_str(&format!(
+            "path: {}\nreports: {}\ntimezone: UTC",
+            path.display(),
+            path.join("reports").display()
+        ))
+        .unwrap()
+    }
+
+    fn reading(time: &str, temperature: f64) -> Reading {
+        let mut reading = Reading::sample();
+        reading.timestamp = time.parse().unwrap();
+        reading.temperature = temperature;
+        reading
+    }
+
+    fn date(date: &str) -> NaiveDate {
+        date.parse().unwrap()
+    }
+
+    #[test]
+    fn day_ends_with_the_first_reading_of_the_next_day() {
+        let mut store = ClimateStore::open(&conf("climate-rollover")).unwrap();
+        assert!(store.statistics().is_none());
+        let first = store.update(&reading("2024-05-01T10:00:00Z", 20.)).unwrap();
+        assert!(first.is_none());
+        store.update(&reading("2024-05-01T15:00:00Z", 25.)).unwrap();
+        let mut bad = reading("2024-05-01T16:00:00Z", 99.);
+        bad.quality = Quality::Bad;
+        store.update(&bad).unwrap();
+
+        let finished = store
+            .update(&reading("2024-05-02T01:00:00Z", 12.))
+            .unwrap()
+            .unwrap();
+        assert_eq!(finished.date(), date("2024-05-01"));
+        let temperature = finished.temperature;
+        assert_eq!(
+            (temperature.min, temperature.max, temperature.count),
+            (20., 25., 2)
+        );
+        assert!((finished.rain.unwrap() - 0.8).abs() < 1e-9);
+
+        let statistics = store.statistics().unwrap();
+        assert_eq!(statistics.today.temperature.max, 12.);
+        assert_eq!(statistics.month.temperature.max, 25.);
+        assert_eq!(statistics.month.temperature.min, 12.);
+        let days = store.read(date("2024-05-01"), date("2024-05-02")).unwrap();
+        assert_eq!(days.len(), 2);
+    }
+
+    #[test]
+    fn month_restarts_on_its_first_day() {
+        let mut store = ClimateStore::open(&conf("climate-month")).unwrap();
+        store.update(&reading("2024-04-30T12:00:00Z", 30.)).unwrap();
+        store.update(&reading("2024-05-01T12:00:00Z", 10.)).unwrap();
+        let statistics = store.statistics().unwrap();
+        assert_eq!(statistics.month.temperature.max, 10.);
+        assert!((statistics.month.rain.unwrap() - 0.4).abs() < 1e-9);
+        assert_eq!(statistics.all_time.temperature.max, 30.);
+
+        store.update(&reading("2024-05-02T12:00:00Z", 11.)).unwrap();
+        let statistics = store.statistics().unwrap();
+        let month = statistics.month.temperature;
+        assert_eq!((month.min, month.max, month.count), (10., 11., 2));
+        assert_eq!(statistics.all_time.temperature.count, 3);
+    }
+
+    #[test]
+    fn restart_continues_the_current_day() {
+        let conf = conf("climate-restart");
+        let mut store = ClimateStore::open(&conf).unwrap();
+        store.update(&reading("2024-04-30T12:00:00Z", 30.)).unwrap();
+        store.update(&reading("2024-05-01T10:00:00Z", 20.)).unwrap();
+        drop(store);
+
+        let mut store = ClimateStore::open(&conf).unwrap();
+        let finished = store.update(&reading("2024-05-01T12:00:00Z", 22.)).unwrap();
+        assert!(finished.is_none());
+        let statistics = store.statistics().unwrap();
+        let today = statistics.today.temperature;
+        assert_eq!((today.min, today.max, today.count), (20., 22., 2));
+        assert_eq!(statistics.month.temperature.count, 2);
+        assert_eq!(statistics.all_time.temperature.max, 30.);
+    }
+
+    #[test]
+    fn day_appended_twice_is_read_once() {
+        let conf = conf("climate-duplicate");
+        let mut store = ClimateStore::open(&conf).unwrap();
+        store.update(&reading("2024-05-01T10:00:00Z", 20.)).unwrap();
+        let finished = store
+            .update(&reading("2024-05-02T10:00:00Z", 21.))
+            .unwrap()
+            .unwrap();
+        // As after a power loss between the append and the write of the current day.
+        store.append(&finished).unwrap();
+        drop(store);
+
+        let store = ClimateStore::open(&conf).unwrap();
+        let days = store.read(date("2024-05-01"), date("2024-05-02")).unwrap();
+        let dates: Vec<_> = days.iter().map(DailySummary::date).collect();
+        assert_eq!(dates, [date("2024-05-01"), date("2024-05-02")]);
+        let statistics = store.statistics().unwrap();
+        assert_eq!(statistics.all_time.temperature.count, 2);
+        assert_eq!(statistics.month.temperature.count, 2);
+    }
+}
@@ -1,19 +1,65 @@
+use crate::aprs::Aprs;
+use crate::dashboard::Dashboard;
+use crate::domoticz::Domoticz;
 use crate::error::{Error, Result};
+use crate::expression::{round, Expression};
+use crate::homie::Homie;
+use crate::influxdb::Influx;
+use crate::modbus::Modbus;
+use crate::pws::Pws;
+use crate::realtime::RealtimeFile;
+use crate::transform::Transform;
+use crate::units::Units;
 use crate::util::{
-    BATTERY_CONFIG_TOPIC, BATTERY_SENSOR, HUMIDITY_CONFIG_TOPIC, HUMIDITY_SENSOR, PAYLOAD_OFF,
-    PAYLOAD_ON, PRESSURE_CONFIG_TOPIC, PRESSURE_SENSOR, TEMPERATURE_CONFIG_TOPIC,
-    TEMPERATURE_SENSOR,
+    interpolate, write_atomic, ANALOG_PRECISION, APRS_SINK, BATTERY_CHARGING_CONFIG_TOPIC,
+    BATTERY_CHARGING_SENSOR, BATTERY_CONFIG_TOPIC, BATTERY_CRITICAL_CONFIG_TOPIC,
+    BATTERY_CRITICAL_SENSOR, BATTERY_LEVEL_CONFIG_TOPIC, BATTERY_LEVEL_SENSOR,
+    BATTERY_LOW_CONFIG_TOPIC, BATTERY_LOW_SENSOR, BATTERY_SENSOR, CALIBRATION_PATH,
+    CALIBRATION_SENSORS, CALIBRATION_TOPIC_PREFIX, CALIBRATION_TOPIC_SUFFIX, CLIMATE_PATH,
+    CLIMATE_REPORTS, COUNTER_PINS, COUNTER_UNIT, DASHBOARD_SINK, DOMOTICZ_SINK, HISTORY_TIERS,
+    HOMIE_SINK, HUMIDITY_CONFIG_TOPIC, HUMIDITY_SENSOR, INDICES_GROWING_BASE, INDICES_GROWING_CAP,
+    INDICES_SEASON_START, INFLUXDB_SINK, LI_ION_CURVE, MODBUS_SINK, MQTT_URI,
+    NATIVE_VALUE_TEMPLATE, NI_MH_CURVE, NOAA_DEGREE_DAY_BASE, NOAA_STATION, OUTBOX_HOME_ASSISTANT,
+    OUTBOX_MAX_MESSAGES, OUTBOX_PATH, OUTBOX_VUTBR, PAYLOAD_OFF, PAYLOAD_ON, PRESSURE_CONFIG_TOPIC,
+    PRESSURE_SENSOR, RAIN_CONFIG_TOPIC, RAIN_DAILY_CONFIG_TOPIC, RAIN_DAILY_SENSOR,
+    RAIN_RATE_CONFIG_TOPIC, RAIN_RATE_SENSOR, RAIN_RATE_WINDOW, RAIN_SENSOR,
+    READING_TIMESTAMP_CONFIG_TOPIC, READING_TIMESTAMP_SENSOR, REALTIME_FILE_SINK,
+    RSSI_CONFIG_TOPIC, RSSI_SENSOR, SENML_CBOR_CONTENT_TYPE, SENML_JSON_CONTENT_TYPE,
+    TEMPERATURE_CONFIG_TOPIC, TEMPERATURE_SENSOR, VUTBR_TOPIC, WEBHOOK_SINK,
+    WIND_GUST_CONFIG_TOPIC, WIND_GUST_SENSOR, WIND_GUST_WINDOW, WIND_SPEED_CONFIG_TOPIC,
+    WIND_SPEED_SENSOR,
 };
+use crate::webhook::Webhook;
 use async_std::fs::File;
+use chrono::{Datelike, NaiveDate};
+use chrono_tz::Tz;
 use futures::AsyncReadExt;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::Path;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Config {
     gateway_addr: u8,
     network_id: u8,
     node: Node,
+    #[serde(default)]
+    calibration: Calibrations,
+    #[serde(default)]
+    history: Option<History>,
+    #[serde(default)]
+    climate: Option<Climate>,
+    #[serde(default)]
+    units: Units,
+    #[serde(default)]
+    outbox: Outbox,
+    #[serde(default = "default_sinks")]
+    sinks: Vec<SinkConfig>,
+    #[serde(default)]
+    metrics: Option<Metrics>,
 }
 
 impl Config {
@@ -32,6 +78,89 @@ impl Config {
     pub fn node_mut(&mut self) -> &mut Node {
         &mut self.node
     }
+
+    pub fn calibration(&self) -> &Calibrations {
+        &self.calibration
+    }
+
+    pub fn history(&self) -> Option<&History> {
+        self.history.as_ref()
+    }
+
+    pub fn climate(&self) -> Option<&Climate> {
+        self.climate.as_ref()
+    }
+
+    pub fn units(&self) -> Units {
+        self.units
+    }
+
+    pub fn outbox(&self) -> &Outbox {
+        &self.outbox
+    }
+
+    pub fn sinks(&self) -> &[SinkConfig] {
+        &self.sinks
+    }
+
+    pub fn metrics(&self) -> Option<&Metrics> {
+        self.metrics.as_ref()
+    }
+
+    fn validate_sinks(&self) -> Result<()> {
+        let mut names = HashSet::new();
+        for sink in &self.sinks {
+            if !names.insert(sink.name()) {
+                return Err(Error::new_config(format!(
+                    "Duplicate sink name '{}'",
+                    sink.name()
+                )));
+            }
+            if let SinkType::HomeAssistant {
+                json_topic,
+                json_discovery: true,
+                format,
+                ..
+            } = &sink.sink
+            {
+                if json_topic.is_none() || *format != PayloadFormat::Native {
+                    return Err(Error::new_config(format!(
+                        "Sink '{}' needs json_topic in the Native format for json_discovery",
+                        sink.name()
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn calibration_topics(&self) -> Vec<String> {
+        CALIBRATION_SENSORS
+            .iter()
+            .map(|sensor| {
+                format!(
+                    "{}{}{}",
+                    CALIBRATION_TOPIC_PREFIX, sensor, CALIBRATION_TOPIC_SUFFIX
+                )
+            })
+            .collect()
+    }
+
+    pub fn update_calibration(&mut self, topic: &str, payload: &str) -> Result<()> {
+        let sensor = topic
+            .strip_prefix(CALIBRATION_TOPIC_PREFIX)
+            .and_then(|topic| topic.strip_suffix(CALIBRATION_TOPIC_SUFFIX))
+            .ok_or_else(|| Error::new_option("Not a calibration topic."))?;
+        let calibration = serde_json::from_str::<Calibration>(payload)?;
+        calibration.validate(sensor)?;
+        let entry = self
+            .calibration
+            .get_mut(sensor)
+            .ok_or_else(|| Error::new_option("Cannot find sensor to calibrate."))?;
+        info!("New {} calibration {:?}", sensor, calibration);
+        *entry = calibration;
+        self.calibration.save()
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -40,6 +169,8 @@ pub struct Node {
     node_addr: u8,
     digital: HashMap<String, DigitalPin>,
     analog: HashMap<String, AnalogPin>,
+    #[serde(default)]
+    battery: Battery,
     #[serde(skip)]
     config_dirty: bool,
 }
@@ -49,6 +180,10 @@ impl Node {
         self.node_addr
     }
 
+    pub fn sleep_time(&self) -> u16 {
+        self.sleep_time
+    }
+
     pub fn is_config_dirty(&self) -> bool {
         self.config_dirty
     }
@@ -72,12 +207,24 @@ impl Node {
         Ok(())
     }
 
+    /// Configured state of the output pin with the command topic.
+    pub fn output_state(&self, topic: &str) -> Option<bool> {
+        self.digital.values().find_map(|digital| match digital {
+            DigitalPin::Output {
+                command_topic,
+                state,
+                ..
+            } if command_topic == topic => Some(*state),
+            _ => None,
+        })
+    }
+
     pub fn update_config_dirty(&mut self, dirty: bool) {
         self.config_dirty = dirty;
     }
 
     pub fn to_bytes(&self) -> Vec<u8> {
-        let mut bytes = Vec::with_capacity(5);
+        let mut bytes = Vec::with_capacity(6);
         bytes.extend_from_slice(&self.sleep_time.to_le_bytes());
 
         let mut digital_direction = 0;
@@ -95,6 +242,12 @@ impl Node {
             analog |= pin.as_byte();
         }
         bytes.push(analog);
+
+        let mut counter = 0;
+        for pin in self.digital.values() {
+            counter |= pin.counter_mask();
+        }
+        bytes.push(counter);
         bytes
     }
 
@@ -109,9 +262,39 @@ impl Node {
             }
         }
         result.push((BATTERY_CONFIG_TOPIC.to_string(), BATTERY_SENSOR));
+        result.push((BATTERY_LEVEL_CONFIG_TOPIC.to_string(), BATTERY_LEVEL_SENSOR));
+        result.push((BATTERY_LOW_CONFIG_TOPIC.to_string(), BATTERY_LOW_SENSOR));
+        result.push((
+            BATTERY_CRITICAL_CONFIG_TOPIC.to_string(),
+            BATTERY_CRITICAL_SENSOR,
+        ));
+        if self.battery.solar {
+            result.push((
+                BATTERY_CHARGING_CONFIG_TOPIC.to_string(),
+                BATTERY_CHARGING_SENSOR,
+            ));
+        }
+        result.push((
+            READING_TIMESTAMP_CONFIG_TOPIC.to_string(),
+            READING_TIMESTAMP_SENSOR,
+        ));
+        result.push((RSSI_CONFIG_TOPIC.to_string(), RSSI_SENSOR));
         result.push((TEMPERATURE_CONFIG_TOPIC.to_string(), TEMPERATURE_SENSOR));
         result.push((PRESSURE_CONFIG_TOPIC.to_string(), PRESSURE_SENSOR));
         result.push((HUMIDITY_CONFIG_TOPIC.to_string(), HUMIDITY_SENSOR));
+        for sensor in self.counter_sensors() {
+            match sensor {
+                CounterSensor::RainGauge { .. } => {
+                    result.push((RAIN_CONFIG_TOPIC.to_string(), RAIN_SENSOR));
+                    result.push((RAIN_RATE_CONFIG_TOPIC.to_string(), RAIN_RATE_SENSOR));
+                    result.push((RAIN_DAILY_CONFIG_TOPIC.to_string(), RAIN_DAILY_SENSOR));
+                }
+                CounterSensor::Anemometer { .. } => {
+                    result.push((WIND_SPEED_CONFIG_TOPIC.to_string(), WIND_SPEED_SENSOR));
+                    result.push((WIND_GUST_CONFIG_TOPIC.to_string(), WIND_GUST_SENSOR));
+                }
+            }
+        }
         result
     }
 
@@ -131,16 +314,42 @@ impl Node {
         self.digital.values()
     }
 
+    /// Digital pins with their configured names.
+    pub fn digital_pins(&self) -> impl Iterator<Item = (&str, &DigitalPin)> {
+        self.digital.iter().map(|(name, pin)| (name.as_str(), pin))
+    }
+
+    /// Analog pins with their configured names.
+    pub fn analog_pins(&self) -> impl Iterator<Item = (&str, &AnalogPin)> {
+        self.analog.iter().map(|(name, pin)| (name.as_str(), pin))
+    }
+
     pub fn analog(&self) -> impl Iterator<Item = &AnalogPin> {
         self.analog.values()
     }
 
+    pub fn battery(&self) -> &Battery {
+        &self.battery
+    }
+
+    pub fn counter_sensors(&self) -> impl Iterator<Item = &CounterSensor> {
+        self.digital.values().filter_map(|pin| match pin {
+            DigitalPin::Counter { sensor, .. } => Some(sensor),
+            _ => None,
+        })
+    }
+
     fn init_mqtt_topic(&mut self) {
         for pin in self.digital.values_mut() {
             match pin {
                 DigitalPin::Input {
                     number,
                     state_topic,
+                }
+                | DigitalPin::Counter {
+                    number,
+                    state_topic,
+                    ..
                 } => {
                     *state_topic = format!("node/digital/{}/state", number);
                 }
@@ -157,7 +366,54 @@ impl Node {
         }
         for pin in self.analog.values_mut() {
             pin.state_topic = format!("node/analog/{}/state", pin.number);
+            pin.value_topic = format!("node/analog/{}/value", pin.number);
+        }
+    }
+
+    fn init_counters(&self) -> Result<()> {
+        let mut rain_gauges = 0;
+        let mut anemometers = 0;
+        for (name, pin) in &self.digital {
+            if let DigitalPin::Counter { number, .. } = pin {
+                if !COUNTER_PINS.contains(number) {
+                    return Err(Error::new_config(format!(
+                        "Counter pin {} has number {}, expected {} to {}",
+                        name,
+                        number,
+                        COUNTER_PINS.start(),
+                        COUNTER_PINS.end()
+                    )));
+                }
+            }
+        }
+        for sensor in self.counter_sensors() {
+            match sensor {
+                CounterSensor::RainGauge { .. } => rain_gauges += 1,
+                CounterSensor::Anemometer { .. } => anemometers += 1,
+            }
+        }
+        if rain_gauges > 1 || anemometers > 1 {
+            return Err(Error::new_config(
+                "Only one rain gauge and one anemometer counter is supported".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn init_analog(&mut self) -> Result<()> {
+        for (name, pin) in self.analog.iter_mut() {
+            if let Some(transform) = &mut pin.transform {
+                transform.init(name)?;
+            }
+            match Expression::parse(&pin.expr) {
+                Ok(expression) => pin.expression = Some(expression),
+                Err(err) => {
+                    error!("Invalid expression of analog pin {}: {}", name, pin.expr);
+                    return Err(err);
+                }
+            }
         }
+        Ok(())
     }
 }
 
@@ -183,6 +439,27 @@ pub enum DigitalPin {
         #[serde(skip)]
         state_topic: String,
     },
+    Counter {
+        number: u8,
+        sensor: CounterSensor,
+        #[serde(skip)]
+        state_topic: String,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum CounterSensor {
+    RainGauge {
+        mm_per_pulse: f64,
+        #[serde(default = "default_rain_rate_window")]
+        rate_window: u64,
+    },
+    Anemometer {
+        speed_per_hz: f64,
+        #[serde(default = "default_wind_gust_window")]
+        gust_window: u64,
+    },
 }
 
 impl Pin for DigitalPin {
@@ -204,6 +481,14 @@ impl Pin for DigitalPin {
                 state_topic: &state_topic,
                 payload_on: PAYLOAD_ON,
                 payload_off: PAYLOAD_OFF,
+                device_class: None,
+            },
+            DigitalPin::Counter { state_topic, .. } => Discovery::Sensor {
+                name,
+                state_topic: &state_topic,
+                unit_of_measurement: COUNTER_UNIT,
+                value_template: NATIVE_VALUE_TEMPLATE,
+                device_class: None,
             },
         }
     }
@@ -218,6 +503,9 @@ impl Pin for DigitalPin {
                 "homeassistant/binary_sensor/node/digital_{}/config",
                 *number
             ),
+            DigitalPin::Counter { number, .. } => {
+                format!("homeassistant/sensor/node/digital_{}/config", *number)
+            }
         }
     }
 
@@ -233,6 +521,11 @@ impl Pin for DigitalPin {
                 state_topic,
                 ..
             } => (state_topic, *number),
+            DigitalPin::Counter {
+                number,
+                state_topic,
+                ..
+            } => (state_topic, *number),
         }
     }
 }
@@ -247,6 +540,13 @@ impl DigitalPin {
         }
         (direction, value)
     }
+
+    pub fn counter_mask(&self) -> u8 {
+        match self {
+            DigitalPin::Counter { number, .. } => 1u8 << number,
+            _ => 0,
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -254,18 +554,29 @@ pub struct AnalogPin {
     pub number: u8,
     pub enabled: bool,
     pub unit: String,
+    #[serde(default = "default_expr")]
     pub expr: String,
+    #[serde(default)]
+    pub transform: Option<Transform>,
+    /// Decimal places of the published value.
+    #[serde(default = "default_precision")]
+    pub precision: u8,
     #[serde(skip)]
     pub state_topic: String,
+    #[serde(skip)]
+    pub value_topic: String,
+    #[serde(skip)]
+    pub expression: Option<Expression>,
 }
 
 impl Pin for AnalogPin {
     fn as_discovery<'str, 'pin>(&'pin self, name: &'str str) -> Discovery<'str, 'pin> {
         Discovery::Sensor {
             name,
-            state_topic: &self.state_topic,
+            state_topic: &self.value_topic,
             unit_of_measurement: &self.unit,
-            value_template: &self.expr,
+            value_template: NATIVE_VALUE_TEMPLATE,
+            device_class: None,
         }
     }
 
@@ -282,6 +593,570 @@ impl AnalogPin {
     pub fn as_byte(&self) -> u8 {
         (self.enabled as u8) << self.number
     }
+
+    /// Value of the pin rounded to its precision, arithmetic on the raw value leaves
+    /// binary fractions such as `0.30000000000000004` otherwise.
+    pub fn evaluate(&self, raw: u16) -> Option<f64> {
+        let value = self.expression.as_ref()?.evaluate(raw as f64)?;
+        let value = match &self.transform {
+            Some(transform) => transform.apply(value)?,
+            None => value,
+        };
+        Some(round(value, self.precision as i32))
+    }
+
+    pub fn is_wind_vane(&self) -> bool {
+        matches!(self.transform, Some(Transform::WindVane { .. }))
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Battery {
+    pub curve: DischargeCurve,
+    pub cells: u8,
+    pub low_level: f64,
+    pub critical_level: f64,
+    /// Percentage points above a level the charge has to rise before the level is left.
+    pub recovery_margin: f64,
+    pub solar: bool,
+    pub trend_threshold: f64,
+}
+
+impl Default for Battery {
+    fn default() -> Self {
+        Battery {
+            curve: DischargeCurve::LiIon,
+            cells: 1,
+            low_level: 20.,
+            critical_level: 5.,
+            recovery_margin: 5.,
+            solar: false,
+            trend_threshold: 0.05,
+        }
+    }
+}
+
+impl Battery {
+    pub fn percent(&self, voltage: f64) -> f64 {
+        let cell_voltage = voltage / self.cells.max(1) as f64;
+        interpolate(self.curve.points(), cell_voltage, |point| {
+            (point.voltage, point.percent)
+        })
+        .unwrap_or(0.)
+    }
+
+    fn init_curve(&mut self) -> Result<()> {
+        if let DischargeCurve::Custom { points } = &mut self.curve {
+            points.sort_by(|a, b| a.voltage.partial_cmp(&b.voltage).unwrap_or(Ordering::Equal));
+            if points.is_empty() {
+                return Err(Error::new_config(
+                    "Custom battery curve has no points".to_string(),
+                ));
+            }
+            if let Some(pair) = points
+                .windows(2)
+                .find(|pair| pair[0].voltage == pair[1].voltage)
+            {
+                return Err(Error::new_config(format!(
+                    "Custom battery curve has more points at {} V",
+                    pair[0].voltage
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.critical_level >= self.low_level {
+            return Err(Error::new_config(format!(
+                "Battery critical_level {} must be below low_level {}",
+                self.critical_level, self.low_level
+            )));
+        }
+        if self.recovery_margin < 0. {
+            return Err(Error::new_config(format!(
+                "Battery recovery_margin {} must not be negative",
+                self.recovery_margin
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum DischargeCurve {
+    LiIon,
+    NiMh,
+    Custom { points: Vec<CurvePoint> },
+}
+
+impl DischargeCurve {
+    pub fn points(&self) -> &[CurvePoint] {
+        match self {
+            DischargeCurve::LiIon => LI_ION_CURVE,
+            DischargeCurve::NiMh => NI_MH_CURVE,
+            DischargeCurve::Custom { points } => points,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CurvePoint {
+    pub voltage: f64,
+    pub percent: f64,
+}
+
+impl CurvePoint {
+    pub const fn new(voltage: f64, percent: f64) -> Self {
+        CurvePoint { voltage, percent }
+    }
+}
+
+/// Calibrations of the sensors, the ones received over MQTT are kept in `path` and
+/// replace the configured ones after a restart.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Calibrations {
+    pub path: String,
+    pub temperature: Calibration,
+    pub pressure: Calibration,
+    pub humidity: Calibration,
+    pub battery: Calibration,
+}
+
+impl Default for Calibrations {
+    fn default() -> Self {
+        Calibrations {
+            path: CALIBRATION_PATH.to_string(),
+            temperature: Calibration::default(),
+            pressure: Calibration::default(),
+            humidity: Calibration::default(),
+            battery: Calibration::default(),
+        }
+    }
+}
+
+impl Calibrations {
+    fn get(&self, sensor: &str) -> Option<&Calibration> {
+        match sensor {
+            "temperature" => Some(&self.temperature),
+            "pressure" => Some(&self.pressure),
+            "humidity" => Some(&self.humidity),
+            "battery" => Some(&self.battery),
+            _ => None,
+        }
+    }
+
+    fn get_mut(&mut self, sensor: &str) -> Option<&mut Calibration> {
+        match sensor {
+            "temperature" => Some(&mut self.temperature),
+            "pressure" => Some(&mut self.pressure),
+            "humidity" => Some(&mut self.humidity),
+            "battery" => Some(&mut self.battery),
+            _ => None,
+        }
+    }
+
+    /// Replaces the configured calibrations by the ones saved from MQTT.
+    fn load(&mut self) -> Result<()> {
+        let content = match fs::read(&self.path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err.into()),
+        };
+        let saved = serde_json::from_slice::<HashMap<String, Calibration>>(&content)?;
+        for (sensor, calibration) in saved {
+            calibration.validate(&sensor)?;
+            let entry = match self.get_mut(&sensor) {
+                Some(entry) => entry,
+                None => {
+                    return Err(Error::new_config(format!(
+                        "Unknown sensor '{}' in {}",
+                        sensor, self.path
+                    )))
+                }
+            };
+            info!("Saved {} calibration {:?}", sensor, calibration);
+            *entry = calibration;
+        }
+        Ok(())
+    }
+
+    fn save(&self) -> Result<()> {
+        let sensors: BTreeMap<&str, &Calibration> = CALIBRATION_SENSORS
+            .iter()
+            .filter_map(|sensor| Some((*sensor, self.get(sensor)?)))
+            .collect();
+        write_atomic(Path::new(&self.path), &serde_json::to_vec(&sensors)?)
+    }
+
+    fn validate(&self) -> Result<()> {
+        self.temperature.validate("temperature")?;
+        self.pressure.validate("pressure")?;
+        self.humidity.validate("humidity")?;
+        self.battery.validate("battery")
+    }
+}
+
+/// Linear correction of a sensor value, either `value * gain + offset` or a line
+/// through two reference points when `points` is set.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Calibration {
+    pub offset: f64,
+    pub gain: f64,
+    pub points: Option<[CalibrationPoint; 2]>,
+}
+
+impl Default for Calibration {
+    fn default() -> Self {
+        Calibration {
+            offset: 0.,
+            gain: 1.,
+            points: None,
+        }
+    }
+}
+
+impl Calibration {
+    pub fn apply(&self, value: f64) -> f64 {
+        match &self.points {
+            Some([low, high]) => {
+                let gain = (high.actual - low.actual) / (high.measured - low.measured);
+                low.actual + (value - low.measured) * gain
+            }
+            None => value * self.gain + self.offset,
+        }
+    }
+
+    fn validate(&self, sensor: &str) -> Result<()> {
+        if let Some([low, high]) = &self.points {
+            if (high.measured - low.measured).abs() < f64::EPSILON {
+                return Err(Error::new_config(format!(
+                    "Calibration points of {} must have distinct measured values",
+                    sensor
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CalibrationPoint {
+    pub measured: f64,
+    pub actual: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct History {
+    pub path: String,
+    #[serde(default = "default_history_tiers")]
+    pub tiers: Vec<HistoryTier>,
+}
+
+impl History {
+    fn validate(&self) -> Result<()> {
+        let mut names = HashSet::new();
+        for tier in &self.tiers {
+            if tier.name.is_empty() || tier.name.contains('/') || !names.insert(&tier.name) {
+                return Err(Error::new_config(format!(
+                    "Invalid or duplicate history tier name '{}'",
+                    tier.name
+                )));
+            }
+        }
+        if self.tiers.iter().filter(|tier| tier.is_raw()).count() > 1 {
+            return Err(Error::new_config(
+                "Only one raw history tier is supported".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Daily summaries of the good readings of `node`, or of every node when unset, kept
+/// in `path`. The NOAA reports of the month and the year are rewritten in `reports`
+/// whenever a day ends. Days start at midnight in `timezone`, an IANA name such as
+/// `Europe/Prague`, or in the system timezone when unset.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Climate {
+    #[serde(default = "default_climate_path")]
+    pub path: String,
+    #[serde(default = "default_climate_reports")]
+    pub reports: String,
+    #[serde(default = "default_noaa_station")]
+    pub station: String,
+    #[serde(default)]
+    pub node: Option<u8>,
+    #[serde(default)]
+    pub timezone: Option<Tz>,
+    #[serde(default)]
+    pub agriculture: Option<Agriculture>,
+}
+
+impl Climate {
+    fn validate(&self) -> Result<()> {
+        if let Some(agriculture) = &self.agriculture {
+            agriculture.season_month_day()?;
+        }
+        Ok(())
+    }
+}
+
+/// Degree days and the reference evapotranspiration of every day, summed since the
+/// last `season_start` given as `MM-DD`. Growing degree days clamp the temperature
+/// extremes to `[growing_base, growing_cap]`. The evapotranspiration of Hargreaves needs
+/// the `latitude` of the station in decimal degrees, negative to the south.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Agriculture {
+    #[serde(default = "default_growing_base")]
+    pub growing_base: f64,
+    #[serde(default = "default_growing_cap")]
+    pub growing_cap: f64,
+    #[serde(default = "default_degree_day_base")]
+    pub heating_base: f64,
+    #[serde(default = "default_degree_day_base")]
+    pub cooling_base: f64,
+    #[serde(default = "default_season_start")]
+    pub season_start: String,
+    #[serde(default)]
+    pub latitude: Option<f64>,
+}
+
+impl Agriculture {
+    /// First day of the season containing `date`.
+    pub fn season_start(&self, date: NaiveDate) -> NaiveDate {
+        let (month, day) = self.season_month_day().unwrap_or((1, 1));
+        match NaiveDate::from_ymd_opt(date.year(), month, day) {
+            Some(start) if start <= date => start,
+            _ => NaiveDate::from_ymd_opt(date.year() - 1, month, day).unwrap_or(NaiveDate::MIN),
+        }
+    }
+
+    fn season_month_day(&self) -> Result<(u32, u32)> {
+        // A year without February 29, the season has to start every year.
+        NaiveDate::parse_from_str(&format!("2001-{}", self.season_start), "%Y-%m-%d")
+            .map(|date| (date.month(), date.day()))
+            .map_err(|_| Error::new_config(format!("Invalid season start '{}'", self.season_start)))
+    }
+}
+
+fn default_climate_path() -> String {
+    CLIMATE_PATH.to_string()
+}
+
+fn default_climate_reports() -> String {
+    CLIMATE_REPORTS.to_string()
+}
+
+fn default_noaa_station() -> String {
+    NOAA_STATION.to_string()
+}
+
+fn default_growing_base() -> f64 {
+    INDICES_GROWING_BASE
+}
+
+fn default_growing_cap() -> f64 {
+    INDICES_GROWING_CAP
+}
+
+fn default_degree_day_base() -> f64 {
+    NOAA_DEGREE_DAY_BASE
+}
+
+fn default_season_start() -> String {
+    INDICES_SEASON_START.to_string()
+}
+
+/// Retention policy of one history resolution, `resolution` in seconds (0 keeps every
+/// reading) and `retention` in days.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryTier {
+    pub name: String,
+    pub resolution: u32,
+    pub retention: u32,
+}
+
+impl HistoryTier {
+    pub fn is_raw(&self) -> bool {
+        self.resolution == 0
+    }
+}
+
+fn default_history_tiers() -> Vec<HistoryTier> {
+    HISTORY_TIERS
+        .iter()
+        .map(|(name, resolution, retention)| HistoryTier {
+            name: name.to_string(),
+            resolution: *resolution,
+            retention: *retention,
+        })
+        .collect()
+}
+
+/// Output of the readings, `name` defaults to the snake case type and also names the
+/// outbox queue of the sink.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SinkConfig {
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(flatten)]
+    pub sink: SinkType,
+}
+
+impl SinkConfig {
+    pub fn name(&self) -> String {
+        match (&self.name, &self.sink) {
+            (Some(name), _) => name.clone(),
+            (None, SinkType::HomeAssistant { .. }) => OUTBOX_HOME_ASSISTANT.to_string(),
+            (None, SinkType::VutBr { .. }) => OUTBOX_VUTBR.to_string(),
+            (None, SinkType::InfluxDb(_)) => INFLUXDB_SINK.to_string(),
+            (None, SinkType::Pws(pws)) => pws.service.sink_name().to_string(),
+            (None, SinkType::Aprs(_)) => APRS_SINK.to_string(),
+            (None, SinkType::Webhook(_)) => WEBHOOK_SINK.to_string(),
+            (None, SinkType::Homie(_)) => HOMIE_SINK.to_string(),
+            (None, SinkType::Domoticz(_)) => DOMOTICZ_SINK.to_string(),
+            (None, SinkType::Modbus(_)) => MODBUS_SINK.to_string(),
+            (None, SinkType::RealtimeFile(_)) => REALTIME_FILE_SINK.to_string(),
+            (None, SinkType::Dashboard(_)) => DASHBOARD_SINK.to_string(),
+        }
+    }
+}
+
+/// `VutBr` reads the broker from the `VUTBR_URI` environment variable unless `uri` is set.
+/// `HomeAssistant` also publishes one JSON document per reading when `json_topic` is set,
+/// `json_discovery` points the discovered sensors at that document. `format` selects the
+/// payload of that document and of `VutBr`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum SinkType {
+    HomeAssistant {
+        #[serde(default = "default_mqtt_uri")]
+        uri: String,
+        #[serde(default)]
+        json_topic: Option<String>,
+        #[serde(default)]
+        json_discovery: bool,
+        #[serde(default)]
+        format: PayloadFormat,
+    },
+    VutBr {
+        #[serde(default)]
+        uri: Option<String>,
+        #[serde(default = "default_vutbr_topic")]
+        topic: String,
+        #[serde(default)]
+        format: PayloadFormat,
+    },
+    InfluxDb(Influx),
+    Pws(Pws),
+    Aprs(Aprs),
+    Webhook(Webhook),
+    Homie(Homie),
+    Domoticz(Domoticz),
+    Modbus(Modbus),
+    RealtimeFile(RealtimeFile),
+    Dashboard(Dashboard),
+}
+
+fn default_sinks() -> Vec<SinkConfig> {
+    vec![
+        SinkConfig {
+            name: None,
+            sink: SinkType::HomeAssistant {
+                uri: default_mqtt_uri(),
+                json_topic: None,
+                json_discovery: false,
+                format: PayloadFormat::Native,
+            },
+        },
+        SinkConfig {
+            name: None,
+            sink: SinkType::VutBr {
+                uri: None,
+                topic: default_vutbr_topic(),
+                format: PayloadFormat::Native,
+            },
+        },
+    ]
+}
+
+pub fn default_mqtt_uri() -> String {
+    MQTT_URI.to_string()
+}
+
+fn default_vutbr_topic() -> String {
+    VUTBR_TOPIC.to_string()
+}
+
+/// Payload of the sinks sending whole readings, `Native` is the own format of the sink.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum PayloadFormat {
+    #[default]
+    Native,
+    SenmlJson,
+    SenmlCbor,
+}
+
+impl PayloadFormat {
+    pub fn content_type(self) -> Option<&'static str> {
+        match self {
+            PayloadFormat::Native => None,
+            PayloadFormat::SenmlJson => Some(SENML_JSON_CONTENT_TYPE),
+            PayloadFormat::SenmlCbor => Some(SENML_CBOR_CONTENT_TYPE),
+        }
+    }
+}
+
+/// Prometheus endpoint, `/metrics` is served on `addr` when configured.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Metrics {
+    pub addr: String,
+}
+
+/// Buffering of messages for unreachable destinations, `max_messages` per sink.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Outbox {
+    pub path: String,
+    pub max_messages: usize,
+    pub drop_policy: DropPolicy,
+}
+
+impl Default for Outbox {
+    fn default() -> Self {
+        Outbox {
+            path: OUTBOX_PATH.to_string(),
+            max_messages: OUTBOX_MAX_MESSAGES,
+            drop_policy: DropPolicy::DropOldest,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum DropPolicy {
+    DropOldest,
+    DropNewest,
+}
+
+fn default_expr() -> String {
+    NATIVE_VALUE_TEMPLATE.to_string()
+}
+
+fn default_precision() -> u8 {
+    ANALOG_PRECISION
+}
+
+fn default_rain_rate_window() -> u64 {
+    RAIN_RATE_WINDOW
+}
+
+fn default_wind_gust_window() -> u64 {
+    WIND_GUST_WINDOW
 }
 
 #[derive(Serialize)]
@@ -299,12 +1174,17 @@ pub enum Discovery<'a, 'b> {
         state_topic: &'b str,
         payload_on: &'static str,
         payload_off: &'static str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        device_class: Option<&'static str>,
     },
     Sensor {
         name: &'a str,
         state_topic: &'b str,
+        #[serde(skip_serializing_if = "str::is_empty")]
         unit_of_measurement: &'b str,
         value_template: &'b str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        device_class: Option<&'static str>,
     },
 }
 
@@ -325,6 +1205,60 @@ pub async fn read_conf(path: &str) -> Result<Config> {
     let mut config = serde_yaml::from_str::<Config>(&conf_content)?;
     config.node.update_config_dirty(true);
     config.node.init_mqtt_topic();
+    config.node.battery.init_curve()?;
+    config.node.battery.validate()?;
+    config.node.init_analog()?;
+    config.node.init_counters()?;
+    config.calibration.validate()?;
+    config.calibration.load()?;
+    if let Some(history) = &config.history {
+        history.validate()?;
+    }
+    if let Some(climate) = &config.climate {
+        climate.validate()?;
+    }
+    config.validate_sinks()?;
     info!("{:?}", config);
     Ok(config)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::test_dir;
+
+    #[test]
+    fn calibrations_saved_from_mqtt_replace_configured_ones() {
+        let path = test_dir("calibration").join("calibration.json");
+        let yaml = format!(
+            "path: {}\ntemperature: {{offset: 0.5}}\nhumidity: {{gain: 1.1}}",
+            path.display()
+        );
+        let mut calibrations: Calibrations = serde_yaml::from_str(&yaml).unwrap();
+        calibrations.load().unwrap();
+        assert_eq!(calibrations.temperature.apply(20.), 20.5);
+
+        calibrations.temperature = serde_json::from_str(r#"{"offset": -1.5}"#).unwrap();
+        calibrations.save().unwrap();
+        let mut restarted: Calibrations = serde_yaml::from_str(&yaml).unwrap();
+        restarted.load().unwrap();
+        assert_eq!(restarted.temperature.apply(20.), 18.5);
+        assert!((restarted.humidity.apply(50.) - 55.).abs() < 1e-9);
+        assert_eq!(restarted.pressure.apply(1000.), 1000.);
+    }
+
+    #[test]
+    fn invalid_saved_calibration_is_rejected() {
+        let path = test_dir("calibration-invalid").join("calibration.json");
+        fs::write(
+            &path,
+            r#"{"pressure": {"points": [{"measured": 1, "actual": 2}, {"measured": 1, "actual": 3}]}}"#,
+        )
+        .unwrap();
+        let mut calibrations = Calibrations {
+            path: path.display().to_string(),
+            ..Calibrations::default()
+        };
+        assert!(calibrations.load().is_err());
+    }
+}
@@ -0,0 +1,161 @@
+use crate::config::{CounterSensor, DigitalPin, Node};
+use chrono::{Local, NaiveDate};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone)]
+pub struct Rain {
+    /// Rainfall since the previous reading in mm.
+    pub amount: f64,
+    /// Rainfall intensity over the rate window in mm/h.
+    pub rate: f64,
+    /// Rainfall since local midnight in mm.
+    pub daily: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Wind {
+    /// Average speed since the previous reading in m/s.
+    pub speed: f64,
+    /// Highest average speed within the gust window in m/s.
+    pub gust: f64,
+}
+
+/// Converts the free-running pulse counts of the node into rainfall and wind speed.
+///
+/// The pulses of a reading are the difference to the counts of the previous one, so a
+/// packet resent after a lost ACK adds nothing.
+pub struct CounterMonitor {
+    last: Option<Instant>,
+    counts: Option<[u16; 8]>,
+    rain: VecDeque<(Instant, f64)>,
+    rain_day: NaiveDate,
+    rain_daily: f64,
+    wind: VecDeque<(Instant, f64)>,
+}
+
+impl CounterMonitor {
+    pub fn new() -> Self {
+        CounterMonitor {
+            last: None,
+            counts: None,
+            rain: VecDeque::new(),
+            rain_day: Local::now().naive_local().date(),
+            rain_daily: 0.,
+            wind: VecDeque::new(),
+        }
+    }
+
+    pub fn update(&mut self, node: &Node, counts: &[u16; 8]) -> (Option<Rain>, Option<Wind>) {
+        let now = Instant::now();
+        let interval = match self.last {
+            Some(last) => now.duration_since(last).as_secs_f64(),
+            None => node.sleep_time() as f64,
+        }
+        .max(1.);
+        self.last = Some(now);
+        let previous = self.counts.replace(*counts);
+
+        let mut rain = None;
+        let mut wind = None;
+        for pin in node.digital() {
+            if let DigitalPin::Counter { number, sensor, .. } = pin {
+                let number = *number as usize;
+                let count = pulses(previous.map(|counts| counts[number]), counts[number]) as f64;
+                match sensor {
+                    CounterSensor::RainGauge {
+                        mm_per_pulse,
+                        rate_window,
+                    } => rain = Some(self.update_rain(now, count * mm_per_pulse, *rate_window)),
+                    CounterSensor::Anemometer {
+                        speed_per_hz,
+                        gust_window,
+                    } => {
+                        let speed = count / interval * speed_per_hz;
+                        wind = Some(self.update_wind(now, speed, *gust_window))
+                    }
+                }
+            }
+        }
+        (rain, wind)
+    }
+
+    fn update_rain(&mut self, now: Instant, amount: f64, window: u64) -> Rain {
+        let today = Local::now().naive_local().date();
+        if today != self.rain_day {
+            self.rain_day = today;
+            self.rain_daily = 0.;
+        }
+        self.rain_daily += amount;
+
+        push_window(&mut self.rain, now, amount, window);
+        let total: f64 = self.rain.iter().map(|(_, amount)| amount).sum();
+        Rain {
+            amount,
+            rate: total * 3600. / window.max(1) as f64,
+            daily: self.rain_daily,
+        }
+    }
+
+    fn update_wind(&mut self, now: Instant, speed: f64, window: u64) -> Wind {
+        push_window(&mut self.wind, now, speed, window);
+        let gust = self
+            .wind
+            .iter()
+            .map(|(_, speed)| *speed)
+            .fold(speed, f64::max);
+        Wind { speed, gust }
+    }
+}
+
+/// Pulses between two counts of a pin, the first reading only sets the baseline.
+///
+/// A count lower than the previous one is either the wraparound of the counter or a
+/// restart of the node, the restart is assumed when the wrapped difference would exceed
+/// half of the range.
+fn pulses(previous: Option<u16>, count: u16) -> u16 {
+    match previous {
+        Some(previous) => {
+            let pulses = count.wrapping_sub(previous);
+            if pulses > u16::MAX / 2 {
+                count
+            } else {
+                pulses
+            }
+        }
+        None => 0,
+    }
+}
+
+/// Rainfall summed over a sliding window of `window` seconds.
+pub struct RainWindow {
+    window: u64,
+    samples: VecDeque<(Instant, f64)>,
+}
+
+impl RainWindow {
+    pub fn new(window: u64) -> Self {
+        RainWindow {
+            window,
+            samples: VecDeque::new(),
+        }
+    }
+
+    /// Adds the rainfall of a reading, returns the total or `None` without a rain gauge.
+    pub fn update(&mut self, rain: Option<&Rain>) -> Option<f64> {
+        let rain = rain?;
+        push_window(&mut self.samples, Instant::now(), rain.amount, self.window);
+        Some(self.samples.iter().map(|(_, amount)| amount).sum())
+    }
+}
+
+fn push_window(samples: &mut VecDeque<(Instant, f64)>, now: Instant, value: f64, window: u64) {
+    samples.push_back((now, value));
+    let window = Duration::from_secs(window);
+    while let Some((time, _)) = samples.front() {
+        if now.duration_since(*time) <= window {
+            break;
+        }
+        samples.pop_front();
+    }
+}
@@ -0,0 +1,336 @@
+use crate::config::{Config, DigitalPin};
+use crate::error::Result;
+use crate::history::HistoryStore;
+use crate::metrics::MetricsRegistry;
+use crate::query::Function;
+use crate::reading::{Quality, Reading};
+use crate::sink::Sink;
+use crate::util::{Sender, Shared, DASHBOARD_ADDR, DASHBOARD_MAX_POINTS, DASHBOARD_WINDOW};
+use async_std::sync::{Arc, Mutex};
+use async_std::task;
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use futures::channel::mpsc;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, VecDeque};
+use tide::listener::Listener;
+use tide::{sse, Request, Response, StatusCode};
+
+/// Web dashboard on `addr` with the latest reading of every node, updated live over
+/// Server-Sent Events. The sparklines cover the last `window` seconds, filled from the
+/// history store on start when it is configured.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Dashboard {
+    #[serde(default = "default_dashboard_addr")]
+    pub addr: String,
+    #[serde(default = "default_dashboard_window")]
+    pub window: i64,
+}
+
+fn default_dashboard_addr() -> String {
+    DASHBOARD_ADDR.to_string()
+}
+
+fn default_dashboard_window() -> i64 {
+    DASHBOARD_WINDOW
+}
+
+const PAGE: &str = include_str!("dashboard.html");
+
+/// Serves a page with the latest reading of every node and sparklines of the recent
+/// values, each reading is pushed to the open pages as a `reading` event.
+///
+/// The signal strength comes from the radio through the metrics registry, so a node is
+/// listed with its RSSI only after its first packet since the start.
+pub struct DashboardServer {
+    name: String,
+    conf: Dashboard,
+    shared_conf: Shared<Config>,
+    context: Context,
+}
+
+#[derive(Clone)]
+struct Context {
+    state: Shared<State>,
+    metrics: Shared<MetricsRegistry>,
+    window: i64,
+}
+
+#[derive(Default)]
+struct State {
+    nodes: BTreeMap<u8, NodeState>,
+    clients: Vec<Sender<String>>,
+}
+
+struct NodeState {
+    last_seen: DateTime<Utc>,
+    quality: Quality,
+    measurements: Vec<(String, f64)>,
+    /// Name, kind and value of the digital pins, the count of counter pins.
+    pins: Vec<(String, &'static str, u16)>,
+    /// Milliseconds since the Unix epoch and the value of every measurement.
+    series: BTreeMap<String, VecDeque<(i64, f64)>>,
+}
+
+impl DashboardServer {
+    pub fn new(
+        name: String,
+        conf: &Dashboard,
+        shared_conf: Shared<Config>,
+        metrics: Shared<MetricsRegistry>,
+    ) -> Self {
+        DashboardServer {
+            name,
+            conf: conf.clone(),
+            shared_conf,
+            context: Context {
+                state: new_shared!(State::default()),
+                metrics,
+                window: conf.window,
+            },
+        }
+    }
+
+    /// Fills the sparklines from the finest history tier.
+    async fn load_history(&self) -> Result<()> {
+        let store = {
+            let conf = self.shared_conf.lock().await;
+            match conf.history() {
+                Some(history) => HistoryStore::open_read_only(history)?,
+                None => return Ok(()),
+            }
+        };
+        let tier = match store.tiers().min_by_key(|tier| tier.resolution) {
+            Some(tier) => tier.name.clone(),
+            None => return Ok(()),
+        };
+        let to = Utc::now();
+        let records = store.read(&tier, to - Duration::seconds(self.conf.window), to)?;
+        let mut state = self.context.state.lock().await;
+        for record in &records {
+            let measurements = record
+                .aggregates()
+                .into_iter()
+                .map(|(name, aggregate)| {
+                    let value = Function::accumulation(name).apply(&aggregate);
+                    (name.to_string(), value)
+                })
+                .collect();
+            state
+                .nodes
+                .entry(record.node)
+                .or_insert_with(|| NodeState::new(record.time()))
+                .update(
+                    record.time(),
+                    record.quality,
+                    measurements,
+                    self.conf.window,
+                );
+        }
+        info!("Dashboard loaded {} records of {}", records.len(), tier);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Sink for DashboardServer {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn start(&mut self) -> Result<()> {
+        if let Err(err) = self.load_history().await {
+            warn!("Dashboard history unavailable: {:?}", err);
+        }
+        let mut app = tide::with_state(self.context.clone());
+        app.at("/").get(|_: Request<Context>| async move {
+            Ok(Response::builder(StatusCode::Ok)
+                .header("Content-Type", "text/html; charset=utf-8")
+                .body(PAGE)
+                .build())
+        });
+        app.at("/api/nodes")
+            .get(|request: Request<Context>| async move {
+                let body = request.state().nodes().await;
+                Ok(Response::builder(StatusCode::Ok)
+                    .header("Content-Type", "application/json")
+                    .body(body.to_string())
+                    .build())
+            });
+        app.at("/events").get(sse::endpoint(
+            |request: Request<Context>, sender: sse::Sender| async move {
+                let (client, mut events) = mpsc::unbounded();
+                request.state().state.lock().await.clients.push(client);
+                while let Some(event) = events.next().await {
+                    if sender.send("reading", event, None).await.is_err() {
+                        debug!("Dashboard page closed");
+                        break;
+                    }
+                }
+                Ok(())
+            },
+        ));
+        let mut listener = app.bind(self.conf.addr.as_str()).await?;
+        info!("Serving dashboard on {}", self.conf.addr);
+        task::spawn(async move {
+            if let Err(err) = listener.accept().await {
+                eprintln!("{}", err);
+                error!("{:?}", err);
+            }
+        });
+        Ok(())
+    }
+
+    async fn publish(&mut self, reading: &Reading) -> Result<()> {
+        let pins = {
+            let conf = self.shared_conf.lock().await;
+            conf.node()
+                .digital_pins()
+                .map(|(name, pin)| {
+                    let (kind, value) = match pin {
+                        DigitalPin::Output { number, .. } => {
+                            ("output", (reading.data.gpio_value >> number & 1) as u16)
+                        }
+                        DigitalPin::Input { number, .. } => {
+                            ("input", (reading.data.gpio_value >> number & 1) as u16)
+                        }
+                        DigitalPin::Counter { number, .. } => {
+                            ("counter", reading.data.counter_value[*number as usize])
+                        }
+                    };
+                    (name.to_string(), kind, value)
+                })
+                .collect()
+        };
+        let mut state = self.context.state.lock().await;
+        let node = state
+            .nodes
+            .entry(reading.node)
+            .or_insert_with(|| NodeState::new(reading.timestamp));
+        node.update(
+            reading.timestamp,
+            reading.quality,
+            reading.measurements(),
+            self.conf.window,
+        );
+        node.pins = pins;
+        let event = node.to_json(reading.node, reading.rssi, false).to_string();
+        // A closed page drops its receiver, its sender is removed on the next reading.
+        state
+            .clients
+            .retain(|client| client.unbounded_send(event.clone()).is_ok());
+        Ok(())
+    }
+
+    async fn shutdown(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl Context {
+    /// Every node with its sparklines, for the first render of the page.
+    async fn nodes(&self) -> Value {
+        let state = self.state.lock().await;
+        let metrics = self.metrics.lock().await;
+        let nodes: Vec<Value> = state
+            .nodes
+            .iter()
+            .map(|(addr, node)| node.to_json(*addr, metrics.rssi(*addr), true))
+            .collect();
+        let mut result = Map::new();
+        result.insert("window".to_string(), Value::from(self.window));
+        result.insert("nodes".to_string(), Value::from(nodes));
+        Value::Object(result)
+    }
+}
+
+impl NodeState {
+    fn new(last_seen: DateTime<Utc>) -> Self {
+        NodeState {
+            last_seen,
+            quality: Quality::Good,
+            measurements: Vec::new(),
+            pins: Vec::new(),
+            series: BTreeMap::new(),
+        }
+    }
+
+    /// Keeps the latest values, bad readings are left out of the sparklines.
+    fn update(
+        &mut self,
+        time: DateTime<Utc>,
+        quality: Quality,
+        measurements: Vec<(String, f64)>,
+        window: i64,
+    ) {
+        let millis = time.timestamp_millis();
+        let start = millis - window * 1000;
+        if quality != Quality::Bad {
+            for (name, value) in &measurements {
+                let series = self.series.entry(name.clone()).or_default();
+                series.push_back((millis, *value));
+                while series.len() > DASHBOARD_MAX_POINTS {
+                    series.pop_front();
+                }
+            }
+        }
+        for series in self.series.values_mut() {
+            while matches!(series.front(), Some((time, _)) if *time < start) {
+                series.pop_front();
+            }
+        }
+        self.series.retain(|_, series| !series.is_empty());
+        self.last_seen = time;
+        self.quality = quality;
+        self.measurements = measurements;
+    }
+
+    fn to_json(&self, addr: u8, rssi: Option<f64>, series: bool) -> Value {
+        let mut values = Map::new();
+        values.insert("node".to_string(), Value::from(addr));
+        values.insert(
+            "last_seen".to_string(),
+            Value::from(self.last_seen.to_rfc3339()),
+        );
+        values.insert("rssi".to_string(), rssi.map_or(Value::Null, Value::from));
+        values.insert(
+            "quality".to_string(),
+            Value::from(format!("{:?}", self.quality).to_lowercase()),
+        );
+        let measurements: Map<String, Value> = self
+            .measurements
+            .iter()
+            .map(|(name, value)| (name.clone(), Value::from(*value)))
+            .collect();
+        values.insert("measurements".to_string(), Value::Object(measurements));
+        let pins: Vec<Value> = self
+            .pins
+            .iter()
+            .map(|(name, kind, value)| {
+                let mut pin = Map::new();
+                pin.insert("name".to_string(), Value::from(name.as_str()));
+                pin.insert("kind".to_string(), Value::from(*kind));
+                pin.insert("value".to_string(), Value::from(*value));
+                Value::Object(pin)
+            })
+            .collect();
+        values.insert("pins".to_string(), Value::from(pins));
+        if series {
+            let series: Map<String, Value> = self
+                .series
+                .iter()
+                .map(|(name, points)| {
+                    let points: Vec<Value> = points
+                        .iter()
+                        .map(|(time, value)| Value::from(vec![*time as f64, *value]))
+                        .collect();
+                    (name.clone(), Value::from(points))
+                })
+                .collect();
+            values.insert("series".to_string(), Value::Object(series));
+        }
+        Value::Object(values)
+    }
+}
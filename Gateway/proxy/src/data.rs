@@ -12,6 +12,7 @@ pub struct Data {
     pub temperature: i16,
     pub pressure: u32,
     pub humidity: u16,
+    pub counter_value: [u16; 8],
 }
 
 impl TryFrom<&[u8]> for Data {
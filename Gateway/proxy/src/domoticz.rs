@@ -0,0 +1,266 @@
+use crate::config::{default_mqtt_uri, Config, DigitalPin};
+use crate::error::{Error, Result};
+use crate::outbox::{reconnect, Connection};
+use crate::reading::Reading;
+use crate::sink::{CommandStream, Sink};
+use crate::util::{Shared, DOMOTICZ_IN_TOPIC, DOMOTICZ_OUT_TOPIC, PAYLOAD_OFF, PAYLOAD_ON};
+use async_std::sync::Arc;
+use async_trait::async_trait;
+use futures::compat::{Future01CompatExt, Stream01CompatExt};
+use futures::StreamExt;
+use paho_mqtt::{AsyncClient, AsyncClientBuilder, ConnectOptions, Message};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+
+/// Domoticz device idx of the values, `switches` maps output pin names to Light/Switch
+/// devices which also control the pins.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Domoticz {
+    #[serde(default = "default_mqtt_uri")]
+    pub uri: String,
+    #[serde(default = "default_domoticz_in_topic")]
+    pub in_topic: String,
+    #[serde(default = "default_domoticz_out_topic")]
+    pub out_topic: String,
+    #[serde(default)]
+    pub temp_hum_baro: Option<u32>,
+    #[serde(default)]
+    pub battery: Option<u32>,
+    #[serde(default)]
+    pub switches: BTreeMap<String, u32>,
+}
+
+fn default_domoticz_in_topic() -> String {
+    DOMOTICZ_IN_TOPIC.to_string()
+}
+
+fn default_domoticz_out_topic() -> String {
+    DOMOTICZ_OUT_TOPIC.to_string()
+}
+
+/// Publishes the readings to Domoticz devices over MQTT.
+///
+/// The BME280 values go to a Temp+Hum+Baro device, the output pins to Light/Switch
+/// devices whose commands from `out_topic` are passed on as `Node::update_output`
+/// commands. Domoticz stores values at arrival, so readings are not buffered.
+pub struct DomoticzSink {
+    name: String,
+    conf: Domoticz,
+    mqtt: AsyncClient,
+    shared_conf: Shared<Config>,
+    switches: Arc<HashMap<u32, Switch>>,
+    switch_states: HashMap<u32, bool>,
+}
+
+struct Switch {
+    number: u8,
+    command_topic: String,
+}
+
+impl DomoticzSink {
+    pub fn new(name: String, conf: &Domoticz, shared_conf: Shared<Config>) -> Self {
+        let mqtt = AsyncClientBuilder::new().server_uri(&conf.uri).finalize();
+        DomoticzSink {
+            name,
+            conf: conf.clone(),
+            mqtt,
+            shared_conf,
+            switches: Arc::new(HashMap::new()),
+            switch_states: HashMap::new(),
+        }
+    }
+
+    async fn send(&self, payload: Value) -> Result<()> {
+        debug!("Domoticz {}", payload);
+        self.mqtt
+            .publish(Message::new(
+                self.conf.in_topic.as_str(),
+                payload.to_string(),
+                0,
+            ))
+            .compat()
+            .await?;
+        Ok(())
+    }
+
+    async fn subscribe(&self) -> Result<()> {
+        if !self.switches.is_empty() {
+            self.mqtt
+                .subscribe(self.conf.out_topic.as_str(), 0)
+                .compat()
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Sink for DomoticzSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn start(&mut self) -> Result<()> {
+        let mut switches = HashMap::with_capacity(self.conf.switches.len());
+        {
+            let conf = self.shared_conf.lock().await;
+            for (pin_name, idx) in &self.conf.switches {
+                let pin = conf
+                    .node()
+                    .digital_pins()
+                    .find(|(name, _)| name == pin_name)
+                    .map(|(_, pin)| pin);
+                let switch = match pin {
+                    Some(DigitalPin::Output {
+                        number,
+                        command_topic,
+                        ..
+                    }) => Switch {
+                        number: *number,
+                        command_topic: command_topic.clone(),
+                    },
+                    _ => {
+                        return Err(Error::new_config(format!(
+                            "Domoticz switch '{}' is not an output pin",
+                            pin_name
+                        )))
+                    }
+                };
+                switches.insert(*idx, switch);
+            }
+        }
+        self.switches = Arc::new(switches);
+        match self.mqtt.connect(ConnectOptions::new()).compat().await {
+            Ok(_) => self.subscribe().await?,
+            Err(err) => warn!("Domoticz broker unreachable: {:?}", err),
+        }
+        Ok(())
+    }
+
+    async fn publish(&mut self, reading: &Reading) -> Result<()> {
+        let connection = reconnect(&self.mqtt, &self.name).await;
+        match connection {
+            Connection::Disconnected => return Ok(()),
+            Connection::Reconnected => {
+                self.subscribe().await?;
+                self.switch_states.clear();
+            }
+            Connection::Connected => (),
+        }
+        let battery = reading.battery.percent.round().clamp(0., 100.) as u8;
+        if let Some(idx) = self.conf.temp_hum_baro {
+            self.send(json!({
+                "idx": idx,
+                "nvalue": 0,
+                "svalue": format!(
+                    "{:.1};{:.0};{};{:.1};{}",
+                    reading.temperature,
+                    reading.humidity,
+                    comfort(reading.temperature, reading.humidity),
+                    reading.pressure,
+                    forecast(reading.pressure)
+                ),
+                "Battery": battery,
+            }))
+            .await?;
+        }
+        if let Some(idx) = self.conf.battery {
+            self.send(json!({
+                "idx": idx,
+                "nvalue": 0,
+                "svalue": format!("{:.3}", reading.battery.voltage),
+                "Battery": battery,
+            }))
+            .await?;
+        }
+        // Only changes are sent, Domoticz echoes every update to the out topic.
+        for (idx, switch) in self.switches.iter() {
+            let state = reading.data.gpio_value & (1 << switch.number) != 0;
+            if self.switch_states.get(idx) == Some(&state) {
+                continue;
+            }
+            self.send(json!({ "idx": idx, "nvalue": state as u8 }))
+                .await?;
+            self.switch_states.insert(*idx, state);
+        }
+        Ok(())
+    }
+
+    async fn shutdown(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn commands(&mut self) -> Option<CommandStream> {
+        let shared_conf = self.shared_conf.clone();
+        let switches = self.switches.clone();
+        Some(
+            self.mqtt
+                .get_stream(50)
+                .compat()
+                .filter_map(move |result| {
+                    let shared_conf = shared_conf.clone();
+                    let switches = switches.clone();
+                    async move {
+                        match result {
+                            Ok(Some(message)) => command(&shared_conf, &switches, &message)
+                                .await
+                                .map(|message| Ok(Some(message))),
+                            Ok(None) => {
+                                warn!("Domoticz MQTT connection lost");
+                                None
+                            }
+                            Err(()) => Some(Err(())),
+                        }
+                    }
+                })
+                .boxed(),
+        )
+    }
+}
+
+/// Translates a switch update of Domoticz to the command topic of the output pin, the
+/// echo of the current state is dropped.
+async fn command(
+    shared_conf: &Shared<Config>,
+    switches: &HashMap<u32, Switch>,
+    message: &Message,
+) -> Option<Message> {
+    let value: Value = serde_json::from_slice(message.payload()).ok()?;
+    let idx = value["idx"].as_u64()?;
+    let switch = switches.get(&(idx as u32))?;
+    let state = value["nvalue"].as_i64()? != 0;
+    let conf = shared_conf.lock().await;
+    if conf.node().output_state(&switch.command_topic) == Some(state) {
+        return None;
+    }
+    let payload = if state { PAYLOAD_ON } else { PAYLOAD_OFF };
+    Some(Message::new(switch.command_topic.as_str(), payload, 0))
+}
+
+/// Humidity status of Domoticz, 0 normal, 1 comfortable, 2 dry and 3 wet.
+fn comfort(temperature: f64, humidity: f64) -> u8 {
+    if humidity < 30. {
+        2
+    } else if humidity > 70. {
+        3
+    } else if (40. ..=60.).contains(&humidity) && (19. ..=24.).contains(&temperature) {
+        1
+    } else {
+        0
+    }
+}
+
+/// Barometer forecast of Domoticz estimated from the pressure, 1 sunny, 2 partly cloudy,
+/// 3 cloudy and 4 rain.
+fn forecast(pressure: f64) -> u8 {
+    if pressure < 1000. {
+        4
+    } else if pressure < 1010. {
+        3
+    } else if pressure < 1020. {
+        2
+    } else {
+        1
+    }
+}
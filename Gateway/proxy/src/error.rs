@@ -1,13 +1,17 @@
 use bincode::Error as BincodeError;
+use ciborium::ser::Error as CborError;
+use csv::Error as CsvError;
 use failure::{Backtrace, Fail, SyncFailure};
 use linux_embedded_hal::gpio_cdev::errors::Error as CdevError;
 use paho_mqtt::errors::MqttError;
+use parquet::errors::ParquetError;
 use rfm69::Error as RfmError;
 use serde_json::Error as SerdeJsonError;
 use serde_yaml::Error as SerdeYamlError;
 use std::env::VarError;
 use std::io::Error as IoError;
 use std::result;
+use surf::Error as SurfError;
 
 pub type Result<T> = result::Result<T, Error>;
 
@@ -39,6 +43,24 @@ pub enum Error {
     },
     #[fail(display = "Env variable error: {}", _0)]
     VarError(#[fail(cause)] VarError, Backtrace),
+    #[fail(display = "Expression error: {}", _0)]
+    ExpressionError(String, Backtrace),
+    #[fail(display = "Config error: {}", _0)]
+    ConfigError(String, Backtrace),
+    #[fail(display = "History error: {}", _0)]
+    HistoryError(String, Backtrace),
+    #[fail(display = "Query error: {}", _0)]
+    QueryError(String, Backtrace),
+    #[fail(display = "Argument error: {}", _0)]
+    ArgumentError(String, Backtrace),
+    #[fail(display = "CSV error: {}", _0)]
+    CsvError(#[fail(cause)] CsvError, Backtrace),
+    #[fail(display = "Parquet error: {}", _0)]
+    ParquetError(#[fail(cause)] ParquetError, Backtrace),
+    #[fail(display = "HTTP error: {}", _0)]
+    HttpError(String, Backtrace),
+    #[fail(display = "CBOR error: {}", _0)]
+    CborError(#[fail(cause)] CborError<IoError>, Backtrace),
 }
 
 impl Error {
@@ -50,6 +72,30 @@ impl Error {
         Error::ResultError(msg, Backtrace::new())
     }
 
+    pub fn new_expression(msg: String) -> Self {
+        Error::ExpressionError(msg, Backtrace::new())
+    }
+
+    pub fn new_config(msg: String) -> Self {
+        Error::ConfigError(msg, Backtrace::new())
+    }
+
+    pub fn new_history(msg: String) -> Self {
+        Error::HistoryError(msg, Backtrace::new())
+    }
+
+    pub fn new_query(msg: String) -> Self {
+        Error::QueryError(msg, Backtrace::new())
+    }
+
+    pub fn new_argument(msg: String) -> Self {
+        Error::ArgumentError(msg, Backtrace::new())
+    }
+
+    pub fn new_http(msg: String) -> Self {
+        Error::HttpError(msg, Backtrace::new())
+    }
+
     pub fn new_index_out_of_range(size: usize, index: usize) -> Self {
         Error::IndexOutOfRange {
             size,
@@ -89,6 +135,12 @@ impl From<MqttError> for Error {
     }
 }
 
+impl From<CborError<IoError>> for Error {
+    fn from(err: CborError<IoError>) -> Self {
+        Error::CborError(err, Backtrace::new())
+    }
+}
+
 impl From<SerdeJsonError> for Error {
     fn from(err: SerdeJsonError) -> Self {
         Error::SerdeJsonError(err, Backtrace::new())
@@ -106,3 +158,21 @@ impl From<VarError> for Error {
         Error::VarError(err, Backtrace::new())
     }
 }
+
+impl From<CsvError> for Error {
+    fn from(err: CsvError) -> Self {
+        Error::CsvError(err, Backtrace::new())
+    }
+}
+
+impl From<ParquetError> for Error {
+    fn from(err: ParquetError) -> Self {
+        Error::ParquetError(err, Backtrace::new())
+    }
+}
+
+impl From<SurfError> for Error {
+    fn from(err: SurfError) -> Self {
+        Error::HttpError(err.to_string(), Backtrace::new())
+    }
+}
@@ -0,0 +1,373 @@
+use crate::error::{Error, Result};
+use crate::history::{HistoryStore, Record};
+use crate::query::Function;
+use crate::reading::Reading;
+use crate::units::Units;
+use chrono::{DateTime, Utc};
+use parquet::basic::Compression;
+use parquet::data_type::{ByteArray, ByteArrayType, DoubleType, Int32Type, Int64Type};
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::SerializedFileWriter;
+use parquet::schema::parser::parse_message_type;
+use serde_json::{Map, Value};
+use std::io::Write;
+use std::str::FromStr;
+use std::sync::Arc;
+
+const FIXED_COLUMNS: [&str; 3] = ["time", "node", "quality"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Csv,
+    JsonLines,
+    Parquet,
+}
+
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(name: &str) -> Result<Self> {
+        match name {
+            "csv" => Ok(Format::Csv),
+            "jsonl" | "json-lines" => Ok(Format::JsonLines),
+            "parquet" => Ok(Format::Parquet),
+            _ => Err(Error::new_argument(format!("Unknown format '{}'", name))),
+        }
+    }
+}
+
+/// Dump of stored readings over `[from, to)`, one row per reading.
+///
+/// Columns default to `time`, `node`, `quality` and every measurement present in the
+/// range. Downsampled tiers export the bucket mean, the sum of the rainfall and the last
+/// daily rainfall.
+#[derive(Debug, Clone)]
+pub struct Export {
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+    pub nodes: Vec<u8>,
+    pub columns: Vec<String>,
+    pub units: Vec<String>,
+    pub format: Format,
+    pub tier: Option<String>,
+}
+
+struct Row {
+    time: DateTime<Utc>,
+    node: u8,
+    quality: String,
+    values: Vec<Option<f64>>,
+}
+
+impl Export {
+    pub fn new(from: DateTime<Utc>, to: DateTime<Utc>) -> Self {
+        Export {
+            from,
+            to,
+            nodes: Vec::new(),
+            columns: Vec::new(),
+            units: Vec::new(),
+            format: Format::Csv,
+            tier: None,
+        }
+    }
+
+    pub fn run<W: Write + Send>(&self, store: &HistoryStore, units: Units, out: W) -> Result<()> {
+        let mut units = units;
+        for spec in &self.units {
+            units.update(spec)?;
+        }
+        let tier = match &self.tier {
+            Some(tier) => tier.clone(),
+            None => store
+                .tiers()
+                .min_by_key(|tier| tier.resolution)
+                .map(|tier| tier.name.clone())
+                .ok_or_else(|| Error::new_option("No history tier configured"))?,
+        };
+        let records: Vec<Record> = store
+            .read(&tier, self.from, self.to)?
+            .into_iter()
+            .filter(|record| self.nodes.is_empty() || self.nodes.contains(&record.node))
+            .collect();
+
+        let columns = self.columns(&records)?;
+        let measurements: Vec<&str> = columns
+            .iter()
+            .map(String::as_str)
+            .filter(|column| !FIXED_COLUMNS.contains(column))
+            .collect();
+        let rows: Vec<Row> = records
+            .iter()
+            .map(|record| {
+                let aggregates = record.aggregates();
+                let values = measurements
+                    .iter()
+                    .map(|name| {
+                        aggregates
+                            .iter()
+                            .find(|(key, _)| key == name)
+                            .map(|(_, aggregate)| {
+                                let value = Function::accumulation(name).apply(aggregate);
+                                units.convert(name, value)
+                            })
+                    })
+                    .collect();
+                Row {
+                    time: record.time(),
+                    node: record.node,
+                    quality: format!("{:?}", record.quality).to_lowercase(),
+                    values,
+                }
+            })
+            .collect();
+        info!("Exporting {} rows from history tier {}", rows.len(), tier);
+
+        match self.format {
+            Format::Csv => write_csv(&columns, &measurements, &rows, out),
+            Format::JsonLines => write_json_lines(&columns, &measurements, &rows, out),
+            Format::Parquet => write_parquet(&columns, &measurements, &rows, out),
+        }
+    }
+
+    /// Requested columns have to be fixed columns or known measurements, without any
+    /// the columns are the measurements stored in the range.
+    fn columns(&self, records: &[Record]) -> Result<Vec<String>> {
+        let mut columns: Vec<String> = FIXED_COLUMNS.iter().map(|c| c.to_string()).collect();
+        for record in records {
+            for (name, _) in record.aggregates() {
+                if !columns.iter().any(|column| column == name) {
+                    columns.push(name.to_string());
+                }
+            }
+        }
+        if self.columns.is_empty() {
+            return Ok(columns);
+        }
+        for name in Reading::measurement_names() {
+            if !columns.contains(&name) {
+                columns.push(name);
+            }
+        }
+        if self.columns.is_empty() {
+            return Ok(columns);
+        }
+        for (pos, column) in self.columns.iter().enumerate() {
+            if !columns.contains(column) {
+                return Err(Error::new_argument(format!(
+                    "Unknown column '{}', expected one of {}",
+                    column,
+                    columns.join(",")
+                )));
+            }
+            if self.columns[..pos].contains(column) {
+                return Err(Error::new_argument(format!(
+                    "Duplicate column '{}'",
+                    column
+                )));
+            }
+        }
+        Ok(self.columns.clone())
+    }
+}
+
+fn write_csv<W: Write>(
+    columns: &[String],
+    measurements: &[&str],
+    rows: &[Row],
+    out: W,
+) -> Result<()> {
+    let mut writer = csv::Writer::from_writer(out);
+    writer.write_record(columns)?;
+    for row in rows {
+        let record: Vec<String> = columns
+            .iter()
+            .map(|column| match column.as_str() {
+                "time" => row.time.to_rfc3339(),
+                "node" => row.node.to_string(),
+                "quality" => row.quality.clone(),
+                name => measurement(measurements, row, name)
+                    .map(|value| value.to_string())
+                    .unwrap_or_default(),
+            })
+            .collect();
+        writer.write_record(&record)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+fn write_json_lines<W: Write>(
+    columns: &[String],
+    measurements: &[&str],
+    rows: &[Row],
+    mut out: W,
+) -> Result<()> {
+    for row in rows {
+        let mut map = Map::new();
+        for column in columns {
+            let value = match column.as_str() {
+                "time" => Value::from(row.time.to_rfc3339()),
+                "node" => Value::from(row.node),
+                "quality" => Value::from(row.quality.clone()),
+                name => measurement(measurements, row, name).map_or(Value::Null, Value::from),
+            };
+            map.insert(column.clone(), value);
+        }
+        serde_json::to_writer(&mut out, &map)?;
+        writeln!(out)?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+fn write_parquet<W: Write + Send>(
+    columns: &[String],
+    measurements: &[&str],
+    rows: &[Row],
+    out: W,
+) -> Result<()> {
+    let fields: Vec<String> = columns
+        .iter()
+        .map(|column| match column.as_str() {
+            "time" => "required int64 time (TIMESTAMP(MILLIS, true));".to_string(),
+            "node" => "required int32 node (INTEGER(8, false));".to_string(),
+            "quality" => "required binary quality (UTF8);".to_string(),
+            name => format!("optional double {};", name),
+        })
+        .collect();
+    let schema = parse_message_type(&format!("message reading {{ {} }}", fields.join(" ")))?;
+    let properties = WriterProperties::builder()
+        .set_compression(Compression::SNAPPY)
+        .build();
+    let mut writer = SerializedFileWriter::new(out, Arc::new(schema), Arc::new(properties))?;
+    let mut row_group = writer.next_row_group()?;
+    for column in columns {
+        let mut column_writer = row_group
+            .next_column()?
+            .ok_or_else(|| Error::new_option("Parquet column missing"))?;
+        match column.as_str() {
+            "time" => {
+                let values: Vec<i64> = rows.iter().map(|row| row.time.timestamp_millis()).collect();
+                column_writer
+                    .typed::<Int64Type>()
+                    .write_batch(&values, None, None)?;
+            }
+            "node" => {
+                let values: Vec<i32> = rows.iter().map(|row| row.node as i32).collect();
+                column_writer
+                    .typed::<Int32Type>()
+                    .write_batch(&values, None, None)?;
+            }
+            "quality" => {
+                let values: Vec<ByteArray> = rows
+                    .iter()
+                    .map(|row| ByteArray::from(row.quality.as_str()))
+                    .collect();
+                column_writer
+                    .typed::<ByteArrayType>()
+                    .write_batch(&values, None, None)?;
+            }
+            name => {
+                let present: Vec<Option<f64>> = rows
+                    .iter()
+                    .map(|row| measurement(measurements, row, name))
+                    .collect();
+                let levels: Vec<i16> = present.iter().map(|value| value.is_some() as i16).collect();
+                let values: Vec<f64> = present.into_iter().flatten().collect();
+                column_writer
+                    .typed::<DoubleType>()
+                    .write_batch(&values, Some(&levels), None)?;
+            }
+        }
+        column_writer.close()?;
+    }
+    row_group.close()?;
+    writer.close()?;
+    Ok(())
+}
+
+fn measurement(measurements: &[&str], row: &Row, name: &str) -> Option<f64> {
+    measurements
+        .iter()
+        .position(|measurement| *measurement == name)
+        .and_then(|pos| row.values[pos])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::History;
+    use crate::util::{test_dir, utc_from_millis};
+    use chrono::Duration;
+
+    /// Store with a calm reading, without the anemometer values.
+    fn store(name: &str) -> (HistoryStore, DateTime<Utc>) {
+        let path = test_dir(name).display().to_string();
+        let conf: History = serde_yaml::from_str(&format!(
+            "path: {}\ntiers:\n- {{name: raw, resolution: 0, retention: 7}}",
+            path
+        ))
+        .unwrap();
+        let mut store = HistoryStore::open(&conf).unwrap();
+        let mut reading = Reading::sample();
+        reading.timestamp = utc_from_millis((Utc::now().timestamp() - 3600) * 1000);
+        reading.wind = None;
+        store.append(&reading).unwrap();
+        (store, reading.timestamp)
+    }
+
+    fn export(store: &HistoryStore, columns: &str, format: Format) -> Result<String> {
+        let mut export = Export::new(Utc::now() - Duration::days(1), Utc::now());
+        export.format = format;
+        if !columns.is_empty() {
+            export.columns = columns.split(',').map(String::from).collect();
+        }
+        let mut out = Vec::new();
+        export.run(store, Units::default(), &mut out)?;
+        Ok(String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn default_columns_are_the_stored_measurements() {
+        let (store, _) = store("export-default");
+        let csv = export(&store, "", Format::Csv).unwrap();
+        let header = csv.lines().next().unwrap();
+        assert!(header.starts_with("time,node,quality,temperature,"));
+        assert!(header.contains("rain_daily"));
+        assert!(!header.contains("wind"));
+    }
+
+    #[test]
+    fn absent_measurements_are_empty_cells() {
+        let (store, time) = store("export-absent");
+        let csv = export(&store, "time,temperature,wind_gust", Format::Csv).unwrap();
+        let expected = format!("time,temperature,wind_gust\n{},21.5,\n", time.to_rfc3339());
+        assert_eq!(csv, expected);
+
+        let json = export(&store, "node,wind_speed,analog_2", Format::JsonLines).unwrap();
+        assert_eq!(
+            json,
+            "{\"analog_2\":null,\"node\":10,\"wind_speed\":null}\n"
+        );
+
+        let csv = export(&store, "evapotranspiration_season", Format::Csv).unwrap();
+        assert_eq!(csv, "evapotranspiration_season\n\"\"\n");
+    }
+
+    #[test]
+    fn measurement_names_cover_the_readings() {
+        let names = Reading::measurement_names();
+        for (name, _) in Reading::sample().measurements() {
+            assert!(names.contains(&name), "{}", name);
+        }
+    }
+
+    #[test]
+    fn unknown_and_duplicate_columns_are_rejected() {
+        let (store, _) = store("export-invalid");
+        assert!(export(&store, "time,wind", Format::Csv).is_err());
+        assert!(export(&store, "time,rain,rain", Format::Csv).is_err());
+        assert!(export(&store, "gdd_season,heat_index", Format::Csv).is_err());
+    }
+}
@@ -0,0 +1,473 @@
+use crate::error::{Error, Result};
+use std::iter::Peekable;
+use std::str::Chars;
+
+/// Subset of Jinja expressions used by analog pin templates, e.g.
+/// `{{ (float(value) * 3.3 / (2**12 - 1)) | round(3) }}`.
+#[derive(Debug, Clone)]
+pub struct Expression {
+    term: Term,
+}
+
+impl Expression {
+    pub fn parse(source: &str) -> Result<Self> {
+        let source = source.trim();
+        let source = match (source.strip_prefix("{{"), source.ends_with("}}")) {
+            (Some(inner), true) => &inner[..inner.len() - 2],
+            _ => source,
+        };
+        let tokens = tokenize(source)?;
+        let mut parser = Parser { tokens, pos: 0 };
+        let term = parser.expression()?;
+        if parser.pos != parser.tokens.len() {
+            return Err(Error::new_expression(format!(
+                "Unexpected {:?} in '{}'",
+                parser.tokens[parser.pos], source
+            )));
+        }
+        Ok(Expression { term })
+    }
+
+    /// Result for the raw value, `None` when it is not finite, e.g. after a division by
+    /// zero.
+    pub fn evaluate(&self, value: f64) -> Option<f64> {
+        let result = self.term.evaluate(value);
+        if result.is_finite() {
+            Some(result)
+        } else {
+            debug!("Expression result {} for {} is not finite", result, value);
+            None
+        }
+    }
+}
+
+/// Rounds half away from zero to `digits` decimal places, as the Jinja `round` filter.
+pub fn round(value: f64, digits: i32) -> f64 {
+    let scale = 10f64.powi(digits);
+    (value * scale).round() / scale
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    Ident(String),
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    FloorDiv,
+    Percent,
+    Pow,
+    Pipe,
+    Comma,
+    LeftParen,
+    RightParen,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Function {
+    Float,
+    Int,
+    Abs,
+    Round,
+    Min,
+    Max,
+}
+
+impl Function {
+    fn from_name(name: &str) -> Result<Self> {
+        match name {
+            "float" => Ok(Function::Float),
+            "int" => Ok(Function::Int),
+            "abs" => Ok(Function::Abs),
+            "round" => Ok(Function::Round),
+            "min" => Ok(Function::Min),
+            "max" => Ok(Function::Max),
+            _ => Err(Error::new_expression(format!(
+                "Unknown function '{}'",
+                name
+            ))),
+        }
+    }
+
+    fn arity(self) -> (usize, usize) {
+        match self {
+            Function::Float | Function::Int | Function::Abs => (1, 1),
+            Function::Round => (1, 2),
+            Function::Min | Function::Max => (2, usize::MAX),
+        }
+    }
+
+    fn apply(self, args: &[f64]) -> f64 {
+        match self {
+            Function::Float => args[0],
+            Function::Int => args[0].trunc(),
+            Function::Abs => args[0].abs(),
+            Function::Round => round(args[0], args.get(1).copied().unwrap_or(0.) as i32),
+            Function::Min => args.iter().copied().fold(f64::INFINITY, f64::min),
+            Function::Max => args.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Term {
+    Number(f64),
+    Value,
+    Negate(Box<Term>),
+    Binary(Token, Box<Term>, Box<Term>),
+    Call(Function, Vec<Term>),
+}
+
+impl Term {
+    fn evaluate(&self, value: f64) -> f64 {
+        match self {
+            Term::Number(number) => *number,
+            Term::Value => value,
+            Term::Negate(term) => -term.evaluate(value),
+            Term::Binary(op, left, right) => {
+                let (left, right) = (left.evaluate(value), right.evaluate(value));
+                match op {
+                    Token::Plus => left + right,
+                    Token::Minus => left - right,
+                    Token::Star => left * right,
+                    Token::Slash => left / right,
+                    Token::FloorDiv => (left / right).floor(),
+                    Token::Percent => left % right,
+                    Token::Pow => left.powf(right),
+                    _ => unreachable!("Parser produces only arithmetic operators"),
+                }
+            }
+            Term::Call(function, args) => {
+                let args: Vec<f64> = args.iter().map(|arg| arg.evaluate(value)).collect();
+                function.apply(&args)
+            }
+        }
+    }
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = source.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        let token = match c {
+            ' ' | '\t' | '\n' => {
+                chars.next();
+                continue;
+            }
+            '0'..='9' | '.' => Token::Number(number(&mut chars)?),
+            'a'..='z' | 'A'..='Z' | '_' => Token::Ident(ident(&mut chars)),
+            _ => {
+                chars.next();
+                match c {
+                    '+' => Token::Plus,
+                    '-' => Token::Minus,
+                    '*' if chars.peek() == Some(&'*') => {
+                        chars.next();
+                        Token::Pow
+                    }
+                    '*' => Token::Star,
+                    '/' if chars.peek() == Some(&'/') => {
+                        chars.next();
+                        Token::FloorDiv
+                    }
+                    '/' => Token::Slash,
+                    '%' => Token::Percent,
+                    '|' => Token::Pipe,
+                    ',' => Token::Comma,
+                    '(' => Token::LeftParen,
+                    ')' => Token::RightParen,
+                    _ => {
+                        return Err(Error::new_expression(format!(
+                            "Unexpected character '{}' in '{}'",
+                            c, source
+                        )))
+                    }
+                }
+            }
+        };
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+fn number(chars: &mut Peekable<Chars>) -> Result<f64> {
+    let mut literal = String::new();
+    while let Some(&c) = chars.peek() {
+        if !c.is_ascii_digit() && c != '.' && c != '_' {
+            break;
+        }
+        if c != '_' {
+            literal.push(c);
+        }
+        chars.next();
+    }
+    literal
+        .parse()
+        .map_err(|_| Error::new_expression(format!("Invalid number '{}'", literal)))
+}
+
+fn ident(chars: &mut Peekable<Chars>) -> String {
+    let mut ident = String::new();
+    while let Some(&c) = chars.peek() {
+        if !c.is_ascii_alphanumeric() && c != '_' {
+            break;
+        }
+        ident.push(c);
+        chars.next();
+    }
+    ident
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Result<Token> {
+        let token = self
+            .tokens
+            .get(self.pos)
+            .cloned()
+            .ok_or_else(|| Error::new_expression("Unexpected end of expression".to_string()))?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<()> {
+        let token = self.next()?;
+        if token != expected {
+            return Err(Error::new_expression(format!(
+                "Expected {:?}, found {:?}",
+                expected, token
+            )));
+        }
+        Ok(())
+    }
+
+    fn expression(&mut self) -> Result<Term> {
+        let mut left = self.product()?;
+        while let Some(op @ Token::Plus) | Some(op @ Token::Minus) = self.peek().cloned() {
+            self.pos += 1;
+            left = Term::Binary(op, Box::new(left), Box::new(self.product()?));
+        }
+        Ok(left)
+    }
+
+    fn product(&mut self) -> Result<Term> {
+        let mut left = self.power()?;
+        while let Some(op @ Token::Star)
+        | Some(op @ Token::Slash)
+        | Some(op @ Token::FloorDiv)
+        | Some(op @ Token::Percent) = self.peek().cloned()
+        {
+            self.pos += 1;
+            left = Term::Binary(op, Box::new(left), Box::new(self.power()?));
+        }
+        Ok(left)
+    }
+
+    fn power(&mut self) -> Result<Term> {
+        let mut left = self.unary()?;
+        while let Some(Token::Pow) = self.peek() {
+            self.pos += 1;
+            left = Term::Binary(Token::Pow, Box::new(left), Box::new(self.unary()?));
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Term> {
+        match self.peek() {
+            Some(Token::Minus) => {
+                self.pos += 1;
+                Ok(Term::Negate(Box::new(self.unary()?)))
+            }
+            Some(Token::Plus) => {
+                self.pos += 1;
+                self.unary()
+            }
+            _ => self.filtered(),
+        }
+    }
+
+    fn filtered(&mut self) -> Result<Term> {
+        let mut term = self.primary()?;
+        while let Some(Token::Pipe) = self.peek() {
+            self.pos += 1;
+            let name = match self.next()? {
+                Token::Ident(name) => name,
+                token => {
+                    return Err(Error::new_expression(format!(
+                        "Expected filter name, found {:?}",
+                        token
+                    )))
+                }
+            };
+            let mut args = vec![term];
+            if let Some(Token::LeftParen) = self.peek() {
+                args.extend(self.arguments()?);
+            }
+            term = self.call(&name, args)?;
+        }
+        Ok(term)
+    }
+
+    fn primary(&mut self) -> Result<Term> {
+        match self.next()? {
+            Token::Number(number) => Ok(Term::Number(number)),
+            Token::Ident(name) => {
+                if let Some(Token::LeftParen) = self.peek() {
+                    let args = self.arguments()?;
+                    self.call(&name, args)
+                } else if name == "value" {
+                    Ok(Term::Value)
+                } else {
+                    Err(Error::new_expression(format!(
+                        "Unknown variable '{}'",
+                        name
+                    )))
+                }
+            }
+            Token::LeftParen => {
+                let term = self.expression()?;
+                self.expect(Token::RightParen)?;
+                Ok(term)
+            }
+            token => Err(Error::new_expression(format!("Unexpected {:?}", token))),
+        }
+    }
+
+    fn arguments(&mut self) -> Result<Vec<Term>> {
+        self.expect(Token::LeftParen)?;
+        let mut args = Vec::new();
+        if let Some(Token::RightParen) = self.peek() {
+            self.pos += 1;
+            return Ok(args);
+        }
+        loop {
+            args.push(self.expression()?);
+            match self.next()? {
+                Token::Comma => continue,
+                Token::RightParen => break,
+                token => {
+                    return Err(Error::new_expression(format!(
+                        "Expected ',' or ')', found {:?}",
+                        token
+                    )))
+                }
+            }
+        }
+        Ok(args)
+    }
+
+    fn call(&self, name: &str, args: Vec<Term>) -> Result<Term> {
+        let function = Function::from_name(name)?;
+        let (min, max) = function.arity();
+        if args.len() < min || args.len() > max {
+            return Err(Error::new_expression(format!(
+                "Function '{}' called with {} arguments",
+                name,
+                args.len()
+            )));
+        }
+        Ok(Term::Call(function, args))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn evaluate(source: &str, value: f64) -> Option<f64> {
+        Expression::parse(source).unwrap().evaluate(value)
+    }
+
+    #[test]
+    fn operators_follow_precedence() {
+        assert_eq!(evaluate("1 + 2 * 3", 0.), Some(7.));
+        assert_eq!(evaluate("10 - 4 - 3", 0.), Some(3.));
+        assert_eq!(evaluate("2 * 3 ** 2", 0.), Some(18.));
+        assert_eq!(evaluate("2 ** 3 ** 2", 0.), Some(64.));
+        assert_eq!(evaluate("7 // 2 + 7 % 2", 0.), Some(4.));
+        assert_eq!(evaluate("value / 4 * 2", 10.), Some(5.));
+    }
+
+    #[test]
+    fn unary_minus_binds_tighter_than_power() {
+        assert_eq!(evaluate("-2 ** 2", 0.), Some(4.));
+        assert_eq!(evaluate("3 - -value", 2.), Some(5.));
+        assert_eq!(evaluate("-value * 2", 3.), Some(-6.));
+        assert_eq!(evaluate("+value", 3.), Some(3.));
+    }
+
+    #[test]
+    fn parentheses_override_precedence() {
+        assert_eq!(evaluate("(1 + 2) * 3", 0.), Some(9.));
+        assert_eq!(evaluate("((value))", 5.), Some(5.));
+        assert_eq!(evaluate("-(value - 10)", 4.), Some(6.));
+    }
+
+    #[test]
+    fn functions_and_filters() {
+        assert_eq!(evaluate("float(value) + int(2.9)", 1.), Some(3.));
+        assert_eq!(evaluate("abs(value)", -4.5), Some(4.5));
+        assert_eq!(evaluate("round(value)", 2.5), Some(3.));
+        assert_eq!(evaluate("min(value, 3, 8)", 5.), Some(3.));
+        assert_eq!(evaluate("max(value, 3, 8)", 5.), Some(8.));
+        assert_eq!(evaluate("value | abs", -2.), Some(2.));
+        assert_eq!(evaluate("value | round(2)", 1.23456), Some(1.23));
+        assert_eq!(evaluate("-1 | abs", 0.), Some(-1.));
+        assert_eq!(evaluate("value * 2.4 | round", 1.5), Some(3.));
+    }
+
+    #[test]
+    fn template_braces_are_optional() {
+        let source = "{{ (float(value) * 3.3 / (2**12 - 1)) | round(3) }}";
+        assert_eq!(evaluate(source, 1000.), Some(0.806));
+        assert_eq!(evaluate("  {{value}}  ", 12.), Some(12.));
+        assert_eq!(evaluate("1_000 + value", 1.), Some(1001.));
+    }
+
+    #[test]
+    fn malformed_expressions_are_rejected() {
+        for source in &[
+            "",
+            "1 +",
+            "(1 + 2",
+            "1 + 2)",
+            "value value",
+            "temperature",
+            "sqrt(value)",
+            "abs(1, 2)",
+            "min(1)",
+            "round(value,)",
+            "value | 3",
+            "1.2.3",
+            "value & 1",
+            "{{ value",
+        ] {
+            assert!(Expression::parse(source).is_err(), "{}", source);
+        }
+    }
+
+    #[test]
+    fn non_finite_result_is_none() {
+        assert_eq!(evaluate("1 / value", 0.), None);
+        assert_eq!(evaluate("value // 0", 1.), None);
+        assert_eq!(evaluate("value % 0", 1.), None);
+        assert_eq!(evaluate("(value - 1) ** 0.5", 0.), None);
+        assert_eq!(evaluate("10 ** value", 400.), None);
+    }
+
+    #[test]
+    fn round_is_half_away_from_zero() {
+        assert_eq!(round(0.1 + 0.2, 3), 0.3);
+        assert_eq!(round(-2.5, 0), -3.);
+        assert_eq!(round(1234.5678, -2), 1200.);
+    }
+}
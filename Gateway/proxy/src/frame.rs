@@ -0,0 +1,100 @@
+use crate::error::Result;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+/// Frame header, payload length and CRC32 of the payload, both little endian.
+const FRAME_HEADER_LENGTH: usize = 8;
+
+/// Encodes a value as a checksummed frame of the append-only files.
+pub fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>> {
+    let payload = bincode::serialize(value)?;
+    let mut frame = Vec::with_capacity(FRAME_HEADER_LENGTH + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    frame.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+    frame.extend_from_slice(&payload);
+    Ok(frame)
+}
+
+/// Decodes frames up to the first incomplete or corrupted one, returns the values
+/// together with the length of the valid part.
+pub fn read_frames<T: DeserializeOwned>(path: &Path) -> Result<(Vec<T>, u64)> {
+    let mut buffer = Vec::new();
+    File::open(path)?.read_to_end(&mut buffer)?;
+    let mut values = Vec::new();
+    let mut pos = 0;
+    while buffer.len() - pos >= FRAME_HEADER_LENGTH {
+        let header = &buffer[pos..pos + FRAME_HEADER_LENGTH];
+        let length = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+        let crc = u32::from_le_bytes(header[4..].try_into().unwrap());
+        let payload =
+            match buffer.get(pos + FRAME_HEADER_LENGTH..pos + FRAME_HEADER_LENGTH + length) {
+                Some(payload) if crc32fast::hash(payload) == crc => payload,
+                _ => break,
+            };
+        match bincode::deserialize(payload) {
+            Ok(value) => values.push(value),
+            Err(_) => break,
+        }
+        pos += FRAME_HEADER_LENGTH + length;
+    }
+    if pos != buffer.len() {
+        warn!("File {:?} has invalid data at byte {}", path, pos);
+    }
+    Ok((values, pos as u64))
+}
+
+/// Persists creation, removal or rename of entries in the directory.
+pub fn sync_dir(path: &Path) -> Result<()> {
+    File::open(path)?.sync_all()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::test_dir;
+    use std::fs;
+
+    fn write(name: &str, frames: &[Vec<u8>]) -> std::path::PathBuf {
+        let path = test_dir(name).join("frames");
+        fs::write(&path, frames.concat()).unwrap();
+        path
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        let values = vec![(1u32, "one".to_string()), (2, "two".to_string())];
+        let frames: Vec<_> = values.iter().map(|value| encode(value).unwrap()).collect();
+        let path = write("frame-round-trip", &frames);
+        let (read, valid) = read_frames::<(u32, String)>(&path).unwrap();
+        assert_eq!(read, values);
+        assert_eq!(valid, fs::metadata(&path).unwrap().len());
+    }
+
+    #[test]
+    fn reading_stops_at_a_corrupted_frame() {
+        let first = encode(&1u64).unwrap();
+        let mut second = encode(&2u64).unwrap();
+        let last = second.len() - 1;
+        second[last] ^= 0xff;
+        let third = encode(&3u64).unwrap();
+        let path = write("frame-corrupted", &[first.clone(), second, third]);
+        let (read, valid) = read_frames::<u64>(&path).unwrap();
+        assert_eq!(read, [1]);
+        assert_eq!(valid, first.len() as u64);
+    }
+
+    #[test]
+    fn reading_stops_at_a_truncated_frame() {
+        let first = encode(&"complete").unwrap();
+        let second = encode(&"torn").unwrap();
+        let path = write("frame-truncated", &[first.clone(), second[..5].to_vec()]);
+        let (read, valid) = read_frames::<String>(&path).unwrap();
+        assert_eq!(read, ["complete"]);
+        assert_eq!(valid, first.len() as u64);
+    }
+}
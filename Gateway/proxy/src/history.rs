@@ -0,0 +1,519 @@
+use crate::config::{History, HistoryTier};
+use crate::error::{Error, Result};
+use crate::frame::{self, read_frames};
+use crate::reading::{Quality, Reading};
+use crate::util::{
+    utc_day_start, utc_from_millis, HISTORY_SEGMENT_EXTENSION, HISTORY_SEGMENT_FORMAT,
+};
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Record {
+    /// Milliseconds since the Unix epoch, start of the bucket for aggregated records.
+    pub timestamp: i64,
+    pub node: u8,
+    pub quality: Quality,
+    pub values: RecordValues,
+}
+
+impl Record {
+    pub fn from_reading(reading: &Reading) -> Self {
+        Record {
+            timestamp: reading.timestamp.timestamp_millis(),
+            node: reading.node,
+            quality: reading.quality,
+            values: RecordValues::Raw(reading.measurements()),
+        }
+    }
+
+    pub fn time(&self) -> DateTime<Utc> {
+        utc_from_millis(self.timestamp)
+    }
+
+    /// Values as aggregates, a raw value becomes an aggregate of a single sample.
+    pub fn aggregates(&self) -> Vec<(&str, Aggregate)> {
+        match &self.values {
+            RecordValues::Raw(values) => values
+                .iter()
+                .map(|(name, value)| (name.as_str(), Aggregate::new(*value)))
+                .collect(),
+            RecordValues::Aggregated(aggregates) => aggregates
+                .iter()
+                .map(|(name, aggregate)| (name.as_str(), *aggregate))
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum RecordValues {
+    Raw(Vec<(String, f64)>),
+    Aggregated(Vec<(String, Aggregate)>),
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Aggregate {
+    pub min: f64,
+    pub max: f64,
+    pub sum: f64,
+    pub count: u32,
+    pub last: f64,
+}
+
+impl Aggregate {
+    pub fn new(value: f64) -> Self {
+        Aggregate {
+            min: value,
+            max: value,
+            sum: value,
+            count: 1,
+            last: value,
+        }
+    }
+
+    /// Merges a later aggregate into this one.
+    pub fn merge(&mut self, other: &Aggregate) {
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        self.sum += other.sum;
+        self.count += other.count;
+        self.last = other.last;
+    }
+
+    pub fn mean(&self) -> f64 {
+        self.sum / self.count.max(1) as f64
+    }
+}
+
+/// Append-only store of readings, one directory per tier with a segment file per UTC day.
+///
+/// Every frame is synced before `append` returns, a torn frame at the end of the newest
+/// segment is truncated on open. Buckets of downsampled tiers are written once complete,
+/// the unfinished ones are rebuilt from the raw tier after a restart.
+///
+/// A read only store never touches the files, so it can be opened next to the running
+/// proxy. Buckets the proxy did not write yet are rebuilt in memory instead.
+pub struct HistoryStore {
+    tiers: Vec<TierStore>,
+}
+
+struct TierStore {
+    conf: HistoryTier,
+    dir: PathBuf,
+    segment: Option<(NaiveDate, File)>,
+    pending: HashMap<u8, Record>,
+    read_only: bool,
+    unwritten: Vec<Record>,
+}
+
+impl HistoryStore {
+    pub fn open(conf: &History) -> Result<Self> {
+        Self::open_with(conf, false)
+    }
+
+    pub fn open_read_only(conf: &History) -> Result<Self> {
+        Self::open_with(conf, true)
+    }
+
+    fn open_with(conf: &History, read_only: bool) -> Result<Self> {
+        let mut tiers = Vec::with_capacity(conf.tiers.len());
+        for tier in &conf.tiers {
+            let dir = Path::new(&conf.path).join(&tier.name);
+            let store = TierStore {
+                conf: tier.clone(),
+                dir,
+                segment: None,
+                pending: HashMap::new(),
+                read_only,
+                unwritten: Vec::new(),
+            };
+            if !read_only {
+                fs::create_dir_all(&store.dir)?;
+                store.repair()?;
+                store.prune(Utc::now().date_naive())?;
+            }
+            tiers.push(store);
+        }
+        let mut store = HistoryStore { tiers };
+        store.replay()?;
+        Ok(store)
+    }
+
+    pub fn append(&mut self, reading: &Reading) -> Result<()> {
+        let record = Record::from_reading(reading);
+        for tier in self.tiers.iter_mut() {
+            tier.append(&record)?;
+        }
+        Ok(())
+    }
+
+    pub fn tiers(&self) -> impl Iterator<Item = &HistoryTier> {
+        self.tiers.iter().map(|tier| &tier.conf)
+    }
+
+    /// Records of the tier within `[from, to)`, including the unfinished buckets.
+    pub fn read(&self, tier: &str, from: DateTime<Utc>, to: DateTime<Utc>) -> Result<Vec<Record>> {
+        let tier = self
+            .tiers
+            .iter()
+            .find(|store| store.conf.name == tier)
+            .ok_or_else(|| Error::new_history(format!("Unknown history tier '{}'", tier)))?;
+        let (from, to) = (from.timestamp_millis(), to.timestamp_millis());
+        let mut records = tier.read(from, to)?;
+        records.extend(
+            tier.unwritten
+                .iter()
+                .chain(tier.pending.values())
+                .filter(|record| record.timestamp >= from && record.timestamp < to)
+                .cloned(),
+        );
+        Ok(records)
+    }
+
+    /// Feeds raw records newer than the last written bucket into the downsampled tiers.
+    fn replay(&mut self) -> Result<()> {
+        let raw = match self.tiers.iter().position(|tier| tier.conf.is_raw()) {
+            Some(raw) => raw,
+            None => return Ok(()),
+        };
+        for num in 0..self.tiers.len() {
+            if num == raw {
+                continue;
+            }
+            let resolution = self.tiers[num].conf.resolution as i64 * 1000;
+            let from = match self.tiers[num].last_record()? {
+                Some(record) => record.timestamp + resolution,
+                None => i64::MIN,
+            };
+            let records = self.tiers[raw].read(from, i64::MAX)?;
+            debug!(
+                "Replaying {} raw records into history tier {}",
+                records.len(),
+                self.tiers[num].conf.name
+            );
+            for record in &records {
+                self.tiers[num].append(record)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl TierStore {
+    fn append(&mut self, record: &Record) -> Result<()> {
+        if self.conf.is_raw() {
+            return self.write(record);
+        }
+        let values = match &record.values {
+            RecordValues::Raw(values) if record.quality != Quality::Bad => values,
+            _ => return Ok(()),
+        };
+        let resolution = self.conf.resolution as i64 * 1000;
+        let start = record.timestamp - record.timestamp.rem_euclid(resolution);
+        let finished = match self.pending.get(&record.node) {
+            Some(pending) if pending.timestamp != start => self.pending.remove(&record.node),
+            _ => None,
+        };
+        if let Some(finished) = finished {
+            self.write(&finished)?;
+        }
+
+        let bucket = self.pending.entry(record.node).or_insert_with(|| Record {
+            timestamp: start,
+            node: record.node,
+            quality: Quality::Good,
+            values: RecordValues::Aggregated(Vec::new()),
+        });
+        bucket.quality = bucket.quality.max(record.quality);
+        if let RecordValues::Aggregated(aggregates) = &mut bucket.values {
+            for (name, value) in values {
+                let value = Aggregate::new(*value);
+                match aggregates.iter_mut().find(|(key, _)| key == name) {
+                    Some((_, aggregate)) => aggregate.merge(&value),
+                    None => aggregates.push((name.clone(), value)),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, record: &Record) -> Result<()> {
+        if self.read_only {
+            self.unwritten.push(record.clone());
+            return Ok(());
+        }
+        let date = record.time().date_naive();
+        let current = match &self.segment {
+            Some((segment_date, _)) => *segment_date == date,
+            None => false,
+        };
+        if !current {
+            let path = self.segment_path(date);
+            let created = !path.exists();
+            let file = OpenOptions::new().create(true).append(true).open(&path)?;
+            if created {
+                frame::sync_dir(&self.dir)?;
+                self.prune(date)?;
+            }
+            self.segment = Some((date, file));
+        }
+
+        let frame = frame::encode(record)?;
+        if let Some((_, file)) = &mut self.segment {
+            file.write_all(&frame)?;
+            file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    fn read(&self, from: i64, to: i64) -> Result<Vec<Record>> {
+        let mut records = Vec::new();
+        for (date, path) in self.segments()? {
+            let start = utc_day_start(date).timestamp_millis();
+            let end = start + Duration::days(1).num_milliseconds();
+            if end <= from || start >= to {
+                continue;
+            }
+            let (segment, _) = read_frames::<Record>(&path)?;
+            records.extend(
+                segment
+                    .into_iter()
+                    .filter(|record| record.timestamp >= from && record.timestamp < to),
+            );
+        }
+        Ok(records)
+    }
+
+    fn last_record(&self) -> Result<Option<Record>> {
+        for (_, path) in self.segments()?.iter().rev() {
+            let (mut records, _) = read_frames(path)?;
+            if let Some(record) = records.pop() {
+                return Ok(Some(record));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Drops an incomplete frame left behind by a power loss during the last write.
+    fn repair(&self) -> Result<()> {
+        if let Some((_, path)) = self.segments()?.pop() {
+            let (_, valid) = read_frames::<Record>(&path)?;
+            let file = OpenOptions::new().write(true).open(&path)?;
+            if file.metadata()?.len() > valid {
+                warn!(
+                    "Truncating torn history segment {:?} to {} bytes",
+                    path, valid
+                );
+                file.set_len(valid)?;
+                file.sync_all()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn prune(&self, today: NaiveDate) -> Result<()> {
+        let oldest = today - Duration::days(self.conf.retention as i64);
+        for (date, path) in self.segments()? {
+            if date < oldest {
+                info!("Removing expired history segment {:?}", path);
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Segment files sorted from the oldest day.
+    fn segments(&self) -> Result<Vec<(NaiveDate, PathBuf)>> {
+        let mut segments = Vec::new();
+        if !self.dir.exists() {
+            return Ok(segments);
+        }
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some(HISTORY_SEGMENT_EXTENSION) {
+                continue;
+            }
+            let date = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| NaiveDate::parse_from_str(stem, HISTORY_SEGMENT_FORMAT).ok());
+            if let Some(date) = date {
+                segments.push((date, path));
+            }
+        }
+        segments.sort();
+        Ok(segments)
+    }
+
+    fn segment_path(&self, date: NaiveDate) -> PathBuf {
+        self.dir
+            .join(date.format(HISTORY_SEGMENT_FORMAT).to_string())
+            .with_extension(HISTORY_SEGMENT_EXTENSION)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::test_dir;
+    use std::io::Read;
+
+    fn conf(name: &str) -> History {
+        let path = test_dir(name).display().to_string();
+        serde_yaml::from_str(&format!(
+            "path: {}\ntiers:\n\
+             - {{name: raw, resolution: 0, retention: 7}}\n\
+             - {{name: 5min, resolution: 300, retention: 30}}",
+            path
+        ))
+        .unwrap()
+    }
+
+    /// Start of a 5 minute bucket an hour ago.
+    fn bucket_start() -> DateTime<Utc> {
+        let now = Utc::now().timestamp();
+        utc_from_millis((now - 3600 - now.rem_euclid(300)) * 1000)
+    }
+
+    fn reading(time: DateTime<Utc>, temperature: f64) -> Reading {
+        let mut reading = Reading::sample();
+        reading.timestamp = time;
+        reading.temperature = temperature;
+        reading
+    }
+
+    fn temperatures(records: &[Record]) -> Vec<(i64, Aggregate)> {
+        records
+            .iter()
+            .filter_map(|record| {
+                let (_, aggregate) = record
+                    .aggregates()
+                    .into_iter()
+                    .find(|(name, _)| *name == "temperature")?;
+                Some((record.timestamp, aggregate))
+            })
+            .collect()
+    }
+
+    fn all(store: &HistoryStore, tier: &str) -> Vec<Record> {
+        let from = Utc::now() - Duration::days(60);
+        store.read(tier, from, Utc::now()).unwrap()
+    }
+
+    #[test]
+    fn records_survive_reopening() {
+        let conf = conf("history-reopen");
+        let start = bucket_start();
+        let mut store = HistoryStore::open(&conf).unwrap();
+        store.append(&reading(start, 20.)).unwrap();
+        store
+            .append(&reading(start + Duration::seconds(60), 21.))
+            .unwrap();
+        drop(store);
+
+        let store = HistoryStore::open_read_only(&conf).unwrap();
+        let raw = temperatures(&all(&store, "raw"));
+        assert_eq!(raw.len(), 2);
+        assert_eq!(raw[0].0, start.timestamp_millis());
+        assert_eq!(raw[1].1.last, 21.);
+    }
+
+    #[test]
+    fn torn_tail_is_truncated() {
+        let conf = conf("history-torn");
+        let start = bucket_start();
+        let mut store = HistoryStore::open(&conf).unwrap();
+        store.append(&reading(start, 20.)).unwrap();
+        drop(store);
+
+        let segment = fs::read_dir(Path::new(&conf.path).join("raw"))
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap()
+            .path();
+        let valid = fs::metadata(&segment).unwrap().len();
+        // Half of a frame, as left behind by a power loss during the write.
+        let frame = frame::encode(&Record::from_reading(&reading(start, 30.))).unwrap();
+        let mut file = OpenOptions::new().append(true).open(&segment).unwrap();
+        file.write_all(&frame[..frame.len() / 2]).unwrap();
+        drop(file);
+
+        let mut store = HistoryStore::open(&conf).unwrap();
+        assert_eq!(fs::metadata(&segment).unwrap().len(), valid);
+        store
+            .append(&reading(start + Duration::seconds(60), 21.))
+            .unwrap();
+        let raw = temperatures(&all(&store, "raw"));
+        assert_eq!(
+            raw.iter().map(|(_, t)| t.last).collect::<Vec<_>>(),
+            [20., 21.]
+        );
+    }
+
+    #[test]
+    fn expired_segments_are_pruned() {
+        let conf = conf("history-prune");
+        let now = Utc::now();
+        let mut store = HistoryStore::open(&conf).unwrap();
+        store
+            .append(&reading(now - Duration::days(10), 18.))
+            .unwrap();
+        store
+            .append(&reading(now - Duration::days(6), 19.))
+            .unwrap();
+        drop(store);
+
+        let store = HistoryStore::open(&conf).unwrap();
+        let raw = temperatures(&all(&store, "raw"));
+        assert_eq!(raw.iter().map(|(_, t)| t.last).collect::<Vec<_>>(), [19.]);
+        let segments = fs::read_dir(Path::new(&conf.path).join("raw")).unwrap();
+        assert_eq!(segments.count(), 1);
+        // The downsampled tier keeps its buckets for 30 days.
+        assert_eq!(temperatures(&all(&store, "5min")).len(), 2);
+    }
+
+    #[test]
+    fn unfinished_buckets_are_replayed_from_raw_records() {
+        let conf = conf("history-replay");
+        let start = bucket_start();
+        let mut store = HistoryStore::open(&conf).unwrap();
+        for (offset, temperature) in &[(0, 20.), (60, 24.), (120, 22.), (300, 25.), (360, 27.)] {
+            let time = start + Duration::seconds(*offset);
+            store.append(&reading(time, *temperature)).unwrap();
+        }
+        let mut bad = reading(start + Duration::seconds(420), 99.);
+        bad.quality = Quality::Bad;
+        store.append(&bad).unwrap();
+        drop(store);
+
+        // Only the first bucket is complete, the second one is rebuilt on open.
+        let mut written = Vec::new();
+        for entry in fs::read_dir(Path::new(&conf.path).join("5min")).unwrap() {
+            File::open(entry.unwrap().path())
+                .unwrap()
+                .read_to_end(&mut written)
+                .unwrap();
+        }
+        assert!(!written.is_empty());
+
+        let store = HistoryStore::open(&conf).unwrap();
+        let buckets = temperatures(&all(&store, "5min"));
+        assert_eq!(buckets.len(), 2);
+        let (time, first) = buckets[0];
+        assert_eq!(time, start.timestamp_millis());
+        assert_eq!((first.min, first.max, first.count), (20., 24., 3));
+        assert!((first.mean() - 22.).abs() < 1e-9);
+        let (time, second) = buckets[1];
+        assert_eq!(time, (start + Duration::seconds(300)).timestamp_millis());
+        assert_eq!(
+            (second.min, second.max, second.count, second.last),
+            (25., 27., 2, 27.)
+        );
+    }
+}
@@ -1,92 +1,244 @@
-use crate::config::{Config, Pin};
-use crate::data::Data;
+use crate::climate::Statistics;
+use crate::config::{Config, CounterSensor, DigitalPin, Discovery, Node, PayloadFormat, Pin};
 use crate::error::Result;
+use crate::indices::Indices;
+use crate::outbox::{reconnect, Connection, OutboxQueue, QueuedMessage};
+use crate::reading::Reading;
+use crate::senml;
+use crate::sink::{CommandStream, Sink};
 use crate::util::{
-    Shared, BATTERY_SENSOR, HUMIDITY_SENSOR, MQTT_URI, PAYLOAD_OFF, PAYLOAD_ON, PRESSURE_SENSOR,
-    TEMPERATURE_SENSOR,
+    convert_bool, utc_from_millis, Shared, BATTERY_CHARGING_SENSOR, BATTERY_CRITICAL_SENSOR,
+    BATTERY_LEVEL_SENSOR, BATTERY_LOW_SENSOR, BATTERY_SENSOR, HUMIDITY_SENSOR, INDICES,
+    INDICES_PERIODS, JSON_STATE_KEYS, NATIVE_VALUE_TEMPLATE, PAYLOAD_OFF, PAYLOAD_ON,
+    PRESSURE_SENSOR, RAIN_DAILY_SENSOR, RAIN_RATE_SENSOR, RAIN_SENSOR, READING_TIMESTAMP_SENSOR,
+    RSSI_SENSOR, STATISTICS_EXTREMES, STATISTICS_PERIODS, STATISTICS_QUANTITIES,
+    STATISTICS_TOPIC_PREFIX, TEMPERATURE_SENSOR, WIND_GUST_SENSOR, WIND_SPEED_SENSOR,
 };
-use async_std::task::block_on;
+use async_trait::async_trait;
 use futures::compat::{Future01CompatExt, Stream01CompatExt};
 use futures::StreamExt;
 use paho_mqtt::{AsyncClient, AsyncClientBuilder, ConnectOptions, Message};
-use std::result::Result as StdResult;
+use serde_json::{Map, Value};
 
-macro_rules! mqtt_publish {
-    ($mqtt:expr,$topic:expr, $payload:expr) => {
-        let msg = Message::new($topic, $payload, 0);
-        $mqtt.publish(msg).compat().await?;
+macro_rules! queue_message {
+    ($messages:expr, $timestamp:expr, $topic:expr, $payload:expr) => {
+        $messages.push(QueuedMessage::new($timestamp, $topic, $payload));
     };
 }
 
 pub struct HomeAssistant {
+    name: String,
     mqtt: AsyncClient,
     conf: Shared<Config>,
+    outbox: OutboxQueue,
+    initialized: bool,
+    json_topic: Option<String>,
+    json_discovery: bool,
+    format: PayloadFormat,
 }
 
 impl HomeAssistant {
-    pub async fn new(conf: Shared<Config>) -> Result<Self> {
-        let mqtt = AsyncClientBuilder::new().server_uri(MQTT_URI).finalize();
-        mqtt.connect(ConnectOptions::new()).compat().await?;
-        let result = HomeAssistant { mqtt, conf };
-        result.init_topics().await?;
-        Ok(result)
+    pub fn new(
+        name: String,
+        uri: &str,
+        json_topic: Option<String>,
+        json_discovery: bool,
+        format: PayloadFormat,
+        conf: Shared<Config>,
+        outbox: OutboxQueue,
+    ) -> Self {
+        let mqtt = AsyncClientBuilder::new().server_uri(uri).finalize();
+        HomeAssistant {
+            name,
+            mqtt,
+            conf,
+            outbox,
+            initialized: false,
+            json_topic,
+            json_discovery,
+            format,
+        }
     }
 
-    pub async fn update_state(&self, data: &Data) -> Result<()> {
-        debug!("Data for MQTT {:?}", data);
+    async fn messages(&self, reading: &Reading) -> Result<Vec<QueuedMessage>> {
+        let data = &reading.data;
+        let battery = &reading.battery;
+        let timestamp = reading.timestamp.timestamp_millis();
+        let mut messages = Vec::with_capacity(32);
         let conf = self.conf.lock().await;
+        queue_message!(
+            messages,
+            timestamp,
+            READING_TIMESTAMP_SENSOR.state_topic(),
+            reading.timestamp.to_rfc3339()
+        );
+        if let Some(rssi) = reading.rssi {
+            queue_message!(
+                messages,
+                timestamp,
+                RSSI_SENSOR.state_topic(),
+                format!("{:.1}", rssi)
+            );
+        }
         let digital = data.gpio_value;
         for pin in conf.node().digital() {
             let (topic, num) = pin.topic_number_tuple();
-            mqtt_publish!(self.mqtt, topic, convert_digital!(num, digital));
+            if let DigitalPin::Counter { .. } = pin {
+                let count = data.counter_value[num as usize];
+                queue_message!(messages, timestamp, topic, count.to_string());
+            } else {
+                queue_message!(messages, timestamp, topic, convert_digital!(num, digital));
+            }
         }
         for pin in conf.node().analog() {
             if !pin.enabled {
                 continue;
             }
             let (topic, num) = pin.topic_number_tuple();
-            mqtt_publish!(self.mqtt, topic, data.adc_value[num as usize].to_string());
+            queue_message!(
+                messages,
+                timestamp,
+                topic,
+                data.adc_value[num as usize].to_string()
+            );
+            if let Some(value) = reading.analog[num as usize] {
+                queue_message!(messages, timestamp, &pin.value_topic, value.to_string());
+            }
         }
-        mqtt_publish!(
-            self.mqtt,
+        queue_message!(
+            messages,
+            timestamp,
             BATTERY_SENSOR.state_topic(),
-            data.bat_value.to_string()
+            format!("{:.3}", battery.voltage)
+        );
+        queue_message!(
+            messages,
+            timestamp,
+            BATTERY_LEVEL_SENSOR.state_topic(),
+            format!("{:.0}", battery.percent)
         );
-        mqtt_publish!(
-            self.mqtt,
+        queue_message!(
+            messages,
+            timestamp,
+            BATTERY_LOW_SENSOR.state_topic(),
+            convert_bool(battery.is_low())
+        );
+        queue_message!(
+            messages,
+            timestamp,
+            BATTERY_CRITICAL_SENSOR.state_topic(),
+            convert_bool(battery.is_critical())
+        );
+        if battery.charge.is_some() {
+            queue_message!(
+                messages,
+                timestamp,
+                BATTERY_CHARGING_SENSOR.state_topic(),
+                convert_bool(battery.is_charging())
+            );
+        }
+        queue_message!(
+            messages,
+            timestamp,
             TEMPERATURE_SENSOR.state_topic(),
-            data.temperature.to_string()
+            format!("{:.2}", reading.temperature)
         );
-        mqtt_publish!(
-            self.mqtt,
+        queue_message!(
+            messages,
+            timestamp,
             PRESSURE_SENSOR.state_topic(),
-            data.pressure.to_string()
+            format!("{:.2}", reading.pressure)
         );
-        mqtt_publish!(
-            self.mqtt,
+        queue_message!(
+            messages,
+            timestamp,
             HUMIDITY_SENSOR.state_topic(),
-            data.humidity.to_string()
+            format!("{:.2}", reading.humidity)
         );
-        Ok(())
-    }
-
-    pub fn stream(&mut self) -> impl StreamExt<Item = StdResult<Option<Message>, ()>> {
-        self.mqtt.get_stream(50).compat()
+        if let Some(rain) = &reading.rain {
+            queue_message!(
+                messages,
+                timestamp,
+                RAIN_SENSOR.state_topic(),
+                format!("{:.2}", rain.amount)
+            );
+            queue_message!(
+                messages,
+                timestamp,
+                RAIN_RATE_SENSOR.state_topic(),
+                format!("{:.2}", rain.rate)
+            );
+            queue_message!(
+                messages,
+                timestamp,
+                RAIN_DAILY_SENSOR.state_topic(),
+                format!("{:.2}", rain.daily)
+            );
+        }
+        if let Some(wind) = &reading.wind {
+            queue_message!(
+                messages,
+                timestamp,
+                WIND_SPEED_SENSOR.state_topic(),
+                format!("{:.1}", wind.speed)
+            );
+            queue_message!(
+                messages,
+                timestamp,
+                WIND_GUST_SENSOR.state_topic(),
+                format!("{:.1}", wind.gust)
+            );
+        }
+        if let Some(statistics) = &reading.statistics {
+            for (topic, payload) in statistic_states(statistics) {
+                queue_message!(messages, timestamp, &topic, payload);
+            }
+        }
+        if let Some(indices) = &reading.indices {
+            for (topic, payload) in index_states(indices) {
+                queue_message!(messages, timestamp, &topic, payload);
+            }
+        }
+        if let Some(topic) = &self.json_topic {
+            let state = match self.format {
+                PayloadFormat::Native => json_state(reading, conf.node()).to_string().into_bytes(),
+                PayloadFormat::SenmlJson => senml::to_json(&senml::records(reading)),
+                PayloadFormat::SenmlCbor => senml::to_cbor(&senml::records(reading))?,
+            };
+            queue_message!(messages, timestamp, topic, state);
+        }
+        Ok(messages)
     }
 
     async fn init_topics(&self) -> Result<()> {
         let conf = self.conf.lock().await;
         for (topic, discovery) in &conf.node().discovery() {
-            let json = serde_json::to_string(discovery)?;
+            let json = match (&self.json_topic, json_key(conf.node(), discovery)) {
+                (Some(json_topic), Some(key)) if self.json_discovery => {
+                    json_discovery(discovery, json_topic, &key)?
+                }
+                _ => serde_json::to_string(discovery)?,
+            };
             debug!("Trying to configure {}, {}", topic, json);
             self.mqtt
                 .publish(Message::new(topic, json, 0))
                 .compat()
                 .await?;
         }
+        for sensor in statistic_sensors(&conf) {
+            let json = serde_json::to_string(&sensor.discovery())?;
+            debug!("Trying to configure {}, {}", sensor.config_topic, json);
+            self.mqtt
+                .publish(Message::new(&sensor.config_topic, json, 0))
+                .compat()
+                .await?;
+        }
         for topic in conf.node().subscribe_topics() {
             self.mqtt.subscribe(topic, 0).compat().await?;
         }
+        for topic in conf.calibration_topics() {
+            self.mqtt.subscribe(topic, 0).compat().await?;
+        }
         Ok(())
     }
 
@@ -99,19 +251,270 @@ impl HomeAssistant {
                 .compat()
                 .await?;
         }
+        for sensor in statistic_sensors(&conf) {
+            debug!("Trying to deconfigure {}", sensor.config_topic);
+            self.mqtt
+                .publish(Message::new(&sensor.config_topic, Vec::new(), 0))
+                .compat()
+                .await?;
+        }
         for topic in conf.node().subscribe_topics() {
             self.mqtt.unsubscribe(topic).compat().await?;
         }
+        for topic in conf.calibration_topics() {
+            self.mqtt.unsubscribe(topic).compat().await?;
+        }
         Ok(())
     }
 }
 
-impl Drop for HomeAssistant {
-    fn drop(&mut self) {
-        let result = block_on(self.drop_topics());
-        if let Err(err) = result {
-            eprintln!("{}", err);
-            error!("{:?}", err);
+#[async_trait]
+impl Sink for HomeAssistant {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn start(&mut self) -> Result<()> {
+        match self.mqtt.connect(ConnectOptions::new()).compat().await {
+            Ok(_) => {
+                self.init_topics().await?;
+                self.initialized = true;
+            }
+            Err(err) => warn!("MQTT broker unreachable, buffering: {:?}", err),
         }
+        Ok(())
     }
+
+    async fn publish(&mut self, reading: &Reading) -> Result<()> {
+        debug!("Data for MQTT {:?}", reading);
+        let connection = reconnect(&self.mqtt, &self.name).await;
+        if connection == Connection::Reconnected || (connection.is_up() && !self.initialized) {
+            // Subscriptions and discovery do not survive a broker restart.
+            self.init_topics().await?;
+            self.initialized = true;
+        }
+        let messages = self.messages(reading).await?;
+        self.outbox.publish(&self.mqtt, connection, messages).await
+    }
+
+    async fn shutdown(&mut self) -> Result<()> {
+        if self.mqtt.is_connected() {
+            self.drop_topics().await?;
+        }
+        Ok(())
+    }
+
+    fn commands(&mut self) -> Option<CommandStream> {
+        Some(self.mqtt.get_stream(50).compat().boxed())
+    }
+}
+
+/// All values of the reading in one document, keyed as in `JSON_STATE_KEYS`.
+fn json_state(reading: &Reading, node: &Node) -> Value {
+    let mut values = Map::new();
+    values.insert("node".to_string(), Value::from(reading.node));
+    values.insert(
+        "timestamp".to_string(),
+        Value::from(reading.timestamp.to_rfc3339()),
+    );
+    if let Some(rssi) = reading.rssi {
+        values.insert("rssi".to_string(), Value::from(rssi));
+    }
+    values.insert(
+        "quality".to_string(),
+        Value::from(format!("{:?}", reading.quality).to_lowercase()),
+    );
+    for (name, value) in reading.measurements() {
+        values.insert(name, Value::from(value));
+    }
+    let battery = &reading.battery;
+    values.insert("battery_low".to_string(), Value::from(battery.is_low()));
+    values.insert(
+        "battery_critical".to_string(),
+        Value::from(battery.is_critical()),
+    );
+    if battery.charge.is_some() {
+        values.insert(
+            "battery_charging".to_string(),
+            Value::from(battery.is_charging()),
+        );
+    }
+    let mut units = Map::new();
+    for (_, discovery) in &node.discovery() {
+        if let Discovery::Sensor {
+            unit_of_measurement,
+            ..
+        } = discovery
+        {
+            match json_key(node, discovery) {
+                Some(key) if !unit_of_measurement.is_empty() => {
+                    units.insert(key, Value::from(*unit_of_measurement));
+                }
+                _ => (),
+            }
+        }
+    }
+    values.insert("units".to_string(), Value::Object(units));
+    Value::Object(values)
+}
+
+/// Key of the sensor in the JSON state document, pins other than analog are not included.
+fn json_key(node: &Node, discovery: &Discovery) -> Option<String> {
+    let state_topic = discovery.state_topic();
+    if let Some(pin) = node.analog().find(|pin| pin.value_topic == state_topic) {
+        return Some(format!("analog_{}", pin.number));
+    }
+    JSON_STATE_KEYS
+        .iter()
+        .find(|(sensor, _)| sensor.state_topic() == state_topic)
+        .map(|(_, key)| key.to_string())
+}
+
+/// Discovery reading the sensor from the JSON state document.
+fn json_discovery(discovery: &Discovery, json_topic: &str, key: &str) -> Result<String> {
+    let template = match discovery {
+        Discovery::BinarySensor { .. } => format!(
+            "{{{{ '{}' if value_json.{} else '{}' }}}}",
+            PAYLOAD_ON, key, PAYLOAD_OFF
+        ),
+        _ => format!("{{{{ value_json.{} }}}}", key),
+    };
+    let mut json = serde_json::to_value(discovery)?;
+    json["state_topic"] = Value::from(json_topic);
+    json["value_template"] = Value::from(template);
+    json["json_attributes_topic"] = Value::from(json_topic);
+    Ok(json.to_string())
+}
+
+/// Sensor of one daily, monthly or all-time statistic.
+struct StatisticSensor {
+    config_topic: String,
+    name: String,
+    state_topic: String,
+    unit_of_measurement: &'static str,
+    device_class: Option<&'static str>,
+}
+
+impl StatisticSensor {
+    fn new(
+        id: &[&str],
+        name: String,
+        unit_of_measurement: &'static str,
+        device_class: Option<&'static str>,
+    ) -> Self {
+        StatisticSensor {
+            config_topic: format!("homeassistant/sensor/node/{}/config", id.join("_")),
+            name,
+            state_topic: statistic_topic(id),
+            unit_of_measurement,
+            device_class,
+        }
+    }
+
+    fn discovery(&self) -> Discovery {
+        Discovery::Sensor {
+            name: &self.name,
+            state_topic: &self.state_topic,
+            unit_of_measurement: self.unit_of_measurement,
+            value_template: NATIVE_VALUE_TEMPLATE,
+            device_class: self.device_class,
+        }
+    }
+}
+
+/// Extremes and their times of every period, rainfall only with a rain gauge, and the
+/// agricultural indices when configured. None without the climate summaries.
+fn statistic_sensors(conf: &Config) -> Vec<StatisticSensor> {
+    let mut sensors = Vec::new();
+    let climate = match conf.climate() {
+        Some(climate) => climate,
+        None => return sensors,
+    };
+    let rain = conf
+        .node()
+        .counter_sensors()
+        .any(|sensor| matches!(sensor, CounterSensor::RainGauge { .. }));
+    for (period, period_name) in &STATISTICS_PERIODS {
+        for (quantity, quantity_name, unit) in &STATISTICS_QUANTITIES {
+            for (extreme, extreme_name) in &STATISTICS_EXTREMES {
+                let name = format!("{} {} {}", period_name, quantity_name, extreme_name);
+                sensors.push(StatisticSensor::new(
+                    &[period, quantity, extreme],
+                    name.clone(),
+                    unit,
+                    None,
+                ));
+                sensors.push(StatisticSensor::new(
+                    &[period, quantity, extreme, "time"],
+                    format!("{} time", name),
+                    "",
+                    Some("timestamp"),
+                ));
+            }
+        }
+        if rain {
+            sensors.push(StatisticSensor::new(
+                &[period, "rain"],
+                format!("{} rainfall", period_name),
+                "mm",
+                None,
+            ));
+        }
+    }
+    if let Some(agriculture) = &climate.agriculture {
+        for (period, period_name) in &INDICES_PERIODS {
+            for (index, index_name, unit) in &INDICES {
+                if *index == "evapotranspiration" && agriculture.latitude.is_none() {
+                    continue;
+                }
+                sensors.push(StatisticSensor::new(
+                    &[period, index],
+                    format!("{} {}", period_name, index_name),
+                    unit,
+                    None,
+                ));
+            }
+        }
+    }
+    sensors
+}
+
+/// Values of the statistic sensors, times in RFC 3339.
+fn statistic_states(statistics: &Statistics) -> Vec<(String, String)> {
+    let mut states = Vec::new();
+    for ((period, _), extremes) in STATISTICS_PERIODS.iter().zip(&statistics.periods()) {
+        for ((quantity, _, _), stat) in STATISTICS_QUANTITIES.iter().zip(&extremes.quantities()) {
+            for ((extreme, _), (value, time)) in STATISTICS_EXTREMES.iter().zip(&stat.extremes()) {
+                states.push((
+                    statistic_topic(&[period, quantity, extreme]),
+                    format!("{:.2}", value),
+                ));
+                states.push((
+                    statistic_topic(&[period, quantity, extreme, "time"]),
+                    utc_from_millis(*time).to_rfc3339(),
+                ));
+            }
+        }
+        if let Some(rain) = extremes.rain {
+            states.push((statistic_topic(&[period, "rain"]), format!("{:.2}", rain)));
+        }
+    }
+    states
+}
+
+/// Values of the agricultural index sensors.
+fn index_states(indices: &Indices) -> Vec<(String, String)> {
+    let mut states = Vec::new();
+    for ((period, _), indices) in INDICES_PERIODS.iter().zip(&indices.periods()) {
+        for ((index, _, _), value) in INDICES.iter().zip(&indices.values()) {
+            if let Some(value) = value {
+                states.push((statistic_topic(&[period, index]), format!("{:.2}", value)));
+            }
+        }
+    }
+    states
+}
+
+fn statistic_topic(id: &[&str]) -> String {
+    format!("{}/{}/state", STATISTICS_TOPIC_PREFIX, id.join("/"))
 }
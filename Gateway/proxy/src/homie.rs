@@ -0,0 +1,388 @@
+use crate::config::{default_mqtt_uri, Config, CounterSensor, DigitalPin, Node, Pin};
+use crate::error::Result;
+use crate::outbox::{reconnect, Connection};
+use crate::reading::Reading;
+use crate::sink::{CommandStream, Sink};
+use crate::util::{Shared, COUNTER_UNIT, HOMIE_BASE_TOPIC, HOMIE_VERSION, PAYLOAD_OFF, PAYLOAD_ON};
+use async_trait::async_trait;
+use futures::compat::{Future01CompatExt, Stream01CompatExt};
+use futures::future;
+use futures::StreamExt;
+use paho_mqtt::{AsyncClient, AsyncClientBuilder, ConnectOptionsBuilder, Message};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Homie 4.0 device published under `base_topic`, `device` defaults to `node-<addr>`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Homie {
+    #[serde(default = "default_mqtt_uri")]
+    pub uri: String,
+    #[serde(default = "default_homie_base_topic")]
+    pub base_topic: String,
+    #[serde(default)]
+    pub device: Option<String>,
+    #[serde(default)]
+    pub device_name: Option<String>,
+}
+
+fn default_homie_base_topic() -> String {
+    HOMIE_BASE_TOPIC.to_string()
+}
+
+/// Publishes the node as a Homie 4.0 device.
+///
+/// Pins, the BME280, the battery and the counter sensors are Homie nodes, output pins
+/// are settable and their `/set` messages are passed on as `Node::update_output`
+/// commands. Values are retained, a lost connection is announced by the last will.
+pub struct HomieDevice {
+    name: String,
+    conf: Homie,
+    mqtt: AsyncClient,
+    shared_conf: Shared<Config>,
+    device_topic: String,
+    commands: HashMap<String, String>,
+    initialized: bool,
+}
+
+struct HomieNode {
+    id: &'static str,
+    name: &'static str,
+    kind: &'static str,
+    properties: Vec<Property>,
+}
+
+struct Property {
+    id: String,
+    name: String,
+    datatype: &'static str,
+    unit: String,
+    command_topic: Option<String>,
+    value: Option<String>,
+}
+
+impl Property {
+    fn new(id: &str, name: &str, datatype: &'static str, unit: &str) -> Self {
+        Property {
+            id: id.to_string(),
+            name: name.to_string(),
+            datatype,
+            unit: unit.to_string(),
+            command_topic: None,
+            value: None,
+        }
+    }
+
+    fn value<T: ToString>(mut self, value: Option<T>) -> Self {
+        self.value = value.map(|value| value.to_string());
+        self
+    }
+}
+
+impl HomieDevice {
+    pub fn new(name: String, conf: &Homie, shared_conf: Shared<Config>) -> Self {
+        let mqtt = AsyncClientBuilder::new().server_uri(&conf.uri).finalize();
+        HomieDevice {
+            name,
+            conf: conf.clone(),
+            mqtt,
+            shared_conf,
+            device_topic: String::new(),
+            commands: HashMap::new(),
+            initialized: false,
+        }
+    }
+
+    async fn publish_message(&self, topic: &str, payload: &str) -> Result<()> {
+        let topic = format!("{}/{}", self.device_topic, topic);
+        self.mqtt
+            .publish(Message::new_retained(topic, payload, 1))
+            .compat()
+            .await?;
+        Ok(())
+    }
+
+    /// Publishes the device, node and property attributes and subscribes to `/set`.
+    async fn init_device(&self) -> Result<()> {
+        let (device_name, nodes) = {
+            let conf = self.shared_conf.lock().await;
+            let device_name = match &self.conf.device_name {
+                Some(name) => name.clone(),
+                None => format!("Weather station node {}", conf.node().addr()),
+            };
+            (device_name, homie_nodes(conf.node(), None))
+        };
+        self.publish_message("$state", "init").await?;
+        self.publish_message("$homie", HOMIE_VERSION).await?;
+        self.publish_message("$name", &device_name).await?;
+        self.publish_message("$extensions", "").await?;
+        let ids: Vec<&str> = nodes.iter().map(|node| node.id).collect();
+        self.publish_message("$nodes", &ids.join(",")).await?;
+        for node in &nodes {
+            self.publish_message(&format!("{}/$name", node.id), node.name)
+                .await?;
+            self.publish_message(&format!("{}/$type", node.id), node.kind)
+                .await?;
+            let ids: Vec<&str> = node.properties.iter().map(|p| p.id.as_str()).collect();
+            self.publish_message(&format!("{}/$properties", node.id), &ids.join(","))
+                .await?;
+            for property in &node.properties {
+                let topic = format!("{}/{}", node.id, property.id);
+                self.publish_message(&format!("{}/$name", topic), &property.name)
+                    .await?;
+                self.publish_message(&format!("{}/$datatype", topic), property.datatype)
+                    .await?;
+                if !property.unit.is_empty() {
+                    self.publish_message(&format!("{}/$unit", topic), &property.unit)
+                        .await?;
+                }
+                if property.command_topic.is_some() {
+                    self.publish_message(&format!("{}/$settable", topic), "true")
+                        .await?;
+                }
+            }
+        }
+        for topic in self.commands.keys() {
+            self.mqtt.subscribe(topic.as_str(), 1).compat().await?;
+        }
+        self.publish_message("$state", "ready").await
+    }
+}
+
+#[async_trait]
+impl Sink for HomieDevice {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn start(&mut self) -> Result<()> {
+        {
+            let conf = self.shared_conf.lock().await;
+            let device = match &self.conf.device {
+                Some(device) => device.clone(),
+                None => format!("node-{}", conf.node().addr()),
+            };
+            self.device_topic = format!("{}/{}", self.conf.base_topic, device);
+            for node in homie_nodes(conf.node(), None) {
+                for property in node.properties {
+                    if let Some(command_topic) = property.command_topic {
+                        let topic =
+                            format!("{}/{}/{}/set", self.device_topic, node.id, property.id);
+                        self.commands.insert(topic, command_topic);
+                    }
+                }
+            }
+        }
+        let will = Message::new_retained(format!("{}/$state", self.device_topic), "lost", 1);
+        let options = ConnectOptionsBuilder::new().will_message(will).finalize();
+        match self.mqtt.connect(options).compat().await {
+            Ok(_) => {
+                self.init_device().await?;
+                self.initialized = true;
+            }
+            Err(err) => warn!("Homie broker unreachable: {:?}", err),
+        }
+        Ok(())
+    }
+
+    async fn publish(&mut self, reading: &Reading) -> Result<()> {
+        let connection = reconnect(&self.mqtt, &self.name).await;
+        if !connection.is_up() {
+            // Homie values are the current state, there is nothing to replay later.
+            return Ok(());
+        }
+        if connection == Connection::Reconnected || !self.initialized {
+            // The last will marked the device as lost.
+            self.init_device().await?;
+            self.initialized = true;
+        }
+        let nodes = {
+            let conf = self.shared_conf.lock().await;
+            homie_nodes(conf.node(), Some(reading))
+        };
+        for node in &nodes {
+            for property in &node.properties {
+                if let Some(value) = &property.value {
+                    self.publish_message(&format!("{}/{}", node.id, property.id), value)
+                        .await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn shutdown(&mut self) -> Result<()> {
+        if self.mqtt.is_connected() {
+            self.publish_message("$state", "disconnected").await?;
+        }
+        Ok(())
+    }
+
+    fn commands(&mut self) -> Option<CommandStream> {
+        let commands = self.commands.clone();
+        Some(
+            self.mqtt
+                .get_stream(50)
+                .compat()
+                .filter_map(move |result| {
+                    future::ready(match result {
+                        Ok(Some(message)) => command(&commands, &message).map(|m| Ok(Some(m))),
+                        Ok(None) => {
+                            warn!("Homie MQTT connection lost");
+                            None
+                        }
+                        Err(()) => Some(Err(())),
+                    })
+                })
+                .boxed(),
+        )
+    }
+}
+
+/// Translates a Homie `/set` message to the command topic of the output pin.
+fn command(commands: &HashMap<String, String>, message: &Message) -> Option<Message> {
+    let command_topic = commands.get(message.topic())?;
+    let payload = match message.payload_str().as_ref() {
+        "true" => PAYLOAD_ON,
+        "false" => PAYLOAD_OFF,
+        payload => {
+            warn!("Invalid Homie value {} for {}", payload, message.topic());
+            return None;
+        }
+    };
+    Some(Message::new(command_topic.as_str(), payload, 0))
+}
+
+/// Homie nodes of the node config, with the values of the reading when given.
+fn homie_nodes(node: &Node, reading: Option<&Reading>) -> Vec<HomieNode> {
+    let data = reading.map(|reading| &reading.data);
+    let mut nodes = Vec::with_capacity(6);
+
+    let mut digital: Vec<_> = node.digital_pins().collect();
+    digital.sort_by_key(|(_, pin)| pin.topic_number_tuple().1);
+    let mut properties = Vec::with_capacity(digital.len());
+    for (name, pin) in digital {
+        let (_, num) = pin.topic_number_tuple();
+        let id = format!("pin-{}", num);
+        let property = match pin {
+            DigitalPin::Counter { .. } => Property::new(&id, name, "integer", COUNTER_UNIT)
+                .value(data.map(|data| data.counter_value[num as usize])),
+            DigitalPin::Output { command_topic, .. } => Property {
+                command_topic: Some(command_topic.clone()),
+                ..Property::new(&id, name, "boolean", "")
+                    .value(data.map(|data| data.gpio_value & (1 << num) != 0))
+            },
+            DigitalPin::Input { .. } => Property::new(&id, name, "boolean", "")
+                .value(data.map(|data| data.gpio_value & (1 << num) != 0)),
+        };
+        properties.push(property);
+    }
+    if !properties.is_empty() {
+        nodes.push(HomieNode {
+            id: "digital",
+            name: "Digital pins",
+            kind: "pins",
+            properties,
+        });
+    }
+
+    let mut analog: Vec<_> = node.analog_pins().filter(|(_, pin)| pin.enabled).collect();
+    analog.sort_by_key(|(_, pin)| pin.number);
+    let mut properties = Vec::with_capacity(analog.len() * 2);
+    for (name, pin) in analog {
+        let num = pin.number as usize;
+        let id = format!("pin-{}", num);
+        properties.push(
+            Property::new(&id, name, "float", &pin.unit)
+                .value(reading.and_then(|reading| reading.analog[num])),
+        );
+        properties.push(
+            Property::new(
+                &format!("{}-raw", id),
+                &format!("{} raw", name),
+                "integer",
+                "",
+            )
+            .value(data.map(|data| data.adc_value[num])),
+        );
+    }
+    if !properties.is_empty() {
+        nodes.push(HomieNode {
+            id: "analog",
+            name: "Analog pins",
+            kind: "pins",
+            properties,
+        });
+    }
+
+    nodes.push(HomieNode {
+        id: "environment",
+        name: "Environment",
+        kind: "BME280",
+        properties: vec![
+            Property::new("temperature", "Temperature", "float", "°C")
+                .value(reading.map(|reading| reading.temperature)),
+            Property::new("pressure", "Pressure", "float", "hPa")
+                .value(reading.map(|reading| reading.pressure)),
+            Property::new("humidity", "Humidity", "float", "%")
+                .value(reading.map(|reading| reading.humidity)),
+        ],
+    });
+
+    let battery = reading.map(|reading| &reading.battery);
+    let mut properties = vec![
+        Property::new("voltage", "Voltage", "float", "V")
+            .value(battery.map(|battery| battery.voltage)),
+        Property::new("level", "Level", "float", "%").value(battery.map(|battery| battery.percent)),
+        Property::new("low", "Low", "boolean", "").value(battery.map(|battery| battery.is_low())),
+        Property::new("critical", "Critical", "boolean", "")
+            .value(battery.map(|battery| battery.is_critical())),
+    ];
+    if node.battery().solar {
+        properties.push(
+            Property::new("charging", "Charging", "boolean", "")
+                .value(battery.map(|battery| battery.is_charging())),
+        );
+    }
+    nodes.push(HomieNode {
+        id: "battery",
+        name: "Battery",
+        kind: "battery",
+        properties,
+    });
+
+    for sensor in node.counter_sensors() {
+        match sensor {
+            CounterSensor::RainGauge { .. } => {
+                let rain = reading.and_then(|reading| reading.rain.as_ref());
+                nodes.push(HomieNode {
+                    id: "rain",
+                    name: "Rain gauge",
+                    kind: "rain gauge",
+                    properties: vec![
+                        Property::new("amount", "Amount", "float", "mm")
+                            .value(rain.map(|rain| rain.amount)),
+                        Property::new("rate", "Rate", "float", "mm/h")
+                            .value(rain.map(|rain| rain.rate)),
+                        Property::new("daily", "Today", "float", "mm")
+                            .value(rain.map(|rain| rain.daily)),
+                    ],
+                });
+            }
+            CounterSensor::Anemometer { .. } => {
+                let wind = reading.and_then(|reading| reading.wind.as_ref());
+                nodes.push(HomieNode {
+                    id: "wind",
+                    name: "Anemometer",
+                    kind: "anemometer",
+                    properties: vec![
+                        Property::new("speed", "Speed", "float", "m/s")
+                            .value(wind.map(|wind| wind.speed)),
+                        Property::new("gust", "Gust", "float", "m/s")
+                            .value(wind.map(|wind| wind.gust)),
+                    ],
+                });
+            }
+        }
+    }
+    nodes
+}
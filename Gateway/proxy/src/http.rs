@@ -0,0 +1,109 @@
+use crate::error::{Error, Result};
+use crate::util::HTTP_TIMEOUT;
+use std::convert::TryFrom;
+use std::time::Duration;
+use surf::{Client, Config, Response, Url};
+
+/// Client for the HTTP outputs, requests time out after `HTTP_TIMEOUT` seconds.
+pub fn client() -> Client {
+    client_with_timeout(HTTP_TIMEOUT)
+}
+
+/// Every request opens a new connection, the outputs send a request every few minutes
+/// and a pooled connection is usually closed by the server by then, failing the next
+/// request with a connection reset.
+pub fn client_with_timeout(timeout: u64) -> Client {
+    let config = Config::new()
+        .set_timeout(Some(Duration::from_secs(timeout)))
+        .set_http_keep_alive(false);
+    match Client::try_from(config) {
+        Ok(client) => client,
+        Err(err) => match err {},
+    }
+}
+
+pub fn parse_url(url: &str) -> Result<Url> {
+    Url::parse(url).map_err(|err| Error::new_config(format!("Invalid URL '{}': {}", url, err)))
+}
+
+/// Reads the whole response body, a status outside of the 2xx range is returned as an
+/// error.
+pub async fn read_body(mut response: Response) -> Result<String> {
+    let body = response.body_string().await?;
+    if !response.status().is_success() {
+        return Err(Error::new_http(format!(
+            "{} {}",
+            response.status(),
+            body.trim()
+        )));
+    }
+    Ok(body)
+}
+
+/// Local HTTP server standing in for the remote services in tests.
+#[cfg(test)]
+pub mod stand_in {
+    use async_std::io::prelude::*;
+    use async_std::net::TcpListener;
+    use async_std::task::{self, JoinHandle};
+
+    pub struct Request {
+        /// Request line and headers.
+        pub head: String,
+        pub body: String,
+    }
+
+    impl Request {
+        /// Path with the query of the request line.
+        pub fn target(&self) -> &str {
+            self.head.split(' ').nth(1).unwrap_or_default()
+        }
+    }
+
+    /// Answers one request with each status and body in turn, returns the base URL and
+    /// the received requests once all responses are sent.
+    pub async fn serve(responses: Vec<(u16, &'static str)>) -> (String, JoinHandle<Vec<Request>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let handle = task::spawn(async move {
+            let mut requests = Vec::new();
+            for (status, body) in responses {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buffer = Vec::new();
+                let mut chunk = [0; 4096];
+                let head_end = loop {
+                    let read = stream.read(&mut chunk).await.unwrap();
+                    assert!(read > 0, "connection closed before the request head");
+                    buffer.extend_from_slice(&chunk[..read]);
+                    if let Some(end) = buffer.windows(4).position(|w| w == b"\r\n\r\n") {
+                        break end + 4;
+                    }
+                };
+                let head = String::from_utf8_lossy(&buffer[..head_end]).to_string();
+                let length = head
+                    .lines()
+                    .filter_map(|line| line.split_once(':'))
+                    .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+                    .map_or(0, |(_, value)| value.trim().parse().unwrap());
+                while buffer.len() < head_end + length {
+                    let read = stream.read(&mut chunk).await.unwrap();
+                    assert!(read > 0, "connection closed before the request body");
+                    buffer.extend_from_slice(&chunk[..read]);
+                }
+                let response = format!(
+                    "HTTP/1.1 {} Stand-in\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+                requests.push(Request {
+                    head,
+                    body: String::from_utf8_lossy(&buffer[head_end..]).to_string(),
+                });
+            }
+            requests
+        });
+        (url, handle)
+    }
+}
@@ -0,0 +1,82 @@
+use crate::climate::Stat;
+use crate::config::Agriculture;
+use chrono::{Datelike, NaiveDate};
+use std::f64::consts::PI;
+
+/// Degree days in °C·d and the reference evapotranspiration in mm of one day or the sum
+/// of several.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DayIndices {
+    pub growing: f64,
+    pub heating: f64,
+    pub cooling: f64,
+    /// Missing without the latitude of the station.
+    pub evapotranspiration: Option<f64>,
+}
+
+impl DayIndices {
+    /// Indices from the temperature extremes of the day, the current day uses the
+    /// extremes so far.
+    pub fn new(conf: &Agriculture, date: NaiveDate, temperature: &Stat) -> Self {
+        let mean = (temperature.max + temperature.min) / 2.;
+        let clamp = |value: f64| value.max(conf.growing_base).min(conf.growing_cap);
+        DayIndices {
+            growing: (clamp(temperature.max) + clamp(temperature.min)) / 2. - conf.growing_base,
+            heating: (conf.heating_base - mean).max(0.),
+            cooling: (mean - conf.cooling_base).max(0.),
+            evapotranspiration: conf
+                .latitude
+                .map(|latitude| hargreaves(latitude, date, temperature.min, temperature.max)),
+        }
+    }
+
+    /// Values in the order of `INDICES`.
+    pub fn values(&self) -> [Option<f64>; 4] {
+        [
+            Some(self.growing),
+            Some(self.heating),
+            Some(self.cooling),
+            self.evapotranspiration,
+        ]
+    }
+
+    pub fn add(&mut self, other: &DayIndices) {
+        self.growing += other.growing;
+        self.heating += other.heating;
+        self.cooling += other.cooling;
+        if let Some(evapotranspiration) = other.evapotranspiration {
+            *self.evapotranspiration.get_or_insert(0.) += evapotranspiration;
+        }
+    }
+}
+
+/// Indices of the current day and their sums over the season including it.
+#[derive(Debug, Clone, Copy)]
+pub struct Indices {
+    pub today: DayIndices,
+    pub season: DayIndices,
+}
+
+impl Indices {
+    /// Indices in the order of `INDICES_PERIODS`.
+    pub fn periods(&self) -> [&DayIndices; 2] {
+        [&self.today, &self.season]
+    }
+}
+
+/// Reference evapotranspiration in mm/day of Hargreaves and Samani, with the
+/// extraterrestrial radiation of FAO-56.
+fn hargreaves(latitude: f64, date: NaiveDate, min: f64, max: f64) -> f64 {
+    let day = date.ordinal() as f64;
+    let latitude = latitude.to_radians();
+    let distance = 1. + 0.033 * (2. * PI * day / 365.).cos();
+    let declination = 0.409 * (2. * PI * day / 365. - 1.39).sin();
+    let sunset = (-latitude.tan() * declination.tan()).clamp(-1., 1.).acos();
+    let radiation = 24. * 60. / PI
+        * 0.0820
+        * distance
+        * (sunset * latitude.sin() * declination.sin()
+            + latitude.cos() * declination.cos() * sunset.sin());
+    // 0.408 converts MJ/m² to the mm of evaporated water.
+    0.0023 * 0.408 * radiation * ((max + min) / 2. + 17.8) * (max - min).max(0.).sqrt()
+}
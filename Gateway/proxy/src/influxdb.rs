@@ -0,0 +1,463 @@
+use crate::error::{Error, Result};
+use crate::http::{self, read_body};
+use crate::reading::Reading;
+use crate::sink::Sink;
+use crate::util::{
+    Receiver, Sender, INFLUXDB_BATCH_SIZE, INFLUXDB_FLUSH_INTERVAL, INFLUXDB_MAX_LINES,
+    INFLUXDB_MEASUREMENT, INFLUXDB_TOKEN_ENV, INFLUXDB_UDP_PAYLOAD,
+};
+use async_std::future;
+use async_std::net::UdpSocket;
+use async_std::task::{self, JoinHandle};
+use async_trait::async_trait;
+use futures::channel::mpsc;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::time::{Duration, Instant};
+use surf::{Client, Url};
+
+/// Influx line protocol output, one line per reading.
+///
+/// Tag values may contain the `{node}` and `{quality}` placeholders. `fields` maps
+/// measurement names to field names, when empty every measurement is written under its
+/// own name. Lines are sent once `batch_size` of them are buffered or the oldest one is
+/// `flush_interval` seconds old.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Influx {
+    pub output: InfluxOutput,
+    #[serde(default = "default_influx_measurement")]
+    pub measurement: String,
+    #[serde(default = "default_influx_tags")]
+    pub tags: BTreeMap<String, String>,
+    #[serde(default)]
+    pub fields: BTreeMap<String, String>,
+    #[serde(default = "default_influx_batch_size")]
+    pub batch_size: usize,
+    #[serde(default = "default_influx_flush_interval")]
+    pub flush_interval: u64,
+}
+
+/// `Http` writes to the InfluxDB 2 `/api/v2/write` endpoint, the token is read from the
+/// `INFLUXDB_TOKEN` environment variable unless set.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum InfluxOutput {
+    Http {
+        url: String,
+        org: String,
+        bucket: String,
+        #[serde(default)]
+        token: Option<String>,
+    },
+    File {
+        path: String,
+    },
+    Udp {
+        addr: String,
+    },
+}
+
+fn default_influx_measurement() -> String {
+    INFLUXDB_MEASUREMENT.to_string()
+}
+
+fn default_influx_tags() -> BTreeMap<String, String> {
+    let mut tags = BTreeMap::new();
+    tags.insert("node".to_string(), "{node}".to_string());
+    tags.insert("quality".to_string(), "{quality}".to_string());
+    tags
+}
+
+fn default_influx_batch_size() -> usize {
+    INFLUXDB_BATCH_SIZE
+}
+
+fn default_influx_flush_interval() -> u64 {
+    INFLUXDB_FLUSH_INTERVAL
+}
+
+enum Output {
+    Http { url: Url, token: Option<String> },
+    File { path: String },
+    Udp { addr: String },
+}
+
+/// Writes readings as Influx line protocol.
+///
+/// The lines are written by a task, so a slow endpoint does not hold back the radio.
+/// Lines that could not be written stay buffered and are retried with the next batch,
+/// the oldest are dropped above `INFLUXDB_MAX_LINES`.
+pub struct InfluxDb {
+    name: String,
+    conf: Influx,
+    sender: Sender<String>,
+    writer: Option<(Writer, Receiver<String>)>,
+    task: Option<JoinHandle<Result<()>>>,
+}
+
+/// Buffers the lines of the sink and flushes them in batches.
+struct Writer {
+    name: String,
+    client: Client,
+    output: Output,
+    batch_size: usize,
+    flush_interval: Duration,
+    lines: VecDeque<String>,
+    oldest: Option<Instant>,
+}
+
+impl InfluxDb {
+    pub fn new(name: String, conf: &Influx) -> Result<Self> {
+        let output = match &conf.output {
+            InfluxOutput::Http {
+                url,
+                org,
+                bucket,
+                token,
+            } => {
+                let mut url =
+                    http::parse_url(&format!("{}/api/v2/write", url.trim_end_matches('/')))?;
+                url.query_pairs_mut()
+                    .append_pair("org", org)
+                    .append_pair("bucket", bucket)
+                    .append_pair("precision", "ns");
+                Output::Http {
+                    url,
+                    token: token
+                        .clone()
+                        .or_else(|| std::env::var(INFLUXDB_TOKEN_ENV).ok()),
+                }
+            }
+            InfluxOutput::File { path } => Output::File { path: path.clone() },
+            InfluxOutput::Udp { addr } => Output::Udp { addr: addr.clone() },
+        };
+        let writer = Writer {
+            name: name.clone(),
+            client: http::client(),
+            output,
+            batch_size: conf.batch_size,
+            // A failed flush is retried after the interval, at least a second later.
+            flush_interval: Duration::from_secs(conf.flush_interval.max(1)),
+            lines: VecDeque::new(),
+            oldest: None,
+        };
+        let (sender, receiver) = mpsc::unbounded();
+        Ok(InfluxDb {
+            name,
+            conf: conf.clone(),
+            sender,
+            writer: Some((writer, receiver)),
+            task: None,
+        })
+    }
+
+    fn line(&self, reading: &Reading) -> Option<String> {
+        let fields: Vec<String> = reading
+            .measurements()
+            .into_iter()
+            .filter(|(_, value)| value.is_finite())
+            .filter_map(|(name, value)| {
+                let field = if self.conf.fields.is_empty() {
+                    name
+                } else {
+                    self.conf.fields.get(&name)?.clone()
+                };
+                Some(format!("{}={}", escape_key(&field), value))
+            })
+            .collect();
+        if fields.is_empty() {
+            return None;
+        }
+        let quality = format!("{:?}", reading.quality).to_lowercase();
+        let mut line = escape_measurement(&self.conf.measurement);
+        for (key, value) in &self.conf.tags {
+            let value = value
+                .replace("{node}", &reading.node.to_string())
+                .replace("{quality}", &quality);
+            if !value.is_empty() {
+                line.push_str(&format!(",{}={}", escape_key(key), escape_key(&value)));
+            }
+        }
+        // Timestamps are nanoseconds, the default precision of every Influx input.
+        Some(format!(
+            "{} {} {}",
+            line,
+            fields.join(","),
+            reading.timestamp.timestamp_millis() * 1_000_000
+        ))
+    }
+}
+
+impl Writer {
+    /// Flushes when the batch is full or its oldest line is `flush_interval` old, the
+    /// remaining lines are flushed once the sink closes the channel.
+    async fn run(mut self, mut receiver: Receiver<String>) -> Result<()> {
+        loop {
+            let next = match self.oldest {
+                Some(oldest) => {
+                    let wait = self.flush_interval.checked_sub(oldest.elapsed());
+                    future::timeout(wait.unwrap_or_default(), receiver.next())
+                        .await
+                        .ok()
+                }
+                None => Some(receiver.next().await),
+            };
+            match next {
+                Some(Some(line)) => self.push(line),
+                Some(None) => break,
+                None => (),
+            }
+            let due = match self.oldest {
+                Some(oldest) => oldest.elapsed() >= self.flush_interval,
+                None => false,
+            };
+            if due || self.lines.len() >= self.batch_size {
+                if let Err(err) = self.flush().await {
+                    self.oldest = Some(Instant::now());
+                    eprintln!("{}: {}", self.name, err);
+                    error!("{}: {:?}", self.name, err);
+                }
+            }
+        }
+        let result = self.flush().await;
+        if result.is_err() {
+            warn!(
+                "InfluxDB sink {} lost {} buffered lines",
+                self.name,
+                self.lines.len()
+            );
+        }
+        result
+    }
+
+    fn push(&mut self, line: String) {
+        self.lines.push_back(line);
+        self.oldest.get_or_insert_with(Instant::now);
+        let excess = self.lines.len().saturating_sub(INFLUXDB_MAX_LINES);
+        if excess > 0 {
+            warn!(
+                "InfluxDB sink {} is full, dropping {} oldest lines",
+                self.name, excess
+            );
+            self.lines.drain(..excess);
+        }
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        if self.lines.is_empty() {
+            return Ok(());
+        }
+        let lines: Vec<&str> = self.lines.iter().map(String::as_str).collect();
+        match &self.output {
+            Output::Http { url, token } => {
+                let mut request = self
+                    .client
+                    .post(url.as_str())
+                    .header("Content-Type", "text/plain; charset=utf-8")
+                    .body_string(lines.join("\n"));
+                if let Some(token) = token {
+                    request = request.header("Authorization", format!("Token {}", token));
+                }
+                read_body(request.await?).await?;
+            }
+            Output::File { path } => {
+                let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+                for line in &lines {
+                    writeln!(file, "{}", line)?;
+                }
+                file.sync_data()?;
+            }
+            Output::Udp { addr } => {
+                let socket = UdpSocket::bind("0.0.0.0:0").await?;
+                for packet in packets(&lines) {
+                    socket.send_to(packet.as_bytes(), addr.as_str()).await?;
+                }
+            }
+        }
+        debug!("InfluxDB sink {} wrote {} lines", self.name, lines.len());
+        self.lines.clear();
+        self.oldest = None;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Sink for InfluxDb {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn start(&mut self) -> Result<()> {
+        if let Some((writer, receiver)) = self.writer.take() {
+            self.task = Some(task::spawn(writer.run(receiver)));
+        }
+        Ok(())
+    }
+
+    async fn publish(&mut self, reading: &Reading) -> Result<()> {
+        if let Some(line) = self.line(reading) {
+            self.sender
+                .unbounded_send(line)
+                .map_err(|_| Error::new_option("InfluxDB writer has stopped"))?;
+        }
+        Ok(())
+    }
+
+    async fn shutdown(&mut self) -> Result<()> {
+        // The writer flushes what is left once the channel is closed.
+        self.sender.close_channel();
+        match self.task.take() {
+            Some(task) => task.await,
+            None => Ok(()),
+        }
+    }
+}
+
+/// Joins lines into datagrams of at most `INFLUXDB_UDP_PAYLOAD` bytes, a longer line is
+/// sent alone.
+fn packets(lines: &[&str]) -> Vec<String> {
+    let mut packets: Vec<String> = Vec::new();
+    for line in lines {
+        match packets.last_mut() {
+            Some(packet) if packet.len() + 1 + line.len() <= INFLUXDB_UDP_PAYLOAD => {
+                packet.push('\n');
+                packet.push_str(line);
+            }
+            _ => packets.push(line.to_string()),
+        }
+    }
+    packets
+}
+
+fn escape_measurement(value: &str) -> String {
+    value.replace(',', "\\,").replace(' ', "\\ ")
+}
+
+/// Escapes tag keys, tag values and field keys.
+fn escape_key(value: &str) -> String {
+    escape_measurement(value).replace('=', "\\=")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::stand_in;
+
+    fn conf(yaml: &str) -> Influx {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn http_conf(url: &str, batch_size: usize) -> Influx {
+        conf(&format!(
+            "output: {{type: Http, url: '{}/', org: home, bucket: weather station, \
+             token: secret}}\nbatch_size: {}\nflush_interval: 3600",
+            url, batch_size
+        ))
+    }
+
+    #[test]
+    fn line_escapes_measurement_tags_and_fields() {
+        let influx = InfluxDb::new(
+            "influxdb".to_string(),
+            &conf(
+                "output: {type: File, path: /dev/null}\n\
+                 measurement: weather station\n\
+                 tags: {node id: '{node}', 'site,name': 'roof=a b', empty: ''}\n\
+                 fields: {temperature: air temp, rain: 'rain,mm=total'}",
+            ),
+        )
+        .unwrap();
+        assert_eq!(
+            influx.line(&Reading::sample()).unwrap(),
+            "weather\\ station,node\\ id=10,site\\,name=roof\\=a\\ b \
+             air\\ temp=21.5,rain\\,mm\\=total=0.4 1714564800000000000"
+        );
+    }
+
+    #[test]
+    fn line_without_fields_is_skipped() {
+        let influx = InfluxDb::new(
+            "influxdb".to_string(),
+            &conf("output: {type: File, path: /dev/null}\nfields: {unknown: value}"),
+        )
+        .unwrap();
+        assert!(influx.line(&Reading::sample()).is_none());
+    }
+
+    #[async_std::test]
+    async fn flushes_full_batch_to_write_endpoint() {
+        let (url, server) = stand_in::serve(vec![(204, "")]).await;
+        let mut influx = InfluxDb::new("influxdb".to_string(), &http_conf(&url, 2)).unwrap();
+        influx.start().await.unwrap();
+        influx.publish(&Reading::sample()).await.unwrap();
+        influx.publish(&Reading::sample()).await.unwrap();
+        let requests = server.await;
+        let request = &requests[0];
+        assert_eq!(
+            request.target(),
+            "/api/v2/write?org=home&bucket=weather+station&precision=ns"
+        );
+        assert!(request.head.starts_with("POST "));
+        assert!(request.head.contains("authorization: Token secret"));
+        let lines: Vec<&str> = request.body.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("weather,node=10,quality=good temperature=21.5,"));
+        influx.shutdown().await.unwrap();
+    }
+
+    #[async_std::test]
+    async fn keeps_lines_after_http_error() {
+        let (url, server) =
+            stand_in::serve(vec![(500, "database unavailable"), (204, ""), (204, "")]).await;
+        let mut influx = InfluxDb::new("influxdb".to_string(), &http_conf(&url, 1)).unwrap();
+        influx.start().await.unwrap();
+        influx.publish(&Reading::sample()).await.unwrap();
+        influx.publish(&Reading::sample()).await.unwrap();
+        let mut reading = Reading::sample();
+        reading.temperature = 22.;
+        influx.publish(&reading).await.unwrap();
+        influx.shutdown().await.unwrap();
+        let requests = server.await;
+        let lines: Vec<usize> = requests
+            .iter()
+            .map(|request| request.body.lines().count())
+            .collect();
+        // The failed line is written again with the next one.
+        assert_eq!(lines, vec![1, 2, 1]);
+        assert!(requests[2].body.contains("temperature=22,"));
+    }
+
+    #[async_std::test]
+    async fn shutdown_flushes_partial_batch() {
+        let (url, server) = stand_in::serve(vec![(204, "")]).await;
+        let mut influx = InfluxDb::new("influxdb".to_string(), &http_conf(&url, 10)).unwrap();
+        influx.start().await.unwrap();
+        influx.publish(&Reading::sample()).await.unwrap();
+        influx.shutdown().await.unwrap();
+        assert_eq!(server.await[0].body.lines().count(), 1);
+    }
+
+    #[async_std::test]
+    async fn shutdown_reports_http_error() {
+        let (url, server) = stand_in::serve(vec![(401, "unauthorized")]).await;
+        let mut influx = InfluxDb::new("influxdb".to_string(), &http_conf(&url, 10)).unwrap();
+        influx.start().await.unwrap();
+        influx.publish(&Reading::sample()).await.unwrap();
+        let err = influx.shutdown().await.unwrap_err();
+        assert!(err.to_string().contains("unauthorized"));
+        server.await;
+    }
+
+    #[test]
+    fn packets_split_at_payload_size() {
+        let long = "x".repeat(INFLUXDB_UDP_PAYLOAD);
+        let packets = packets(&["a", "b", &long, "c"]);
+        assert_eq!(
+            packets,
+            vec!["a\nb".to_string(), long.clone(), "c".to_string()]
+        );
+    }
+}
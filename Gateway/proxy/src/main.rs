@@ -4,8 +4,15 @@ extern crate failure;
 #[macro_use]
 extern crate log;
 
-use crate::error::Result;
+use crate::cli::{parse_args, Command};
+use crate::climate::ClimateStore;
+use crate::config::read_conf;
+use crate::error::{Error, Result};
+use crate::export::Export;
+use crate::history::HistoryStore;
+use crate::noaa::Report;
 use crate::proxy::Proxy;
+use crate::query::Query;
 use crate::util::{Sender, Shared, CONF_PATH, LOG_MODULE_IGNORE, LOG_PATH, LOG_TIME_FORMAT};
 use async_std::sync::{Arc, Mutex};
 use async_std::task::block_on;
@@ -13,17 +20,46 @@ use futures::channel::mpsc;
 use futures::SinkExt;
 use simplelog::{ConfigBuilder, LevelFilter, WriteLogger};
 use std::fs::File;
+use std::io::{stdout, BufWriter};
 
+mod aprs;
+mod battery;
+mod cli;
+mod climate;
 mod config;
+mod counter;
 mod data;
+mod domoticz;
 mod error;
+mod export;
+mod expression;
+mod frame;
+mod history;
+mod http;
+mod indices;
+mod influxdb;
+mod metrics;
+mod noaa;
+mod outbox;
 
 #[macro_use]
 mod util;
+mod dashboard;
 mod home_assistant;
+mod homie;
+mod modbus;
 mod proxy;
+mod pws;
+mod query;
 mod radio;
+mod reading;
+mod realtime;
+mod senml;
+mod sink;
+mod transform;
+mod units;
 mod vutbr;
+mod webhook;
 
 #[async_std::main]
 async fn main() {
@@ -31,10 +67,28 @@ async fn main() {
     if let Err(err) = result {
         eprintln!("{}", err);
         error!("{:?}", err);
+        std::process::exit(1);
     }
 }
 
 async fn run_main() -> Result<()> {
+    match parse_args(std::env::args().skip(1))? {
+        Command::Run => run_proxy().await,
+        Command::Query { conf, query } => run_query(&conf, &query).await,
+        Command::Export {
+            conf,
+            output,
+            export,
+        } => run_export(&conf, output.as_deref(), &export).await,
+        Command::Report {
+            conf,
+            output,
+            report,
+        } => run_report(&conf, output.as_deref(), &report).await,
+    }
+}
+
+async fn run_proxy() -> Result<()> {
     let config = ConfigBuilder::new()
         .set_location_level(LevelFilter::Error)
         .set_time_format_str(LOG_TIME_FORMAT)
@@ -52,6 +106,46 @@ async fn run_main() -> Result<()> {
     Ok(())
 }
 
+async fn run_query(conf_path: &str, query: &Query) -> Result<()> {
+    let conf = read_conf(conf_path).await?;
+    let history = conf
+        .history()
+        .ok_or_else(|| Error::new_option("History is not configured"))?;
+    let store = HistoryStore::open_read_only(history)?;
+    let rows: Vec<_> = query.run(&store)?.iter().map(|row| row.to_json()).collect();
+    println!("{}", serde_json::to_string_pretty(&rows)?);
+    Ok(())
+}
+
+async fn run_export(conf_path: &str, output: Option<&str>, export: &Export) -> Result<()> {
+    let conf = read_conf(conf_path).await?;
+    let history = conf
+        .history()
+        .ok_or_else(|| Error::new_option("History is not configured"))?;
+    let store = HistoryStore::open_read_only(history)?;
+    match output {
+        Some(path) => export.run(&store, conf.units(), BufWriter::new(File::create(path)?)),
+        None => export.run(&store, conf.units(), stdout()),
+    }
+}
+
+async fn run_report(conf_path: &str, output: Option<&str>, report: &Report) -> Result<()> {
+    let conf = read_conf(conf_path).await?;
+    let climate = conf
+        .climate()
+        .ok_or_else(|| Error::new_option("Climate summaries are not configured"))?;
+    let store = ClimateStore::open_read_only(climate)?;
+    match output {
+        Some(path) => report.run(
+            &store,
+            climate,
+            conf.units(),
+            BufWriter::new(File::create(path)?),
+        ),
+        None => report.run(&store, climate, conf.units(), stdout()),
+    }
+}
+
 fn configure_ctrlc_handler(shared_sender: Shared<Sender<bool>>) {
     ctrlc::set_handler(move || {
         info!("Got CTRL+C");
@@ -0,0 +1,165 @@
+use crate::config::Metrics;
+use crate::error::Result;
+use crate::reading::Reading;
+use crate::util::Shared;
+use async_std::task;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use tide::listener::Listener;
+use tide::{Request, Response, StatusCode};
+
+/// Counters of the radio link and the sinks together with the latest reading of
+/// every node, rendered in the Prometheus text format.
+#[derive(Debug, Default)]
+pub struct MetricsRegistry {
+    pub packets_received: u64,
+    pub acks_sent: u64,
+    pub configs_sent: u64,
+    pub decode_errors: u64,
+    pub invalid_packets: u64,
+    publish_failures: BTreeMap<String, u64>,
+    nodes: BTreeMap<u8, NodeMetrics>,
+    /// Strength of the last packet from every node in dBm.
+    rssi: BTreeMap<u8, f64>,
+}
+
+#[derive(Debug)]
+struct NodeMetrics {
+    last_seen: f64,
+    measurements: Vec<(String, f64)>,
+}
+
+impl MetricsRegistry {
+    pub fn new() -> Self {
+        MetricsRegistry::default()
+    }
+
+    pub fn update(&mut self, reading: &Reading) {
+        self.nodes.insert(
+            reading.node,
+            NodeMetrics {
+                last_seen: reading.timestamp.timestamp_millis() as f64 / 1000.,
+                measurements: reading.measurements(),
+            },
+        );
+    }
+
+    pub fn update_rssi(&mut self, node: u8, rssi: f64) {
+        self.rssi.insert(node, rssi);
+    }
+
+    pub fn rssi(&self, node: u8) -> Option<f64> {
+        self.rssi.get(&node).copied()
+    }
+
+    pub fn publish_failed(&mut self, sink: &str) {
+        *self.publish_failures.entry(sink.to_string()).or_insert(0) += 1;
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let counters = [
+            (
+                "packets_received",
+                "Radio packets received",
+                self.packets_received,
+            ),
+            ("acks_sent", "ACKs sent to the nodes", self.acks_sent),
+            ("configs_sent", "Node configs sent", self.configs_sent),
+            (
+                "decode_errors",
+                "Packets that failed to decode",
+                self.decode_errors,
+            ),
+            (
+                "invalid_packets",
+                "Packets of unknown type or length",
+                self.invalid_packets,
+            ),
+        ];
+        for (name, help, value) in counters.iter() {
+            metric_header(&mut out, &format!("proxy_{}_total", name), help, "counter");
+            let _ = writeln!(out, "proxy_{}_total {}", name, value);
+        }
+
+        metric_header(
+            &mut out,
+            "proxy_mqtt_publish_failures_total",
+            "MQTT messages that failed to publish and were buffered",
+            "counter",
+        );
+        for (sink, value) in &self.publish_failures {
+            let _ = writeln!(
+                out,
+                "proxy_mqtt_publish_failures_total{{sink=\"{}\"}} {}",
+                sink, value
+            );
+        }
+
+        metric_header(
+            &mut out,
+            "proxy_node_last_seen_seconds",
+            "Unix time of the last reading from the node",
+            "gauge",
+        );
+        for (node, metrics) in &self.nodes {
+            let _ = writeln!(
+                out,
+                "proxy_node_last_seen_seconds{{node=\"{}\"}} {}",
+                node, metrics.last_seen
+            );
+        }
+
+        metric_header(
+            &mut out,
+            "proxy_node_rssi_dbm",
+            "Signal strength of the last packet from the node",
+            "gauge",
+        );
+        for (node, rssi) in &self.rssi {
+            let _ = writeln!(out, "proxy_node_rssi_dbm{{node=\"{}\"}} {}", node, rssi);
+        }
+
+        let mut gauges: BTreeMap<&str, Vec<(u8, f64)>> = BTreeMap::new();
+        for (node, metrics) in &self.nodes {
+            for (name, value) in &metrics.measurements {
+                gauges.entry(name).or_default().push((*node, *value));
+            }
+        }
+        for (name, values) in gauges {
+            let metric = format!("weather_{}", name);
+            metric_header(&mut out, &metric, &format!("Latest {}", name), "gauge");
+            for (node, value) in values {
+                let _ = writeln!(out, "{}{{node=\"{}\"}} {}", metric, node, value);
+            }
+        }
+        out
+    }
+}
+
+/// Serves `/metrics` in the background, fails only when the address cannot be bound.
+pub async fn serve(conf: &Metrics, registry: Shared<MetricsRegistry>) -> Result<()> {
+    let mut app = tide::with_state(registry);
+    app.at("/metrics")
+        .get(|request: Request<Shared<MetricsRegistry>>| async move {
+            let body = request.state().lock().await.render();
+            Ok(Response::builder(StatusCode::Ok)
+                .header("Content-Type", "text/plain; version=0.0.4; charset=utf-8")
+                .body(body)
+                .build())
+        });
+    let mut listener = app.bind(conf.addr.as_str()).await?;
+    info!("Serving metrics on {}", conf.addr);
+    task::spawn(async move {
+        if let Err(err) = listener.accept().await {
+            eprintln!("{}", err);
+            error!("{:?}", err);
+        }
+    });
+    Ok(())
+}
+
+fn metric_header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
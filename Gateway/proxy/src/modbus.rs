@@ -0,0 +1,420 @@
+use crate::config::{Config, DigitalPin};
+use crate::error::{Error, Result};
+use crate::reading::Reading;
+use crate::sink::{CommandStream, Sink};
+use crate::util::{
+    Receiver, Sender, Shared, MODBUS_ADDR, MODBUS_DEVICE_FAILURE, MODBUS_ILLEGAL_DATA_ADDRESS,
+    MODBUS_ILLEGAL_DATA_VALUE, MODBUS_ILLEGAL_FUNCTION, MODBUS_MAX_READ_BITS,
+    MODBUS_MAX_READ_REGISTERS, MODBUS_MAX_WRITE_COILS, PAYLOAD_OFF, PAYLOAD_ON,
+};
+use async_std::io::ErrorKind;
+use async_std::net::{TcpListener, TcpStream};
+use async_std::prelude::*;
+use async_std::sync::{Arc, Mutex};
+use async_std::task;
+use async_trait::async_trait;
+use futures::channel::mpsc;
+use futures::StreamExt;
+use paho_mqtt::Message;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::result::Result as StdResult;
+
+/// Modbus TCP server on `addr`, the register map addresses are zero based.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Modbus {
+    #[serde(default = "default_modbus_addr")]
+    pub addr: String,
+    #[serde(default)]
+    pub input_registers: Vec<InputRegister>,
+    #[serde(default)]
+    pub discrete_inputs: Vec<ModbusBit>,
+    #[serde(default)]
+    pub coils: Vec<ModbusBit>,
+}
+
+/// Measurement multiplied by `scale`, the 32 bit types take two registers with the high
+/// word first. A measurement the reading does not have reads as zero.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InputRegister {
+    pub address: u16,
+    pub measurement: String,
+    #[serde(default = "default_modbus_scale")]
+    pub scale: f64,
+    #[serde(default)]
+    pub data_type: RegisterType,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum RegisterType {
+    #[default]
+    I16,
+    U16,
+    I32,
+    U32,
+    F32,
+}
+
+impl RegisterType {
+    pub fn words(self) -> u16 {
+        match self {
+            RegisterType::I16 | RegisterType::U16 => 1,
+            RegisterType::I32 | RegisterType::U32 | RegisterType::F32 => 2,
+        }
+    }
+}
+
+/// Digital pin by its configured name.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModbusBit {
+    pub address: u16,
+    pub pin: String,
+}
+
+fn default_modbus_addr() -> String {
+    MODBUS_ADDR.to_string()
+}
+
+fn default_modbus_scale() -> f64 {
+    1.
+}
+
+/// Serves the latest reading over Modbus TCP.
+///
+/// Measurements are input registers, also readable as holding registers, digital inputs
+/// are discrete inputs and output pins are coils. Written coils are passed on as
+/// `Node::update_output` commands, reading a coil returns the configured pin state.
+pub struct ModbusServer {
+    name: String,
+    conf: Modbus,
+    shared_conf: Shared<Config>,
+    latest: Shared<Latest>,
+    sender: Sender<Message>,
+    receiver: Option<Receiver<Message>>,
+}
+
+#[derive(Default)]
+struct Latest {
+    measurements: HashMap<String, f64>,
+    gpio: u8,
+}
+
+/// Register map resolved against the node config.
+struct Registers {
+    input_registers: Vec<InputRegister>,
+    discrete_inputs: HashMap<u16, u8>,
+    coils: HashMap<u16, String>,
+}
+
+#[derive(Clone)]
+struct Context {
+    registers: Arc<Registers>,
+    latest: Shared<Latest>,
+    shared_conf: Shared<Config>,
+    sender: Sender<Message>,
+}
+
+/// Response data or a Modbus exception code.
+type Response = StdResult<Vec<u8>, u8>;
+
+impl ModbusServer {
+    pub fn new(name: String, conf: &Modbus, shared_conf: Shared<Config>) -> Self {
+        let (sender, receiver) = mpsc::unbounded();
+        ModbusServer {
+            name,
+            conf: conf.clone(),
+            shared_conf,
+            latest: new_shared!(Latest::default()),
+            sender,
+            receiver: Some(receiver),
+        }
+    }
+
+    async fn registers(&self) -> Result<Registers> {
+        let conf = self.shared_conf.lock().await;
+        let find_pin = |name: &str| {
+            conf.node()
+                .digital_pins()
+                .find(|(pin_name, _)| *pin_name == name)
+                .map(|(_, pin)| pin)
+        };
+        let mut discrete_inputs = HashMap::new();
+        for bit in &self.conf.discrete_inputs {
+            match find_pin(&bit.pin) {
+                Some(DigitalPin::Input { number, .. }) => {
+                    discrete_inputs.insert(bit.address, *number);
+                }
+                _ => {
+                    return Err(Error::new_config(format!(
+                        "Modbus discrete input '{}' is not an input pin",
+                        bit.pin
+                    )))
+                }
+            }
+        }
+        let mut coils = HashMap::new();
+        for bit in &self.conf.coils {
+            match find_pin(&bit.pin) {
+                Some(DigitalPin::Output { command_topic, .. }) => {
+                    coils.insert(bit.address, command_topic.clone());
+                }
+                _ => {
+                    return Err(Error::new_config(format!(
+                        "Modbus coil '{}' is not an output pin",
+                        bit.pin
+                    )))
+                }
+            }
+        }
+        let mut words = HashSet::new();
+        for register in &self.conf.input_registers {
+            for word in 0..register.data_type.words() {
+                match register.address.checked_add(word) {
+                    Some(address) if words.insert(address) => (),
+                    _ => {
+                        return Err(Error::new_config(format!(
+                            "Modbus input register {} of {} overlaps",
+                            register.address, register.measurement
+                        )))
+                    }
+                }
+            }
+        }
+        Ok(Registers {
+            input_registers: self.conf.input_registers.clone(),
+            discrete_inputs,
+            coils,
+        })
+    }
+}
+
+#[async_trait]
+impl Sink for ModbusServer {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn start(&mut self) -> Result<()> {
+        let context = Context {
+            registers: Arc::new(self.registers().await?),
+            latest: self.latest.clone(),
+            shared_conf: self.shared_conf.clone(),
+            sender: self.sender.clone(),
+        };
+        let listener = TcpListener::bind(self.conf.addr.as_str()).await?;
+        info!("Serving Modbus on {}", self.conf.addr);
+        task::spawn(async move {
+            let mut incoming = listener.incoming();
+            while let Some(stream) = incoming.next().await {
+                match stream {
+                    Ok(stream) => {
+                        task::spawn(serve(stream, context.clone()));
+                    }
+                    Err(err) => {
+                        eprintln!("{}", err);
+                        error!("{:?}", err);
+                    }
+                }
+            }
+        });
+        Ok(())
+    }
+
+    async fn publish(&mut self, reading: &Reading) -> Result<()> {
+        let mut latest = self.latest.lock().await;
+        latest.measurements = reading.measurements().into_iter().collect();
+        latest.gpio = reading.data.gpio_value;
+        Ok(())
+    }
+
+    async fn shutdown(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn commands(&mut self) -> Option<CommandStream> {
+        self.receiver
+            .take()
+            .map(|receiver| receiver.map(|message| Ok(Some(message))).boxed())
+    }
+}
+
+/// Answers the requests of one client until it disconnects.
+async fn serve(mut stream: TcpStream, context: Context) {
+    let peer = stream.peer_addr().ok();
+    debug!("Modbus client {:?} connected", peer);
+    let mut header = [0u8; 7];
+    loop {
+        // MBAP header: transaction, protocol, length of the unit id and the PDU, unit id.
+        if let Err(err) = stream.read_exact(&mut header).await {
+            if err.kind() != ErrorKind::UnexpectedEof {
+                warn!("Modbus client {:?}: {}", peer, err);
+            }
+            break;
+        }
+        let length = u16::from_be_bytes([header[4], header[5]]) as usize;
+        if header[2..4] != [0, 0] || !(2..=254).contains(&length) {
+            warn!("Modbus client {:?} sent an invalid frame", peer);
+            break;
+        }
+        let mut pdu = vec![0; length - 1];
+        if let Err(err) = stream.read_exact(&mut pdu).await {
+            warn!("Modbus client {:?}: {}", peer, err);
+            break;
+        }
+        let function = pdu[0];
+        let response = match context.respond(&pdu).await {
+            Ok(data) => [&[function][..], &data].concat(),
+            Err(code) => vec![function | 0x80, code],
+        };
+        let mut frame = Vec::with_capacity(7 + response.len());
+        frame.extend_from_slice(&header[..4]);
+        frame.extend_from_slice(&(response.len() as u16 + 1).to_be_bytes());
+        frame.push(header[6]);
+        frame.extend_from_slice(&response);
+        if let Err(err) = stream.write_all(&frame).await {
+            warn!("Modbus client {:?}: {}", peer, err);
+            break;
+        }
+    }
+    debug!("Modbus client {:?} disconnected", peer);
+}
+
+impl Context {
+    async fn respond(&self, pdu: &[u8]) -> Response {
+        match pdu[0] {
+            0x01 => {
+                let conf = self.shared_conf.lock().await;
+                read_bits(pdu, |address| {
+                    let topic = self.registers.coils.get(&address)?;
+                    conf.node().output_state(topic)
+                })
+            }
+            0x02 => {
+                let latest = self.latest.lock().await;
+                read_bits(pdu, |address| {
+                    let number = self.registers.discrete_inputs.get(&address)?;
+                    Some(latest.gpio & (1 << number) != 0)
+                })
+            }
+            0x03 | 0x04 => {
+                let (start, count) = range(pdu, MODBUS_MAX_READ_REGISTERS)?;
+                let latest = self.latest.lock().await;
+                let mut data = vec![(count * 2) as u8];
+                for address in start..start + count {
+                    data.extend_from_slice(&self.register(&latest, address).to_be_bytes());
+                }
+                Ok(data)
+            }
+            0x05 => {
+                let address = word(pdu, 1)?;
+                let state = match word(pdu, 3)? {
+                    0xFF00 => true,
+                    0x0000 => false,
+                    _ => return Err(MODBUS_ILLEGAL_DATA_VALUE),
+                };
+                self.write_coils(&[(address, state)])?;
+                Ok(pdu[1..5].to_vec())
+            }
+            0x0F => {
+                let (start, count) = range(pdu, MODBUS_MAX_WRITE_COILS)?;
+                let bytes = count.div_ceil(8) as usize;
+                if pdu.get(5) != Some(&(bytes as u8)) || pdu.len() != 6 + bytes {
+                    return Err(MODBUS_ILLEGAL_DATA_VALUE);
+                }
+                let coils: Vec<_> = (0..count)
+                    .map(|i| {
+                        let bit = pdu[6 + i as usize / 8] & (1 << (i % 8)) != 0;
+                        (start + i, bit)
+                    })
+                    .collect();
+                self.write_coils(&coils)?;
+                Ok(pdu[1..5].to_vec())
+            }
+            _ => Err(MODBUS_ILLEGAL_FUNCTION),
+        }
+    }
+
+    /// Word of the input register at the address, unmapped addresses read as zero.
+    fn register(&self, latest: &Latest, address: u16) -> u16 {
+        for register in &self.registers.input_registers {
+            let offset = address.wrapping_sub(register.address);
+            if offset < register.data_type.words() {
+                let value = latest
+                    .measurements
+                    .get(&register.measurement)
+                    .map_or(0., |value| value * register.scale);
+                return encode(value, register.data_type)[offset as usize];
+            }
+        }
+        0
+    }
+
+    /// Checks all addresses before any command is sent.
+    fn write_coils(&self, coils: &[(u16, bool)]) -> StdResult<(), u8> {
+        let mut messages = Vec::with_capacity(coils.len());
+        for (address, state) in coils {
+            let topic = self
+                .registers
+                .coils
+                .get(address)
+                .ok_or(MODBUS_ILLEGAL_DATA_ADDRESS)?;
+            let payload = if *state { PAYLOAD_ON } else { PAYLOAD_OFF };
+            messages.push(Message::new(topic.as_str(), payload, 0));
+        }
+        for message in messages {
+            self.sender
+                .unbounded_send(message)
+                .map_err(|_| MODBUS_DEVICE_FAILURE)?;
+        }
+        Ok(())
+    }
+}
+
+/// Packs the bits of the requested range, unmapped addresses read as zero.
+fn read_bits<F: Fn(u16) -> Option<bool>>(pdu: &[u8], bit: F) -> Response {
+    let (start, count) = range(pdu, MODBUS_MAX_READ_BITS)?;
+    let mut data = vec![0; 1 + (count as usize).div_ceil(8)];
+    data[0] = (data.len() - 1) as u8;
+    for i in 0..count {
+        if bit(start + i).unwrap_or(false) {
+            data[1 + i as usize / 8] |= 1 << (i % 8);
+        }
+    }
+    Ok(data)
+}
+
+/// Start address and count of a request, the range has to fit the address space.
+fn range(pdu: &[u8], max_count: u16) -> StdResult<(u16, u16), u8> {
+    let start = word(pdu, 1)?;
+    let count = word(pdu, 3)?;
+    if count == 0 || count > max_count {
+        return Err(MODBUS_ILLEGAL_DATA_VALUE);
+    }
+    if start.checked_add(count - 1).is_none() {
+        return Err(MODBUS_ILLEGAL_DATA_ADDRESS);
+    }
+    Ok((start, count))
+}
+
+fn word(pdu: &[u8], index: usize) -> StdResult<u16, u8> {
+    match pdu.get(index..index + 2) {
+        Some(bytes) => Ok(u16::from_be_bytes([bytes[0], bytes[1]])),
+        None => Err(MODBUS_ILLEGAL_DATA_VALUE),
+    }
+}
+
+/// Register words of the value, saturated to the range of the type.
+fn encode(value: f64, data_type: RegisterType) -> Vec<u16> {
+    let split = |bits: u32| vec![(bits >> 16) as u16, bits as u16];
+    match data_type {
+        RegisterType::I16 => {
+            vec![value.round().clamp(i16::MIN as f64, i16::MAX as f64) as i16 as u16]
+        }
+        RegisterType::U16 => vec![value.round().clamp(0., u16::MAX as f64) as u16],
+        RegisterType::I32 => {
+            split(value.round().clamp(i32::MIN as f64, i32::MAX as f64) as i32 as u32)
+        }
+        RegisterType::U32 => split(value.round().clamp(0., u32::MAX as f64) as u32),
+        RegisterType::F32 => split((value as f32).to_bits()),
+    }
+}
@@ -0,0 +1,605 @@
+use crate::climate::{ClimateStore, DailySummary, Stat};
+use crate::config::Climate;
+use crate::error::{Error, Result};
+use crate::units::{RainUnit, TemperatureUnit, Units};
+use crate::util::{
+    compass_point, local_time, utc_from_millis, write_atomic, NOAA_DEGREE_DAY_BASE,
+    NOAA_RAIN_THRESHOLDS_INCH, NOAA_RAIN_THRESHOLDS_MILLIMETER,
+    NOAA_TEMPERATURE_THRESHOLDS_CELSIUS, NOAA_TEMPERATURE_THRESHOLDS_FAHRENHEIT,
+};
+use chrono::{Datelike, NaiveDate};
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+const MONTH_WIDTHS: [usize; 13] = [3, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 5];
+const YEAR_TEMPERATURE_WIDTHS: [usize; 15] = [4, 3, 6, 6, 6, 7, 7, 6, 4, 6, 4, 6, 6, 6, 6];
+const YEAR_RAIN_WIDTHS: [usize; 8] = [4, 3, 7, 6, 4, 6, 6, 6];
+const YEAR_WIND_WIDTHS: [usize; 6] = [4, 3, 6, 6, 4, 5];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Period {
+    Month(i32, u32),
+    Year(i32),
+}
+
+impl Period {
+    /// First and last day of the period.
+    fn days(self) -> Result<(NaiveDate, NaiveDate)> {
+        let invalid = || Error::new_argument(format!("Invalid report period {:?}", self));
+        let (first, next) = match self {
+            Period::Month(year, 12) => (
+                NaiveDate::from_ymd_opt(year, 12, 1),
+                NaiveDate::from_ymd_opt(year + 1, 1, 1),
+            ),
+            Period::Month(year, month) => (
+                NaiveDate::from_ymd_opt(year, month, 1),
+                NaiveDate::from_ymd_opt(year, month + 1, 1),
+            ),
+            Period::Year(year) => (
+                NaiveDate::from_ymd_opt(year, 1, 1),
+                NaiveDate::from_ymd_opt(year + 1, 1, 1),
+            ),
+        };
+        let last = next.and_then(|next| next.pred_opt()).ok_or_else(invalid)?;
+        Ok((first.ok_or_else(invalid)?, last))
+    }
+
+    pub fn file_name(self) -> String {
+        match self {
+            Period::Month(year, month) => format!("NOAA-{}-{:02}.txt", year, month),
+            Period::Year(year) => format!("NOAA-{}.txt", year),
+        }
+    }
+}
+
+/// NOAA climatological summary of a month or a year from the daily summaries.
+///
+/// Degree days use the mean of the readings of the day against the NOAA base of 65 °F.
+/// The dominant wind direction is the direction of the summed wind vectors, it needs a
+/// wind vane.
+#[derive(Debug, Clone)]
+pub struct Report {
+    pub period: Period,
+    pub units: Vec<String>,
+}
+
+/// Values of a day or a group of days in the report units.
+struct Summary {
+    mean_max: f64,
+    mean_min: f64,
+    mean: f64,
+    heating: f64,
+    cooling: f64,
+    high: (f64, NaiveDate),
+    low: (f64, NaiveDate),
+    temperature_days: [usize; 4],
+    rain: Option<(f64, f64, NaiveDate)>,
+    rain_days: [usize; 3],
+    wind: Option<f64>,
+    gust: Option<(f64, NaiveDate)>,
+    direction: Option<f64>,
+}
+
+struct Layout {
+    units: Units,
+    temperature_thresholds: [f64; 4],
+    rain_thresholds: [f64; 3],
+}
+
+impl Report {
+    pub fn new(period: Period) -> Self {
+        Report {
+            period,
+            units: Vec::new(),
+        }
+    }
+
+    pub fn run<W: Write>(
+        &self,
+        store: &ClimateStore,
+        conf: &Climate,
+        units: Units,
+        mut out: W,
+    ) -> Result<()> {
+        let mut units = units;
+        for spec in &self.units {
+            units.update(spec)?;
+        }
+        let layout = Layout::new(units);
+        let (from, to) = self.period.days()?;
+        let days = store.read(from, to)?;
+        let lines = match self.period {
+            Period::Month(..) => layout.month(&days, conf, from),
+            Period::Year(year) => layout.year(&days, conf, year),
+        };
+        for line in lines {
+            writeln!(out, "{}", line.trim_end())?;
+        }
+        Ok(())
+    }
+}
+
+/// Rewrites the reports of the month and the year of `date` in the report directory.
+pub fn write_reports(
+    store: &ClimateStore,
+    conf: &Climate,
+    units: Units,
+    date: NaiveDate,
+) -> Result<()> {
+    fs::create_dir_all(&conf.reports)?;
+    for period in &[
+        Period::Month(date.year(), date.month()),
+        Period::Year(date.year()),
+    ] {
+        let mut content = Vec::new();
+        Report::new(*period).run(store, conf, units, &mut content)?;
+        write_atomic(&Path::new(&conf.reports).join(period.file_name()), &content)?;
+    }
+    Ok(())
+}
+
+impl Layout {
+    fn new(units: Units) -> Self {
+        let temperature_thresholds = match units.temperature {
+            TemperatureUnit::Celsius => NOAA_TEMPERATURE_THRESHOLDS_CELSIUS,
+            TemperatureUnit::Fahrenheit => NOAA_TEMPERATURE_THRESHOLDS_FAHRENHEIT,
+        };
+        let rain_thresholds = match units.rain {
+            RainUnit::Millimeter => NOAA_RAIN_THRESHOLDS_MILLIMETER,
+            RainUnit::Inch => NOAA_RAIN_THRESHOLDS_INCH,
+        };
+        Layout {
+            units,
+            temperature_thresholds,
+            rain_thresholds,
+        }
+    }
+
+    fn month(&self, days: &[DailySummary], conf: &Climate, first: NaiveDate) -> Vec<String> {
+        let title = format!(
+            "MONTHLY CLIMATOLOGICAL SUMMARY for {}",
+            first.format("%b %Y")
+        );
+        let mut lines = self.header(&title, conf, &MONTH_WIDTHS);
+        lines.push(format!(
+            "TEMPERATURE ({}), RAIN ({}), WIND SPEED ({})",
+            self.units.label("temperature"),
+            self.units.label("rain"),
+            self.units.label("wind_speed")
+        ));
+        lines.push(String::new());
+        let headers: [[&str; 13]; 3] = [
+            [
+                "", "", "", "", "", "", "HEAT", "COOL", "", "AVG", "", "", "",
+            ],
+            [
+                "", "MEAN", "", "", "", "", "DEG", "DEG", "", "WIND", "", "", "DOM",
+            ],
+            [
+                "DAY", "TEMP", "HIGH", "TIME", "LOW", "TIME", "DAYS", "DAYS", "RAIN", "SPEED",
+                "HIGH", "TIME", "DIR",
+            ],
+        ];
+        for header in &headers {
+            lines.push(row(header, &MONTH_WIDTHS));
+        }
+        lines.push(separator(&MONTH_WIDTHS));
+        for day in days {
+            let summary = self.summary(std::slice::from_ref(day));
+            let gust_time = day
+                .gust
+                .as_ref()
+                .map(|gust| time_of_day(conf, gust.max_time));
+            lines.push(row(
+                &[
+                    day.date().day().to_string(),
+                    self.temperature(summary.mean),
+                    self.temperature(summary.high.0),
+                    time_of_day(conf, day.temperature.max_time),
+                    self.temperature(summary.low.0),
+                    time_of_day(conf, day.temperature.min_time),
+                    self.temperature(summary.heating),
+                    self.temperature(summary.cooling),
+                    self.rain(summary.rain.map(|rain| rain.0)),
+                    self.wind(summary.wind),
+                    self.wind(summary.gust.map(|gust| gust.0)),
+                    gust_time.unwrap_or_else(missing),
+                    direction(summary.direction),
+                ],
+                &MONTH_WIDTHS,
+            ));
+        }
+        lines.push(separator(&MONTH_WIDTHS));
+        if days.is_empty() {
+            return lines;
+        }
+
+        let summary = self.summary(days);
+        lines.push(row(
+            &[
+                String::new(),
+                self.temperature(summary.mean),
+                self.temperature(summary.high.0),
+                summary.high.1.day().to_string(),
+                self.temperature(summary.low.0),
+                summary.low.1.day().to_string(),
+                self.temperature(summary.heating),
+                self.temperature(summary.cooling),
+                self.rain(summary.rain.map(|rain| rain.0)),
+                self.wind(summary.wind),
+                self.wind(summary.gust.map(|gust| gust.0)),
+                summary
+                    .gust
+                    .map_or_else(missing, |gust| gust.1.day().to_string()),
+                direction(summary.direction),
+            ],
+            &MONTH_WIDTHS,
+        ));
+        lines.push(String::new());
+        let labels = ["Max >=", "Max <=", "Min <=", "Min <="];
+        for ((label, threshold), count) in labels
+            .iter()
+            .zip(&self.temperature_thresholds)
+            .zip(&summary.temperature_days)
+        {
+            lines.push(format!("{} {:>6.1}: {:>3}", label, threshold, count));
+        }
+        if let Some((_, max, date)) = summary.rain {
+            lines.push(format!(
+                "Max rain: {} on day {}",
+                self.rain(Some(max)),
+                date.day()
+            ));
+            lines.push(format!(
+                "Days of rain: {}",
+                self.rain_days(&summary.rain_days).join(", ")
+            ));
+        }
+        let base = self.temperature(self.units.convert("temperature", NOAA_DEGREE_DAY_BASE));
+        lines.push(format!("Heat base: {}, cool base: {}", base, base));
+        lines
+    }
+
+    fn year(&self, days: &[DailySummary], conf: &Climate, year: i32) -> Vec<String> {
+        let title = format!("ANNUAL CLIMATOLOGICAL SUMMARY for {}", year);
+        let mut lines = self.header(&title, conf, &YEAR_TEMPERATURE_WIDTHS);
+        let months: Vec<(u32, &[DailySummary])> = (1..=12)
+            .filter_map(|month| {
+                let start = days.iter().position(|day| day.date().month() == month)?;
+                let end = days.iter().rposition(|day| day.date().month() == month)?;
+                Some((month, &days[start..=end]))
+            })
+            .collect();
+        let total = if days.is_empty() {
+            None
+        } else {
+            Some(self.summary(days))
+        };
+        let base = self.temperature(self.units.convert("temperature", NOAA_DEGREE_DAY_BASE));
+
+        lines.push(format!(
+            "TEMPERATURE ({}), HEAT BASE {}, COOL BASE {}",
+            self.units.label("temperature"),
+            base,
+            base
+        ));
+        lines.push(String::new());
+        let thresholds: Vec<String> = [">=", "<=", "<=", "<="]
+            .iter()
+            .zip(&self.temperature_thresholds)
+            .map(|(sign, threshold)| format!("{}{:.0}", sign, threshold))
+            .collect();
+        lines.push(row(
+            &[
+                "", "", "MEAN", "MEAN", "", "HEAT", "COOL", "", "", "", "", "MAX", "MAX", "MIN",
+                "MIN",
+            ],
+            &YEAR_TEMPERATURE_WIDTHS,
+        ));
+        let mut header = vec![
+            "YR", "MO", "MAX", "MIN", "MEAN", "DEG DAY", "DEG DAY", "HIGH", "DAY", "LOW", "DAY",
+        ];
+        header.extend(thresholds.iter().map(String::as_str));
+        lines.push(row(&header, &YEAR_TEMPERATURE_WIDTHS));
+        lines.push(separator(&YEAR_TEMPERATURE_WIDTHS));
+        let temperature_row =
+            |label: (String, String), summary: &Summary, day: fn(NaiveDate) -> u32| {
+                let mut cells = vec![
+                    label.0,
+                    label.1,
+                    self.temperature(summary.mean_max),
+                    self.temperature(summary.mean_min),
+                    self.temperature(summary.mean),
+                    self.temperature(summary.heating),
+                    self.temperature(summary.cooling),
+                    self.temperature(summary.high.0),
+                    day(summary.high.1).to_string(),
+                    self.temperature(summary.low.0),
+                    day(summary.low.1).to_string(),
+                ];
+                cells.extend(
+                    summary
+                        .temperature_days
+                        .iter()
+                        .map(|count| count.to_string()),
+                );
+                row(&cells, &YEAR_TEMPERATURE_WIDTHS)
+            };
+        for (month, days) in &months {
+            let label = (year.to_string(), month.to_string());
+            lines.push(temperature_row(label, &self.summary(days), |date| {
+                date.day()
+            }));
+        }
+        lines.push(separator(&YEAR_TEMPERATURE_WIDTHS));
+        if let Some(total) = &total {
+            let label = (String::new(), String::new());
+            lines.push(temperature_row(label, total, |date| date.month()));
+        }
+
+        lines.push(String::new());
+        lines.push(format!("PRECIPITATION ({})", self.units.label("rain")));
+        lines.push(String::new());
+        lines.push(row(
+            &["", "", "", "MAX", "", "DAYS", "OF", "RAIN"],
+            &YEAR_RAIN_WIDTHS,
+        ));
+        let mut header = vec!["YR", "MO", "TOTAL", "OBS", "DAY"];
+        let rain_thresholds: Vec<String> = self
+            .rain_thresholds
+            .iter()
+            .map(|threshold| format!(">={}", threshold))
+            .collect();
+        header.extend(rain_thresholds.iter().map(String::as_str));
+        lines.push(row(&header, &YEAR_RAIN_WIDTHS));
+        lines.push(separator(&YEAR_RAIN_WIDTHS));
+        let rain_row = |label: (String, String), summary: &Summary, day: fn(NaiveDate) -> u32| {
+            let mut cells = vec![label.0, label.1];
+            match summary.rain {
+                Some((total, max, date)) => {
+                    cells.push(self.rain(Some(total)));
+                    cells.push(self.rain(Some(max)));
+                    cells.push(day(date).to_string());
+                    cells.extend(summary.rain_days.iter().map(|count| count.to_string()));
+                }
+                None => cells.extend((0..6).map(|_| missing())),
+            }
+            row(&cells, &YEAR_RAIN_WIDTHS)
+        };
+        for (month, days) in &months {
+            let label = (year.to_string(), month.to_string());
+            lines.push(rain_row(label, &self.summary(days), |date| date.day()));
+        }
+        lines.push(separator(&YEAR_RAIN_WIDTHS));
+        if let Some(total) = &total {
+            let label = (String::new(), String::new());
+            lines.push(rain_row(label, total, |date| date.month()));
+        }
+
+        lines.push(String::new());
+        lines.push(format!("WIND SPEED ({})", self.units.label("wind_speed")));
+        lines.push(String::new());
+        lines.push(row(&["", "", "", "", "", "DOM"], &YEAR_WIND_WIDTHS));
+        lines.push(row(
+            &["YR", "MO", "AVG", "HIGH", "DAY", "DIR"],
+            &YEAR_WIND_WIDTHS,
+        ));
+        lines.push(separator(&YEAR_WIND_WIDTHS));
+        let wind_row = |label: (String, String), summary: &Summary, day: fn(NaiveDate) -> u32| {
+            row(
+                &[
+                    label.0,
+                    label.1,
+                    self.wind(summary.wind),
+                    self.wind(summary.gust.map(|gust| gust.0)),
+                    summary
+                        .gust
+                        .map_or_else(missing, |gust| day(gust.1).to_string()),
+                    direction(summary.direction),
+                ],
+                &YEAR_WIND_WIDTHS,
+            )
+        };
+        for (month, days) in &months {
+            let label = (year.to_string(), month.to_string());
+            lines.push(wind_row(label, &self.summary(days), |date| date.day()));
+        }
+        lines.push(separator(&YEAR_WIND_WIDTHS));
+        if let Some(total) = &total {
+            let label = (String::new(), String::new());
+            lines.push(wind_row(label, total, |date| date.month()));
+        }
+        lines
+    }
+
+    fn header(&self, title: &str, conf: &Climate, widths: &[usize]) -> Vec<String> {
+        let width = widths.iter().sum::<usize>() + widths.len() - 1;
+        vec![
+            format!("{:^width$}", title, width = width),
+            String::new(),
+            format!("NAME: {}", conf.station),
+            String::new(),
+        ]
+    }
+
+    /// Summary of the days, which must not be empty.
+    fn summary(&self, days: &[DailySummary]) -> Summary {
+        let temperature = |value: f64| self.units.convert("temperature", value);
+        let count = days.len() as f64;
+        let extreme = |value: fn(&DailySummary) -> f64, higher: bool| {
+            let mut best: Option<(f64, NaiveDate)> = None;
+            for day in days {
+                let current = value(day);
+                let better = match best {
+                    Some((best, _)) if higher => current > best,
+                    Some((best, _)) => current < best,
+                    None => true,
+                };
+                if better {
+                    best = Some((current, day.date()));
+                }
+            }
+            best.map_or((0., NaiveDate::MIN), |(value, date)| {
+                (temperature(value), date)
+            })
+        };
+        let high = extreme(|day| day.temperature.max, true);
+        let low = extreme(|day| day.temperature.min, false);
+        let thresholds = self.temperature_thresholds;
+        let temperature_days = [
+            days.iter()
+                .filter(|day| temperature(day.temperature.max) >= thresholds[0])
+                .count(),
+            days.iter()
+                .filter(|day| temperature(day.temperature.max) <= thresholds[1])
+                .count(),
+            days.iter()
+                .filter(|day| temperature(day.temperature.min) <= thresholds[2])
+                .count(),
+            days.iter()
+                .filter(|day| temperature(day.temperature.min) <= thresholds[3])
+                .count(),
+        ];
+        // Degree days are differences, only the scale of the unit applies.
+        let scale = temperature(1.) - temperature(0.);
+        let heating = days
+            .iter()
+            .map(|day| (NOAA_DEGREE_DAY_BASE - day.temperature.mean()).max(0.) * scale)
+            .sum();
+        let cooling = days
+            .iter()
+            .map(|day| (day.temperature.mean() - NOAA_DEGREE_DAY_BASE).max(0.) * scale)
+            .sum();
+
+        let rains: Vec<(f64, NaiveDate)> = days
+            .iter()
+            .filter_map(|day| Some((self.units.convert("rain", day.rain?), day.date())))
+            .collect();
+        let mut rain: Option<(f64, f64, NaiveDate)> = None;
+        for (amount, date) in &rains {
+            rain = match rain {
+                Some((total, max, max_date)) if max >= *amount => {
+                    Some((total + amount, max, max_date))
+                }
+                Some((total, ..)) => Some((total + amount, *amount, *date)),
+                None => Some((*amount, *amount, *date)),
+            };
+        }
+        let mut rain_days = [0; 3];
+        for (count, threshold) in rain_days.iter_mut().zip(&self.rain_thresholds) {
+            *count = rains
+                .iter()
+                .filter(|(amount, _)| amount >= threshold)
+                .count();
+        }
+
+        let wind = merge(days.iter().filter_map(|day| day.wind.as_ref()))
+            .map(|(sum, count)| self.units.convert("wind_speed", sum / count as f64));
+        let gust = days
+            .iter()
+            .filter_map(|day| Some((day.gust?.max, day.date())))
+            .fold(None, |best: Option<(f64, NaiveDate)>, current| match best {
+                Some(best) if best.0 >= current.0 => Some(best),
+                _ => Some(current),
+            })
+            .map(|(gust, date)| (self.units.convert("wind_gust", gust), date));
+        let direction = days
+            .iter()
+            .filter_map(|day| day.wind_vector)
+            .fold(None, |sum: Option<(f64, f64)>, (east, north)| {
+                let (sum_east, sum_north) = sum.unwrap_or((0., 0.));
+                Some((sum_east + east, sum_north + north))
+            })
+            .map(|(east, north)| east.atan2(north).to_degrees().rem_euclid(360.));
+
+        Summary {
+            mean_max: days
+                .iter()
+                .map(|day| temperature(day.temperature.max))
+                .sum::<f64>()
+                / count,
+            mean_min: days
+                .iter()
+                .map(|day| temperature(day.temperature.min))
+                .sum::<f64>()
+                / count,
+            mean: days
+                .iter()
+                .map(|day| temperature(day.temperature.mean()))
+                .sum::<f64>()
+                / count,
+            heating,
+            cooling,
+            high,
+            low,
+            temperature_days,
+            rain,
+            rain_days,
+            wind,
+            gust,
+            direction,
+        }
+    }
+
+    fn temperature(&self, value: f64) -> String {
+        format!("{:.1}", value)
+    }
+
+    fn rain(&self, value: Option<f64>) -> String {
+        match (value, self.units.rain) {
+            (Some(value), RainUnit::Millimeter) => format!("{:.1}", value),
+            (Some(value), RainUnit::Inch) => format!("{:.2}", value),
+            (None, _) => missing(),
+        }
+    }
+
+    fn wind(&self, value: Option<f64>) -> String {
+        value.map_or_else(missing, |value| format!("{:.1}", value))
+    }
+
+    fn rain_days(&self, counts: &[usize; 3]) -> Vec<String> {
+        counts
+            .iter()
+            .zip(&self.rain_thresholds)
+            .map(|(count, threshold)| {
+                format!("{} (>= {} {})", count, threshold, self.units.label("rain"))
+            })
+            .collect()
+    }
+}
+
+/// Sum and count of the daily means.
+fn merge<'a>(stats: impl Iterator<Item = &'a Stat>) -> Option<(f64, u32)> {
+    stats.fold(None, |result, stat| {
+        let (sum, count) = result.unwrap_or((0., 0));
+        Some((sum + stat.sum, count + stat.count))
+    })
+}
+
+fn row<S: AsRef<str>>(cells: &[S], widths: &[usize]) -> String {
+    cells
+        .iter()
+        .zip(widths)
+        .map(|(cell, width)| format!("{:>width$}", cell.as_ref(), width = width))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn separator(widths: &[usize]) -> String {
+    "-".repeat(widths.iter().sum::<usize>() + widths.len() - 1)
+}
+
+fn time_of_day(conf: &Climate, millis: i64) -> String {
+    local_time(utc_from_millis(millis), conf.timezone)
+        .format("%H:%M")
+        .to_string()
+}
+
+fn direction(direction: Option<f64>) -> String {
+    direction.map_or_else(missing, |direction| compass_point(direction).to_string())
+}
+
+fn missing() -> String {
+    "---".to_string()
+}
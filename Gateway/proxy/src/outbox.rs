@@ -0,0 +1,308 @@
+use crate::config::{DropPolicy, Outbox};
+use crate::error::Result;
+use crate::frame::{self, read_frames};
+use crate::metrics::MetricsRegistry;
+use crate::util::{utc_from_millis, Shared, OUTBOX_TRIM_PERCENT};
+use futures::compat::Future01CompatExt;
+use paho_mqtt::{AsyncClient, Message};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueuedMessage {
+    /// Milliseconds since the Unix epoch of the reading the message belongs to.
+    pub timestamp: i64,
+    pub topic: String,
+    pub payload: Vec<u8>,
+}
+
+impl QueuedMessage {
+    pub fn new<V: Into<Vec<u8>>>(timestamp: i64, topic: &str, payload: V) -> Self {
+        QueuedMessage {
+            timestamp,
+            topic: topic.to_string(),
+            payload: payload.into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Connection {
+    Connected,
+    Reconnected,
+    Disconnected,
+}
+
+impl Connection {
+    pub fn is_up(self) -> bool {
+        self != Connection::Disconnected
+    }
+}
+
+/// Reconnects a client that lost its broker, the sinks check it before every reading.
+pub async fn reconnect(mqtt: &AsyncClient, name: &str) -> Connection {
+    if mqtt.is_connected() {
+        return Connection::Connected;
+    }
+    match mqtt.reconnect().compat().await {
+        Ok(_) => {
+            info!("Outbox {} reconnected", name);
+            Connection::Reconnected
+        }
+        Err(err) => {
+            debug!("Outbox {} reconnect failed: {:?}", name, err);
+            Connection::Disconnected
+        }
+    }
+}
+
+/// Durable queue of messages a sink could not deliver.
+///
+/// New messages are held back until the queue is drained, so the destination always
+/// receives them in the original order. The queue file is rewritten only when messages
+/// leave the queue, which happens just after an outage.
+pub struct OutboxQueue {
+    name: String,
+    path: PathBuf,
+    file: File,
+    queue: VecDeque<QueuedMessage>,
+    max_messages: usize,
+    drop_policy: DropPolicy,
+    metrics: Shared<MetricsRegistry>,
+}
+
+impl OutboxQueue {
+    pub fn open(conf: &Outbox, name: &str, metrics: Shared<MetricsRegistry>) -> Result<Self> {
+        let dir = PathBuf::from(&conf.path);
+        fs::create_dir_all(&dir)?;
+        let path = dir.join(name).with_extension("queue");
+        let queue: VecDeque<QueuedMessage> = if path.exists() {
+            read_frames::<QueuedMessage>(&path)?.0.into_iter().collect()
+        } else {
+            VecDeque::new()
+        };
+        if !queue.is_empty() {
+            info!("Outbox {} has {} queued messages", name, queue.len());
+        }
+        let mut outbox = OutboxQueue {
+            name: name.to_string(),
+            file: OpenOptions::new().create(true).append(true).open(&path)?,
+            path,
+            queue,
+            max_messages: conf.max_messages,
+            drop_policy: conf.drop_policy,
+            metrics,
+        };
+        // Drops a torn frame and applies a possibly lowered limit.
+        outbox.trim();
+        outbox.rewrite()?;
+        Ok(outbox)
+    }
+
+    /// Publishes the queued messages followed by the new ones, whatever is not delivered
+    /// stays queued. Only a failure of the queue file is returned as an error.
+    pub async fn publish(
+        &mut self,
+        mqtt: &AsyncClient,
+        connection: Connection,
+        messages: Vec<QueuedMessage>,
+    ) -> Result<()> {
+        if !connection.is_up() {
+            return self.enqueue(messages);
+        }
+        if let Some(oldest) = self.queue.front() {
+            let since = utc_from_millis(oldest.timestamp);
+            let queued = self.queue.len();
+            let mut sent = 0;
+            while let Some(message) = self.queue.front() {
+                if send(mqtt, message).await.is_err() {
+                    self.metrics.lock().await.publish_failed(&self.name);
+                    break;
+                }
+                self.queue.pop_front();
+                sent += 1;
+            }
+            info!(
+                "Outbox {} replayed {} of {} messages queued since {}",
+                self.name, sent, queued, since
+            );
+            self.rewrite()?;
+            if !self.queue.is_empty() {
+                return self.enqueue(messages);
+            }
+        }
+
+        let mut messages = messages.into_iter();
+        while let Some(message) = messages.next() {
+            if let Err(err) = send(mqtt, &message).await {
+                self.metrics.lock().await.publish_failed(&self.name);
+                warn!("Outbox {} buffering after error: {:?}", self.name, err);
+                return self.enqueue(Some(message).into_iter().chain(messages).collect());
+            }
+        }
+        Ok(())
+    }
+
+    fn enqueue(&mut self, messages: Vec<QueuedMessage>) -> Result<()> {
+        let mut frames = Vec::new();
+        for message in messages {
+            if self.drop_policy == DropPolicy::DropNewest && self.queue.len() >= self.max_messages {
+                warn!("Outbox {} is full, dropping {}", self.name, message.topic);
+                continue;
+            }
+            frames.extend(frame::encode(&message)?);
+            self.queue.push_back(message);
+        }
+        if self.trim() {
+            return self.rewrite();
+        }
+        self.file.write_all(&frames)?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    /// Drops the oldest messages once above the limit, down to `OUTBOX_TRIM_PERCENT` of it
+    /// so a full queue is not rewritten for every new message. Returns true when the file
+    /// is stale.
+    fn trim(&mut self) -> bool {
+        if self.queue.len() <= self.max_messages {
+            return false;
+        }
+        let keep = (self.max_messages * OUTBOX_TRIM_PERCENT / 100)
+            .min(self.max_messages.saturating_sub(1));
+        let excess = self.queue.len() - keep;
+        warn!(
+            "Outbox {} is full, dropping {} oldest messages",
+            self.name, excess
+        );
+        self.queue.drain(..excess);
+        true
+    }
+
+    /// Atomically replaces the queue file with the queue content.
+    fn rewrite(&mut self) -> Result<()> {
+        let tmp = self.path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        for message in &self.queue {
+            file.write_all(&frame::encode(message)?)?;
+        }
+        file.sync_all()?;
+        fs::rename(&tmp, &self.path)?;
+        if let Some(dir) = self.path.parent() {
+            frame::sync_dir(dir)?;
+        }
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        Ok(())
+    }
+}
+
+async fn send(mqtt: &AsyncClient, message: &QueuedMessage) -> Result<()> {
+    let msg = Message::new(message.topic.as_str(), message.payload.clone(), 0);
+    mqtt.publish(msg).compat().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::test_dir;
+    use async_std::sync::{Arc, Mutex};
+
+    fn conf(name: &str, max_messages: usize, drop_policy: DropPolicy) -> Outbox {
+        Outbox {
+            path: test_dir(name).display().to_string(),
+            max_messages,
+            drop_policy,
+        }
+    }
+
+    fn open(conf: &Outbox) -> OutboxQueue {
+        OutboxQueue::open(conf, "sink", Arc::new(Mutex::new(MetricsRegistry::new()))).unwrap()
+    }
+
+    fn messages(range: std::ops::Range<i64>) -> Vec<QueuedMessage> {
+        range
+            .map(|num| QueuedMessage::new(num, &format!("topic/{}", num), num.to_string()))
+            .collect()
+    }
+
+    fn timestamps(outbox: &OutboxQueue) -> Vec<i64> {
+        outbox
+            .queue
+            .iter()
+            .map(|message| message.timestamp)
+            .collect()
+    }
+
+    #[test]
+    fn queued_messages_are_restored_in_order() {
+        let conf = conf("outbox-restore", 10, DropPolicy::DropOldest);
+        let mut outbox = open(&conf);
+        outbox.enqueue(messages(0..2)).unwrap();
+        outbox.enqueue(messages(2..3)).unwrap();
+        drop(outbox);
+
+        let outbox = open(&conf);
+        assert_eq!(timestamps(&outbox), [0, 1, 2]);
+        let message = &outbox.queue[2];
+        assert_eq!(
+            (message.topic.as_str(), &message.payload[..]),
+            ("topic/2", &b"2"[..])
+        );
+    }
+
+    #[test]
+    fn torn_tail_is_repaired() {
+        let conf = conf("outbox-torn", 10, DropPolicy::DropOldest);
+        let mut outbox = open(&conf);
+        outbox.enqueue(messages(0..2)).unwrap();
+        let valid = fs::metadata(&outbox.path).unwrap().len();
+        let frame = frame::encode(&messages(2..3)[0]).unwrap();
+        outbox.file.write_all(&frame[..frame.len() - 3]).unwrap();
+        drop(outbox);
+
+        let mut outbox = open(&conf);
+        assert_eq!(timestamps(&outbox), [0, 1]);
+        assert_eq!(fs::metadata(&outbox.path).unwrap().len(), valid);
+        outbox.enqueue(messages(3..4)).unwrap();
+        drop(outbox);
+        assert_eq!(timestamps(&open(&conf)), [0, 1, 3]);
+    }
+
+    #[test]
+    fn drop_oldest_trims_in_chunks() {
+        let conf = conf("outbox-oldest", 10, DropPolicy::DropOldest);
+        let mut outbox = open(&conf);
+        for message in messages(0..10) {
+            outbox.enqueue(vec![message]).unwrap();
+        }
+        assert_eq!(outbox.queue.len(), 10);
+        outbox.enqueue(messages(10..11)).unwrap();
+        assert_eq!(timestamps(&outbox), (2..11).collect::<Vec<_>>());
+        // Below the limit again, new messages are appended without dropping.
+        outbox.enqueue(messages(11..12)).unwrap();
+        assert_eq!(outbox.queue.len(), 10);
+        drop(outbox);
+        assert_eq!(timestamps(&open(&conf)), (2..12).collect::<Vec<_>>());
+
+        let conf = Outbox {
+            max_messages: 4,
+            ..conf
+        };
+        assert_eq!(timestamps(&open(&conf)), [9, 10, 11]);
+    }
+
+    #[test]
+    fn drop_newest_keeps_the_queued_messages() {
+        let conf = conf("outbox-newest", 3, DropPolicy::DropNewest);
+        let mut outbox = open(&conf);
+        outbox.enqueue(messages(0..2)).unwrap();
+        outbox.enqueue(messages(2..5)).unwrap();
+        assert_eq!(timestamps(&outbox), [0, 1, 2]);
+        drop(outbox);
+        assert_eq!(timestamps(&open(&conf)), [0, 1, 2]);
+    }
+}
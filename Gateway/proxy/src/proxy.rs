@@ -1,11 +1,18 @@
+use crate::battery::BatteryMonitor;
+use crate::climate::ClimateStore;
 use crate::config::{read_conf, Config};
+use crate::counter::CounterMonitor;
 use crate::data::Data;
 use crate::error::{Error, Result};
-use crate::home_assistant::HomeAssistant;
+use crate::history::HistoryStore;
+use crate::metrics::{self, MetricsRegistry};
+use crate::noaa;
 use crate::radio::Radio;
-use crate::util::{Receiver, Shared, PAYLOAD_ON};
-use crate::vutbr::VutBr;
+use crate::reading::Reading;
+use crate::sink::{create_sinks, CommandStream, Sink, SinkWorker};
+use crate::util::{Receiver, Shared, CALIBRATION_TOPIC_PREFIX, PAYLOAD_ON};
 use async_std::sync::{Arc, Mutex};
+use futures::stream::{self, select_all};
 use futures::{select, FutureExt, StreamExt};
 use paho_mqtt::Message;
 use std::convert::TryFrom;
@@ -15,36 +22,117 @@ pub struct Proxy {
     conf: Shared<Config>,
     shutdown: Receiver<bool>,
     radio: Radio,
-    mqtt: HomeAssistant,
-    vutbr: VutBr,
+    sinks: Vec<Box<dyn Sink>>,
+    battery: BatteryMonitor,
+    counters: CounterMonitor,
+    history: Option<HistoryStore>,
+    climate: Option<ClimateStore>,
+    metrics: Shared<MetricsRegistry>,
 }
 
 impl Proxy {
     pub async fn new(conf_path: &str, shutdown: Receiver<bool>) -> Result<Self> {
-        let conf = new_shared!(read_conf(conf_path).await?);
-        let radio = Radio::new(conf.clone())?;
-        let mqtt = HomeAssistant::new(conf.clone()).await?;
-        let vutbr = VutBr::new().await?;
+        let conf = read_conf(conf_path).await?;
+        let history = match conf.history() {
+            Some(history) => Some(HistoryStore::open(history)?),
+            None => None,
+        };
+        let climate = match conf.climate() {
+            Some(climate) => Some(ClimateStore::open(climate)?),
+            None => None,
+        };
+        let metrics = new_shared!(MetricsRegistry::new());
+        if let Some(metrics_conf) = conf.metrics() {
+            metrics::serve(metrics_conf, metrics.clone()).await?;
+        }
+        let conf = new_shared!(conf);
+        let radio = Radio::new(conf.clone(), metrics.clone())?;
+        let sinks = create_sinks(conf.clone(), metrics.clone()).await?;
         Ok(Proxy {
             conf,
             shutdown,
             radio,
-            mqtt,
-            vutbr,
+            sinks,
+            battery: BatteryMonitor::new(),
+            counters: CounterMonitor::new(),
+            history,
+            climate,
+            metrics,
         })
     }
 
     pub async fn main_loop(&mut self) -> Result<()> {
+        for sink in self.sinks.iter_mut() {
+            sink.start().await?;
+        }
+        let commands: Vec<_> = self
+            .sinks
+            .iter_mut()
+            .filter_map(|sink| sink.commands())
+            .collect();
+        let workers: Vec<_> = self.sinks.drain(..).map(SinkWorker::spawn).collect();
+        let result = self.receive_loop(commands, &workers).await;
+        for worker in workers {
+            let mut sink = worker.stop().await;
+            if let Err(err) = sink.shutdown().await {
+                eprintln!("{}: {}", sink.name(), err);
+                error!("{}: {:?}", sink.name(), err);
+            }
+            self.sinks.push(sink);
+        }
+        info!("Main loop exit");
+        result
+    }
+
+    async fn receive_loop(
+        &mut self,
+        commands: Vec<CommandStream>,
+        workers: &[SinkWorker],
+    ) -> Result<()> {
+        // Without any command stream the merged stream would end immediately.
+        let mut mqtt_receiver = select_all(commands).chain(stream::pending()).fuse();
         let mut shutdown = (&mut self.shutdown).fuse();
         let mut receiver = self.radio.receiver_channel();
-        let mut mqtt_receiver = self.mqtt.stream().fuse();
         loop {
             select! {
                 opt = receiver.next().fuse() => match opt {
                     Some(buffer) => {
-                        let data = Data::try_from(&buffer[..])?;
-                        self.mqtt.update_state(&data).await?;
-                        self.vutbr.update_state(&data).await?;
+                        let data = match Data::try_from(&buffer[..]) {
+                            Ok(data) => data,
+                            Err(err) => {
+                                self.metrics.lock().await.decode_errors += 1;
+                                eprintln!("{}", err);
+                                error!("{:?}", err);
+                                continue;
+                            }
+                        };
+                        let mut reading = {
+                            let conf = self.conf.lock().await;
+                            Reading::new(data, &conf, &mut self.battery, &mut self.counters)
+                        };
+                        {
+                            let mut metrics = self.metrics.lock().await;
+                            metrics.update(&reading);
+                            reading.rssi = metrics.rssi(reading.node);
+                        }
+                        if let Some(climate) = &mut self.climate {
+                            if let Err(err) = update_climate(&self.conf, climate, &reading).await {
+                                eprintln!("{}", err);
+                                error!("{:?}", err);
+                            }
+                            reading.statistics = climate.statistics();
+                            reading.indices = climate.indices();
+                        }
+                        let reading = Arc::new(reading);
+                        for worker in workers {
+                            worker.publish(&reading);
+                        }
+                        if let Some(history) = &mut self.history {
+                            if let Err(err) = history.append(&reading) {
+                                eprintln!("{}", err);
+                                error!("{:?}", err);
+                            }
+                        }
                     },
                     None => error!("Radio channel is closed"),
                 },
@@ -58,11 +146,25 @@ impl Proxy {
             }
         }
         receiver.close();
-        info!("Main loop exit");
         Ok(())
     }
 }
 
+/// Adds the reading to the daily summaries, the reports are rewritten once a day ends.
+async fn update_climate(
+    shared_conf: &Shared<Config>,
+    store: &mut ClimateStore,
+    reading: &Reading,
+) -> Result<()> {
+    if let Some(finished) = store.update(reading)? {
+        let conf = shared_conf.lock().await;
+        if let Some(climate) = conf.climate() {
+            noaa::write_reports(store, climate, conf.units(), finished.date())?;
+        }
+    }
+    Ok(())
+}
+
 async fn helper_mqtt_config(
     shared_conf: Shared<Config>,
     wrapper: Option<StdResult<Option<Message>, ()>>,
@@ -73,14 +175,30 @@ async fn helper_mqtt_config(
                 error!("MQTT stream error");
                 return Ok(());
             }
-            let message = result
-                .map_err(|()| Error::new_result("MQTT message error, hint Result"))?
-                .ok_or_else(|| Error::new_option("MQTT message error"))?;
+            let message =
+                match result.map_err(|()| Error::new_result("MQTT message error, hint Result"))? {
+                    Some(message) => message,
+                    None => {
+                        // paho yields None when the connection is lost, it reconnects itself.
+                        warn!("MQTT connection lost");
+                        return Ok(());
+                    }
+                };
             let mut conf = shared_conf.lock().await;
             let payload = message.payload_str();
-            if payload.len() == 1 {
-                conf.node_mut()
-                    .update_output(message.topic(), payload == PAYLOAD_ON)?;
+            if message.topic().starts_with(CALIBRATION_TOPIC_PREFIX) {
+                if let Err(err) = conf.update_calibration(message.topic(), &payload) {
+                    eprintln!("{}", err);
+                    error!("{:?}", err);
+                }
+            } else if payload.len() == 1 {
+                if let Err(err) = conf
+                    .node_mut()
+                    .update_output(message.topic(), payload == PAYLOAD_ON)
+                {
+                    eprintln!("{}", err);
+                    error!("{:?}", err);
+                }
             }
             debug!("Message {:?}", message);
         }
@@ -0,0 +1,388 @@
+use crate::counter::RainWindow;
+use crate::error::{Error, Result};
+use crate::http::{self, read_body};
+use crate::reading::{Quality, Reading};
+use crate::sink::Sink;
+use crate::units::Units;
+use crate::util::{
+    PWS_INTERVAL, PWS_OPENWEATHERMAP_SINK, PWS_OPENWEATHERMAP_URL, PWS_RETRY_DELAY,
+    PWS_SOFTWARE_TYPE, PWS_WEATHER_UNDERGROUND_SINK, PWS_WEATHER_UNDERGROUND_URL, PWS_WINDY_SINK,
+    PWS_WINDY_URL, RAIN_HOUR_WINDOW,
+};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::time::{Duration, Instant};
+use surf::{Client, Url};
+
+/// Personal weather station upload, `node` limits it to the readings of one node.
+///
+/// `station_id` is the station index for Windy, `key` the station password, API key or
+/// app id of the service. `url` replaces the service endpoint.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Pws {
+    pub service: PwsService,
+    pub station_id: String,
+    pub key: String,
+    #[serde(default)]
+    pub node: Option<u8>,
+    #[serde(default)]
+    pub url: Option<String>,
+    #[serde(default = "default_pws_interval")]
+    pub interval: u64,
+    #[serde(default = "default_pws_retry_delay")]
+    pub retry_delay: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum PwsService {
+    WeatherUnderground,
+    Windy,
+    OpenWeatherMap,
+}
+
+impl PwsService {
+    pub fn sink_name(self) -> &'static str {
+        match self {
+            PwsService::WeatherUnderground => PWS_WEATHER_UNDERGROUND_SINK,
+            PwsService::Windy => PWS_WINDY_SINK,
+            PwsService::OpenWeatherMap => PWS_OPENWEATHERMAP_SINK,
+        }
+    }
+
+    pub fn url(self) -> &'static str {
+        match self {
+            PwsService::WeatherUnderground => PWS_WEATHER_UNDERGROUND_URL,
+            PwsService::Windy => PWS_WINDY_URL,
+            PwsService::OpenWeatherMap => PWS_OPENWEATHERMAP_URL,
+        }
+    }
+}
+
+fn default_pws_interval() -> u64 {
+    PWS_INTERVAL
+}
+
+fn default_pws_retry_delay() -> u64 {
+    PWS_RETRY_DELAY
+}
+
+/// Uploads the current conditions to a personal weather station service.
+///
+/// Only the newest good reading is uploaded once `interval` elapsed. A failed upload is
+/// retried with a later reading after `retry_delay`, since the services only keep the
+/// current conditions.
+pub struct PwsUploader {
+    name: String,
+    conf: Pws,
+    client: Client,
+    url: Url,
+    next_upload: Option<Instant>,
+    failures: u32,
+    rain: RainWindow,
+}
+
+impl PwsUploader {
+    pub fn new(name: String, conf: &Pws) -> Result<Self> {
+        let url = match &conf.url {
+            Some(url) => url.as_str(),
+            None => conf.service.url(),
+        };
+        Ok(PwsUploader {
+            name,
+            conf: conf.clone(),
+            client: http::client(),
+            url: http::parse_url(url)?,
+            next_upload: None,
+            failures: 0,
+            rain: RainWindow::new(RAIN_HOUR_WINDOW),
+        })
+    }
+
+    async fn upload(&self, reading: &Reading, rain_hour: Option<f64>) -> Result<()> {
+        let mut url = self.url.clone();
+        let request = match self.conf.service {
+            PwsService::WeatherUnderground => {
+                let units = Units::imperial();
+                let mut params = vec![
+                    ("ID", self.conf.station_id.clone()),
+                    ("PASSWORD", self.conf.key.clone()),
+                    (
+                        "dateutc",
+                        reading.timestamp.format("%Y-%m-%d %H:%M:%S").to_string(),
+                    ),
+                    (
+                        "tempf",
+                        value(units.convert("temperature", reading.temperature)),
+                    ),
+                    ("humidity", value(reading.humidity)),
+                    (
+                        "dewptf",
+                        value(units.convert("temperature", reading.dew_point())),
+                    ),
+                    (
+                        "baromin",
+                        value(units.convert("pressure", reading.pressure)),
+                    ),
+                ];
+                if let Some(wind) = &reading.wind {
+                    params.push((
+                        "windspeedmph",
+                        value(units.convert("wind_speed", wind.speed)),
+                    ));
+                    params.push(("windgustmph", value(units.convert("wind_gust", wind.gust))));
+                }
+                if let Some(direction) = reading.wind_direction {
+                    params.push(("winddir", value(direction)));
+                }
+                if let (Some(rain), Some(rain_hour)) = (&reading.rain, rain_hour) {
+                    params.push(("rainin", value(units.convert("rain", rain_hour))));
+                    params.push(("dailyrainin", value(units.convert("rain", rain.daily))));
+                }
+                params.push(("softwaretype", PWS_SOFTWARE_TYPE.to_string()));
+                params.push(("action", "updateraw".to_string()));
+                url.query_pairs_mut().extend_pairs(params);
+                self.client.get(url.as_str())
+            }
+            PwsService::Windy => {
+                url.path_segments_mut()
+                    .map_err(|_| Error::new_option("Windy URL cannot have a path"))?
+                    .pop_if_empty()
+                    .push(&self.conf.key);
+                let mut params = vec![
+                    ("station", self.conf.station_id.clone()),
+                    ("ts", reading.timestamp.timestamp().to_string()),
+                    ("temp", value(reading.temperature)),
+                    ("humidity", value(reading.humidity)),
+                    ("dewpoint", value(reading.dew_point())),
+                    ("pressure", value(reading.pressure * 100.)),
+                ];
+                if let Some(wind) = &reading.wind {
+                    params.push(("wind", value(wind.speed)));
+                    params.push(("gust", value(wind.gust)));
+                }
+                if let Some(direction) = reading.wind_direction {
+                    params.push(("winddir", value(direction)));
+                }
+                if let Some(rain_hour) = rain_hour {
+                    params.push(("precip", value(rain_hour)));
+                }
+                url.query_pairs_mut().extend_pairs(params);
+                self.client.get(url.as_str())
+            }
+            PwsService::OpenWeatherMap => {
+                let mut measurement = json!({
+                    "station_id": self.conf.station_id,
+                    "dt": reading.timestamp.timestamp(),
+                    "temperature": reading.temperature,
+                    "humidity": reading.humidity,
+                    "dew_point": reading.dew_point(),
+                    "pressure": reading.pressure,
+                });
+                if let Some(wind) = &reading.wind {
+                    measurement["wind_speed"] = json!(wind.speed);
+                    measurement["wind_gust"] = json!(wind.gust);
+                }
+                if let Some(direction) = reading.wind_direction {
+                    measurement["wind_deg"] = json!(direction);
+                }
+                if let Some(rain_hour) = rain_hour {
+                    measurement["rain_1h"] = json!(rain_hour);
+                }
+                url.query_pairs_mut().append_pair("appid", &self.conf.key);
+                self.client.post(url.as_str()).body(json!([measurement]))
+            }
+        };
+        let body = read_body(request.await?).await?;
+        if self.conf.service == PwsService::WeatherUnderground && body.trim() != "success" {
+            return Err(Error::new_http(format!(
+                "Weather Underground rejected the upload: {}",
+                body.trim()
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Sink for PwsUploader {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn start(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn publish(&mut self, reading: &Reading) -> Result<()> {
+        match self.conf.node {
+            Some(node) if node != reading.node => return Ok(()),
+            _ => (),
+        }
+        let rain_hour = self.rain.update(reading.rain.as_ref());
+        if reading.quality != Quality::Good {
+            return Ok(());
+        }
+        if let Some(next_upload) = self.next_upload {
+            if Instant::now() < next_upload {
+                return Ok(());
+            }
+        }
+
+        let result = self.upload(reading, rain_hour).await;
+        let delay = match &result {
+            Ok(_) => {
+                if self.failures > 0 {
+                    info!(
+                        "PWS sink {} uploaded after {} failures",
+                        self.name, self.failures
+                    );
+                }
+                self.failures = 0;
+                self.conf.interval
+            }
+            Err(_) => {
+                self.failures += 1;
+                self.conf.retry_delay
+            }
+        };
+        self.next_upload = Some(Instant::now() + Duration::from_secs(delay));
+        result
+    }
+
+    async fn shutdown(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+fn value(value: f64) -> String {
+    format!("{:.2}", value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::stand_in::{self, Request};
+    use serde_json::Value;
+    use std::collections::HashMap;
+
+    fn uploader(service: &str, url: &str, extra: &str) -> PwsUploader {
+        let conf: Pws = serde_yaml::from_str(&format!(
+            "service: {}\nstation_id: STATION1\nkey: KEY1\nurl: '{}'\n{}",
+            service, url, extra
+        ))
+        .unwrap();
+        PwsUploader::new(service.to_string(), &conf).unwrap()
+    }
+
+    fn query(request: &Request) -> HashMap<String, String> {
+        Url::parse(&format!("http://localhost{}", request.target()))
+            .unwrap()
+            .query_pairs()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[async_std::test]
+    async fn weather_underground_uploads_imperial_query() {
+        let (url, server) = stand_in::serve(vec![(200, "success\n")]).await;
+        let mut pws = uploader("WeatherUnderground", &format!("{}/update.php", url), "");
+        pws.publish(&Reading::sample()).await.unwrap();
+        let requests = server.await;
+        assert!(requests[0].head.starts_with("GET /update.php?"));
+        let query = query(&requests[0]);
+        assert_eq!(query["ID"], "STATION1");
+        assert_eq!(query["PASSWORD"], "KEY1");
+        assert_eq!(query["dateutc"], "2024-05-01 12:00:00");
+        assert_eq!(query["tempf"], "70.70");
+        assert_eq!(query["humidity"], "48.00");
+        assert_eq!(query["baromin"], "29.92");
+        assert_eq!(query["windspeedmph"], "6.71");
+        assert_eq!(query["windgustmph"], "16.78");
+        assert_eq!(query["rainin"], "0.02");
+        assert_eq!(query["dailyrainin"], "0.20");
+        assert_eq!(query["action"], "updateraw");
+        assert!(!query.contains_key("winddir"));
+    }
+
+    #[async_std::test]
+    async fn weather_underground_rejection_is_error() {
+        let (url, server) = stand_in::serve(vec![(200, "INVALIDPASSWORDID|Password")]).await;
+        let mut pws = uploader("WeatherUnderground", &url, "");
+        let err = pws.publish(&Reading::sample()).await.unwrap_err();
+        assert!(err.to_string().contains("INVALIDPASSWORDID"));
+        server.await;
+    }
+
+    #[async_std::test]
+    async fn windy_puts_key_in_path_and_pressure_in_pa() {
+        let (url, server) = stand_in::serve(vec![(200, "")]).await;
+        let mut pws = uploader("Windy", &format!("{}/pws/update/", url), "");
+        let mut reading = Reading::sample();
+        reading.wind_direction = Some(247.5);
+        pws.publish(&reading).await.unwrap();
+        let requests = server.await;
+        assert!(requests[0].target().starts_with("/pws/update/KEY1?"));
+        let query = query(&requests[0]);
+        assert_eq!(query["station"], "STATION1");
+        assert_eq!(query["ts"], "1714564800");
+        assert_eq!(query["temp"], "21.50");
+        assert_eq!(query["pressure"], "101325.00");
+        assert_eq!(query["wind"], "3.00");
+        assert_eq!(query["gust"], "7.50");
+        assert_eq!(query["precip"], "0.40");
+        assert_eq!(query["winddir"], "247.50");
+    }
+
+    #[async_std::test]
+    async fn openweathermap_posts_json_with_appid() {
+        let (url, server) = stand_in::serve(vec![(204, "")]).await;
+        let mut pws = uploader("OpenWeatherMap", &url, "");
+        pws.publish(&Reading::sample()).await.unwrap();
+        let requests = server.await;
+        assert!(requests[0].head.starts_with("POST "));
+        assert_eq!(query(&requests[0])["appid"], "KEY1");
+        let body: Value = serde_json::from_str(&requests[0].body).unwrap();
+        let measurement = &body[0];
+        assert_eq!(measurement["station_id"], "STATION1");
+        assert_eq!(measurement["dt"], 1_714_564_800);
+        assert_eq!(measurement["temperature"], 21.5);
+        assert_eq!(measurement["pressure"], 1013.25);
+        assert_eq!(measurement["wind_gust"], 7.5);
+        assert_eq!(measurement["rain_1h"], 0.4);
+    }
+
+    #[async_std::test]
+    async fn failed_upload_waits_for_retry_delay() {
+        let (url, server) = stand_in::serve(vec![(500, "busy")]).await;
+        let mut pws = uploader("Windy", &url, "interval: 0\nretry_delay: 3600");
+        assert!(pws.publish(&Reading::sample()).await.is_err());
+        // Skipped before the retry delay, the stand-in answers no further requests.
+        pws.publish(&Reading::sample()).await.unwrap();
+        assert_eq!(pws.failures, 1);
+        server.await;
+    }
+
+    #[async_std::test]
+    async fn retry_and_success_follow_their_delays() {
+        let (url, server) = stand_in::serve(vec![(500, "busy"), (200, "")]).await;
+        let mut pws = uploader("Windy", &url, "interval: 3600\nretry_delay: 0");
+        assert!(pws.publish(&Reading::sample()).await.is_err());
+        pws.publish(&Reading::sample()).await.unwrap();
+        assert_eq!(pws.failures, 0);
+        // Skipped until the interval elapses.
+        pws.publish(&Reading::sample()).await.unwrap();
+        assert_eq!(server.await.len(), 2);
+    }
+
+    #[async_std::test]
+    async fn only_good_readings_of_the_node_are_uploaded() {
+        let mut pws = uploader("Windy", "http://127.0.0.1:9", "node: 11");
+        pws.publish(&Reading::sample()).await.unwrap();
+        let mut pws = uploader("Windy", "http://127.0.0.1:9", "");
+        let mut reading = Reading::sample();
+        reading.quality = Quality::Suspect;
+        pws.publish(&reading).await.unwrap();
+        assert!(pws.next_upload.is_none());
+    }
+}
@@ -0,0 +1,298 @@
+use crate::config::HistoryTier;
+use crate::error::{Error, Result};
+use crate::history::{Aggregate, HistoryStore};
+use crate::reading::Quality;
+use crate::util::utc_from_millis;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Function {
+    Min,
+    Max,
+    Mean,
+    Sum,
+    Last,
+}
+
+impl Function {
+    /// Function representing a bucket of the measurement, the rainfall of the readings is
+    /// summed and the daily running total is taken at the end of the bucket.
+    pub fn accumulation(measurement: &str) -> Self {
+        match measurement {
+            "rain" => Function::Sum,
+            "rain_daily" => Function::Last,
+            _ => Function::Mean,
+        }
+    }
+
+    pub fn apply(self, aggregate: &Aggregate) -> f64 {
+        match self {
+            Function::Min => aggregate.min,
+            Function::Max => aggregate.max,
+            Function::Mean => aggregate.mean(),
+            Function::Sum => aggregate.sum,
+            Function::Last => aggregate.last,
+        }
+    }
+}
+
+impl FromStr for Function {
+    type Err = Error;
+
+    fn from_str(name: &str) -> Result<Self> {
+        match name {
+            "min" => Ok(Function::Min),
+            "max" => Ok(Function::Max),
+            "mean" | "avg" => Ok(Function::Mean),
+            "sum" => Ok(Function::Sum),
+            "last" => Ok(Function::Last),
+            _ => Err(Error::new_query(format!("Unknown function '{}'", name))),
+        }
+    }
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Function::Min => "min",
+            Function::Max => "max",
+            Function::Mean => "mean",
+            Function::Sum => "sum",
+            Function::Last => "last",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Aggregation of the stored history over `[from, to)`.
+///
+/// Buckets are aligned to `from`, without `bucket` the whole range is a single bucket.
+/// Unless `tier` is set the coarsest tier that retains `from` with a resolution not
+/// coarser than the bucket is used, its buckets overlapping the range are included.
+#[derive(Debug, Clone)]
+pub struct Query {
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+    pub bucket: Option<Duration>,
+    pub functions: Vec<Function>,
+    pub measurements: Vec<String>,
+    pub node: Option<u8>,
+    pub tier: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Row {
+    pub time: DateTime<Utc>,
+    pub node: u8,
+    pub values: Vec<(String, f64)>,
+}
+
+impl Row {
+    pub fn to_json(&self) -> Value {
+        let mut map = Map::new();
+        map.insert("time".to_string(), Value::from(self.time.to_rfc3339()));
+        map.insert("node".to_string(), Value::from(self.node));
+        for (name, value) in &self.values {
+            map.insert(name.clone(), Value::from(*value));
+        }
+        Value::Object(map)
+    }
+}
+
+impl Query {
+    pub fn new(from: DateTime<Utc>, to: DateTime<Utc>) -> Self {
+        Query {
+            from,
+            to,
+            bucket: None,
+            functions: vec![Function::Mean],
+            measurements: Vec::new(),
+            node: None,
+            tier: None,
+        }
+    }
+
+    pub fn run(&self, store: &HistoryStore) -> Result<Vec<Row>> {
+        if self.from >= self.to {
+            return Err(Error::new_query(
+                "Query start has to be before its end".to_string(),
+            ));
+        }
+        let from = self.from.timestamp_millis();
+        let step = match self.bucket {
+            Some(bucket) if bucket.num_milliseconds() > 0 => bucket.num_milliseconds(),
+            Some(_) => return Err(Error::new_query("Bucket has to be positive".to_string())),
+            None => self.to.timestamp_millis() - from,
+        };
+        let tier = self.select_tier(store, step)?;
+        debug!("Query {:?} uses history tier {}", self, tier.name);
+        let resolution = tier.resolution as i64 * 1000;
+        let read_from = if tier.is_raw() {
+            self.from
+        } else {
+            utc_from_millis(from - from.rem_euclid(resolution))
+        };
+
+        let mut buckets: BTreeMap<(i64, u8), Vec<(String, Aggregate)>> = BTreeMap::new();
+        for record in store.read(&tier.name, read_from, self.to)? {
+            let other_node = match self.node {
+                Some(node) => node != record.node,
+                None => false,
+            };
+            if record.quality == Quality::Bad || other_node {
+                continue;
+            }
+            // A tier bucket starting before `from` belongs to the first bucket.
+            let start = from + (record.timestamp - from).max(0) / step * step;
+            let bucket = buckets.entry((start, record.node)).or_default();
+            for (name, value) in record.aggregates() {
+                if !self.measurements.is_empty() && !self.measurements.iter().any(|m| m == name) {
+                    continue;
+                }
+                match bucket.iter_mut().find(|(key, _)| key == name) {
+                    Some((_, aggregate)) => aggregate.merge(&value),
+                    None => bucket.push((name.to_string(), value)),
+                }
+            }
+        }
+
+        let rows = buckets
+            .into_iter()
+            .map(|((start, node), aggregates)| Row {
+                time: utc_from_millis(start),
+                node,
+                values: self.values(&aggregates),
+            })
+            .collect();
+        Ok(rows)
+    }
+
+    fn values(&self, aggregates: &[(String, Aggregate)]) -> Vec<(String, f64)> {
+        let mut values = Vec::with_capacity(aggregates.len() * self.functions.len());
+        for (name, aggregate) in aggregates {
+            for function in &self.functions {
+                let name = if self.functions.len() == 1 {
+                    name.clone()
+                } else {
+                    format!("{}_{}", name, function)
+                };
+                values.push((name, function.apply(aggregate)));
+            }
+        }
+        values
+    }
+
+    fn select_tier<'a>(&self, store: &'a HistoryStore, step: i64) -> Result<&'a HistoryTier> {
+        if let Some(name) = &self.tier {
+            return store
+                .tiers()
+                .find(|tier| &tier.name == name)
+                .ok_or_else(|| Error::new_query(format!("Unknown history tier '{}'", name)));
+        }
+        let now = Utc::now();
+        store
+            .tiers()
+            .filter(|tier| {
+                now - Duration::days(tier.retention as i64) <= self.from
+                    && tier.resolution as i64 * 1000 <= step
+            })
+            .max_by_key(|tier| tier.resolution)
+            .ok_or_else(|| {
+                Error::new_query(
+                    "No history tier retains the query range at the bucket resolution".to_string(),
+                )
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::History;
+    use crate::reading::Reading;
+    use crate::util::test_dir;
+
+    fn store(name: &str) -> HistoryStore {
+        let path = test_dir(name).display().to_string();
+        let conf: History = serde_yaml::from_str(&format!(
+            "path: {}\ntiers:\n\
+             - {{name: raw, resolution: 0, retention: 7}}\n\
+             - {{name: 5min, resolution: 300, retention: 30}}\n\
+             - {{name: 1h, resolution: 3600, retention: 365}}",
+            path
+        ))
+        .unwrap();
+        HistoryStore::open(&conf).unwrap()
+    }
+
+    fn tier(store: &HistoryStore, days: i64, bucket: Option<i64>) -> Result<String> {
+        // Deliberately not aligned to any tier resolution.
+        let from = Utc::now() - Duration::days(days) + Duration::seconds(17);
+        let mut query = Query::new(from, from + Duration::days(1));
+        query.bucket = bucket.map(Duration::minutes);
+        let step = match query.bucket {
+            Some(bucket) => bucket.num_milliseconds(),
+            None => Duration::days(1).num_milliseconds(),
+        };
+        query.select_tier(store, step).map(|tier| tier.name.clone())
+    }
+
+    #[test]
+    fn coarsest_retained_tier_within_the_bucket_is_selected() {
+        let store = store("query-select");
+        assert_eq!(tier(&store, 2, None).unwrap(), "1h");
+        assert_eq!(tier(&store, 2, Some(90)).unwrap(), "1h");
+        assert_eq!(tier(&store, 2, Some(10)).unwrap(), "5min");
+        assert_eq!(tier(&store, 2, Some(1)).unwrap(), "raw");
+        assert_eq!(tier(&store, 10, Some(5)).unwrap(), "5min");
+        assert_eq!(tier(&store, 40, Some(60)).unwrap(), "1h");
+    }
+
+    #[test]
+    fn range_without_retained_tier_is_rejected() {
+        let store = store("query-retention");
+        assert!(tier(&store, 10, Some(1)).is_err());
+        assert!(tier(&store, 40, Some(30)).is_err());
+        assert!(tier(&store, 400, None).is_err());
+    }
+
+    #[test]
+    fn unaligned_range_includes_overlapping_buckets() {
+        let mut store = store("query-unaligned");
+        let now = Utc::now().timestamp();
+        let start = utc_from_millis((now - 3600 - now.rem_euclid(3600)) * 1000);
+        for (offset, temperature) in &[(0, 20.), (60, 24.), (120, 22.), (300, 25.)] {
+            let mut reading = Reading::sample();
+            reading.timestamp = start + Duration::seconds(*offset);
+            reading.temperature = *temperature;
+            store.append(&reading).unwrap();
+        }
+
+        let from = start + Duration::seconds(90);
+        let mut query = Query::new(from, start + Duration::seconds(690));
+        query.bucket = Some(Duration::minutes(10));
+        query.functions = vec![Function::Min, Function::Max];
+        query.measurements = vec!["temperature".to_string()];
+        let rows = query.run(&store).unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].time, from);
+        let values = vec![
+            ("temperature_min".to_string(), 20.),
+            ("temperature_max".to_string(), 25.),
+        ];
+        assert_eq!(rows[0].values, values);
+
+        query.bucket = Some(Duration::minutes(1));
+        query.functions = vec![Function::Last];
+        let rows = query.run(&store).unwrap();
+        let values: Vec<_> = rows.iter().map(|row| row.values[0].1).collect();
+        assert_eq!(values, [22., 25.]);
+        assert_eq!(rows[1].time, start + Duration::seconds(270));
+    }
+}
@@ -1,8 +1,9 @@
 use crate::config::Config;
 use crate::error::{Error, Result};
+use crate::metrics::MetricsRegistry;
 use crate::util::{
     Receiver, Shared, CS_NAME, CS_PIN_NUM, GPIO_CHIP, INTERRUPT_NAME, INTERRUPT_PIN_NUM,
-    PACKET_CONFIG, PACKET_DATA, SPI_DEV,
+    PACKET_CONFIG, PACKET_DATA, PACKET_DATA_LENGTH, SPI_DEV,
 };
 use async_std::sync::{Arc, Mutex};
 use async_std::task::{block_on, spawn_blocking};
@@ -21,11 +22,11 @@ pub struct Radio {
 }
 
 impl Radio {
-    pub fn new(shared_conf: Shared<Config>) -> Result<Self> {
+    pub fn new(shared_conf: Shared<Config>, metrics: Shared<MetricsRegistry>) -> Result<Self> {
         let rfm: RfmWrapper;
         {
             let conf = block_on(shared_conf.lock());
-            rfm = RfmWrapper::new(conf.network_id(), conf.gateway_addr())?;
+            rfm = RfmWrapper::new(conf.network_id(), conf.gateway_addr(), metrics)?;
         }
         Ok(Radio {
             rfm: new_shared!(rfm),
@@ -62,6 +63,7 @@ impl Radio {
                         }
                     }
                 } else {
+                    block_on(rfm.metrics.lock()).invalid_packets += 1;
                     error!("Invalid data");
                 }
             }
@@ -74,10 +76,11 @@ struct RfmWrapper {
     rfm: Rfm69<CdevPin, Spidev, Delay>,
     gateway_addr: u8,
     interrupt: LineEventHandle,
+    metrics: Shared<MetricsRegistry>,
 }
 
 impl RfmWrapper {
-    fn new(network_id: u8, gateway_addr: u8) -> Result<Self> {
+    fn new(network_id: u8, gateway_addr: u8, metrics: Shared<MetricsRegistry>) -> Result<Self> {
         let mut chip = Chip::new(GPIO_CHIP)?;
         let spi = configure_spi()?;
         let cs = configure_cs(&mut chip)?;
@@ -94,17 +97,34 @@ impl RfmWrapper {
             rfm,
             gateway_addr,
             interrupt,
+            metrics,
         })
     }
 
     fn receive(&mut self) -> Result<Vec<u8>> {
         let mut buffer = [0; 64];
         self.wait_packet_ready()?;
+        // The RSSI register holds the strength of the packet until the next reception.
+        let rssi = self.rfm.rssi()?;
         self.rfm.recv(&mut buffer)?;
-        let packet = Packet::from_bytes(&buffer)?;
+        let packet = {
+            let mut metrics = block_on(self.metrics.lock());
+            metrics.packets_received += 1;
+            let packet = match Packet::from_bytes(&buffer) {
+                Ok(packet) => packet,
+                Err(err) => {
+                    metrics.decode_errors += 1;
+                    return Err(err);
+                }
+            };
+            metrics.update_rssi(packet.from, rssi as f64);
+            packet
+        };
+        // The lock is not held while sending, a scrape must not delay the ACK.
         if packet.ack_requested() && packet.is_to(self.gateway_addr) {
             let ack = Packet::ack_from(&packet);
             self.rfm.send(&mut ack.as_bytes())?;
+            block_on(self.metrics.lock()).acks_sent += 1;
         }
         Ok(packet.message())
     }
@@ -124,6 +144,7 @@ impl RfmWrapper {
             info!("Sending new config");
             debug!("Config: {:?}", buffer);
             self.send(buffer, node.addr())?;
+            block_on(self.metrics.lock()).configs_sent += 1;
         }
         conf.node_mut().update_config_dirty(false);
         Ok(())
@@ -245,5 +266,5 @@ fn is_config_request(data: &[u8]) -> bool {
 }
 
 fn is_data_update(data: &[u8]) -> bool {
-    data.len() == 18 && (data[0] == PACKET_DATA)
+    data.len() == PACKET_DATA_LENGTH && (data[0] == PACKET_DATA)
 }
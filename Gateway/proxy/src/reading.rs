@@ -0,0 +1,280 @@
+use crate::battery::{raw_to_voltage, BatteryMonitor, BatteryState};
+use crate::climate::Statistics;
+use crate::config::Config;
+use crate::counter::{CounterMonitor, Rain, Wind};
+use crate::data::Data;
+use crate::indices::Indices;
+use crate::util::{INDICES, INDICES_PERIODS};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Ordered from best to worst, so the worst of several qualities is their maximum.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Quality {
+    Good,
+    Suspect,
+    Bad,
+}
+
+/// Decoded and calibrated node measurement shared by all outputs.
+#[derive(Debug)]
+pub struct Reading {
+    pub timestamp: DateTime<Utc>,
+    pub node: u8,
+    pub quality: Quality,
+    pub data: Data,
+    pub battery: BatteryState,
+    pub analog: [Option<f64>; 3],
+    pub temperature: f64,
+    pub pressure: f64,
+    pub humidity: f64,
+    pub rain: Option<Rain>,
+    pub wind: Option<Wind>,
+    /// Direction the wind blows from in degrees, from an analog pin with a wind vane.
+    pub wind_direction: Option<f64>,
+    /// Signal strength of the packet in dBm, set by the proxy from the radio metrics.
+    pub rssi: Option<f64>,
+    /// Daily, monthly and all-time extremes, set when climate summaries are configured.
+    pub statistics: Option<Statistics>,
+    /// Agricultural indices, set when they are configured with the climate summaries.
+    pub indices: Option<Indices>,
+}
+
+impl Reading {
+    pub fn new(
+        data: Data,
+        conf: &Config,
+        battery: &mut BatteryMonitor,
+        counters: &mut CounterMonitor,
+    ) -> Self {
+        let node = conf.node();
+        let calibration = conf.calibration();
+        let mut analog = [None; 3];
+        let mut wind_direction = None;
+        for pin in node.analog().filter(|pin| pin.enabled) {
+            let num = pin.number as usize;
+            if let (Some(value), Some(raw)) = (analog.get_mut(num), data.adc_value.get(num)) {
+                *value = pin.evaluate(*raw);
+                if pin.is_wind_vane() {
+                    wind_direction = *value;
+                }
+            }
+        }
+        let voltage = calibration.battery.apply(raw_to_voltage(data.bat_value));
+        let battery = battery.update(node.battery(), voltage);
+        let (rain, wind) = counters.update(node, &data.counter_value);
+        let quality = quality(&data);
+        Reading {
+            timestamp: Utc::now(),
+            node: node.addr(),
+            quality,
+            temperature: calibration
+                .temperature
+                .apply(data.temperature as f64 / 100.),
+            pressure: calibration.pressure.apply(data.pressure as f64 / 100.),
+            humidity: calibration.humidity.apply(data.humidity as f64 / 100.),
+            data,
+            battery,
+            analog,
+            rain,
+            wind,
+            wind_direction,
+            rssi: None,
+            statistics: None,
+            indices: None,
+        }
+    }
+
+    /// Dew point in °C from the Magnus formula.
+    pub fn dew_point(&self) -> f64 {
+        let (b, c) = (17.62, 243.12);
+        let humidity = self.humidity.clamp(1., 100.);
+        let gamma = (humidity / 100.).ln() + b * self.temperature / (c + self.temperature);
+        c * gamma / (b - gamma)
+    }
+
+    /// Wind chill in °C from the North American formula, the air temperature above 10 °C,
+    /// at wind speeds up to 4.8 km/h or without an anemometer.
+    pub fn wind_chill(&self) -> f64 {
+        let speed = match &self.wind {
+            Some(wind) => wind.speed * 3.6,
+            None => return self.temperature,
+        };
+        if self.temperature > 10. || speed <= 4.8 {
+            return self.temperature;
+        }
+        let factor = speed.powf(0.16);
+        13.12 + 0.6215 * self.temperature - 11.37 * factor + 0.3965 * self.temperature * factor
+    }
+
+    /// Heat index in °C from the NWS algorithm, the Rothfusz regression applies only
+    /// above 80 °F.
+    pub fn heat_index(&self) -> f64 {
+        let t = self.temperature * 9. / 5. + 32.;
+        let rh = self.humidity.clamp(0., 100.);
+        let simple = 0.5 * (t + 61. + (t - 68.) * 1.2 + rh * 0.094);
+        let index = if (simple + t) / 2. < 80. {
+            simple
+        } else {
+            let mut index = -42.379 + 2.049_015_2 * t + 10.143_331 * rh
+                - 0.224_755_4 * t * rh
+                - 0.006_837_83 * t * t
+                - 0.054_817_17 * rh * rh
+                + 0.001_228_74 * t * t * rh
+                + 0.000_852_82 * t * rh * rh
+                - 0.000_001_99 * t * t * rh * rh;
+            if rh < 13. && (80. ..=112.).contains(&t) {
+                index -= (13. - rh) / 4. * ((17. - (t - 95.).abs()) / 17.).sqrt();
+            } else if rh > 85. && (80. ..=87.).contains(&t) {
+                index += (rh - 85.) / 10. * (87. - t) / 5.;
+            }
+            index
+        };
+        (index - 32.) * 5. / 9.
+    }
+
+    /// Humidex in °C of the Meteorological Service of Canada.
+    pub fn humidex(&self) -> f64 {
+        let vapour = 6.11 * (5417.753 * (1. / 273.16 - 1. / (273.15 + self.dew_point()))).exp();
+        self.temperature + 0.5555 * (vapour - 10.)
+    }
+
+    /// Apparent temperature in °C of the Australian Bureau of Meteorology, without solar
+    /// radiation.
+    pub fn apparent_temperature(&self) -> f64 {
+        let vapour = self.humidity.clamp(0., 100.) / 100.
+            * 6.105
+            * (17.27 * self.temperature / (237.7 + self.temperature)).exp();
+        let speed = self.wind.as_ref().map_or(0., |wind| wind.speed);
+        self.temperature + 0.33 * vapour - 0.70 * speed - 4.00
+    }
+
+    /// Flat list of named numeric values, used by the history store and generic outputs.
+    pub fn measurements(&self) -> Vec<(String, f64)> {
+        let mut result = vec![
+            ("temperature".to_string(), self.temperature),
+            ("pressure".to_string(), self.pressure),
+            ("humidity".to_string(), self.humidity),
+            ("battery_voltage".to_string(), self.battery.voltage),
+            ("battery_level".to_string(), self.battery.percent),
+        ];
+        for (num, value) in self.analog.iter().enumerate() {
+            if let Some(value) = value {
+                result.push((format!("analog_{}", num), *value));
+            }
+        }
+        if let Some(rain) = &self.rain {
+            result.push(("rain".to_string(), rain.amount));
+            result.push(("rain_rate".to_string(), rain.rate));
+            result.push(("rain_daily".to_string(), rain.daily));
+        }
+        if let Some(wind) = &self.wind {
+            result.push(("wind_speed".to_string(), wind.speed));
+            result.push(("wind_gust".to_string(), wind.gust));
+        }
+        if let Some(indices) = &self.indices {
+            for ((period, _), indices) in INDICES_PERIODS.iter().zip(&indices.periods()) {
+                for ((name, _, _), value) in INDICES.iter().zip(&indices.values()) {
+                    if let Some(value) = value {
+                        result.push((index_measurement(period, name), *value));
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    /// Names of every measurement a reading can carry, in the order of `measurements`.
+    pub fn measurement_names() -> Vec<String> {
+        let mut names: Vec<String> = [
+            "temperature",
+            "pressure",
+            "humidity",
+            "battery_voltage",
+            "battery_level",
+        ]
+        .iter()
+        .map(|name| name.to_string())
+        .collect();
+        names.extend((0..3).map(|num| format!("analog_{}", num)));
+        for name in &["rain", "rain_rate", "rain_daily", "wind_speed", "wind_gust"] {
+            names.push(name.to_string());
+        }
+        for (period, _) in &INDICES_PERIODS {
+            for (name, _, _) in &INDICES {
+                names.push(index_measurement(period, name));
+            }
+        }
+        names
+    }
+}
+
+/// Name of an agricultural index, the values of the season have a `_season` suffix.
+fn index_measurement(period: &str, name: &str) -> String {
+    match period {
+        "today" => name.to_string(),
+        _ => format!("{}_{}", name, period),
+    }
+}
+
+/// Checks raw BME280 values against the sensor operating range.
+fn quality(data: &Data) -> Quality {
+    if data.temperature == 0 && data.pressure == 0 && data.humidity == 0 {
+        return Quality::Bad;
+    }
+    let temperature = (-4000..=8500).contains(&data.temperature);
+    let pressure = (30_000..=110_000).contains(&data.pressure);
+    let humidity = data.humidity <= 10_000;
+    if temperature && pressure && humidity {
+        Quality::Good
+    } else {
+        Quality::Suspect
+    }
+}
+
+#[cfg(test)]
+impl Reading {
+    /// Good reading of node 10 at 2024-05-01 12:00 UTC, 21.5 °C, 1013.25 hPa and 48 %
+    /// with a rain gauge and an anemometer.
+    pub fn sample() -> Self {
+        use crate::battery::BatteryLevel;
+        Reading {
+            timestamp: "2024-05-01T12:00:00Z".parse().unwrap(),
+            node: 10,
+            quality: Quality::Good,
+            data: Data {
+                packet_type: 0,
+                gpio_value: 0,
+                adc_value: [0; 3],
+                bat_value: 0,
+                temperature: 2150,
+                pressure: 101_325,
+                humidity: 4800,
+                counter_value: [0; 8],
+            },
+            battery: BatteryState {
+                voltage: 3.9,
+                percent: 80.,
+                level: BatteryLevel::Normal,
+                charge: None,
+            },
+            analog: [None; 3],
+            temperature: 21.5,
+            pressure: 1013.25,
+            humidity: 48.,
+            rain: Some(Rain {
+                amount: 0.4,
+                rate: 2.4,
+                daily: 5.,
+            }),
+            wind: Some(Wind {
+                speed: 3.,
+                gust: 7.5,
+            }),
+            wind_direction: None,
+            rssi: Some(-61.5),
+            statistics: None,
+            indices: None,
+        }
+    }
+}
@@ -0,0 +1,503 @@
+use crate::climate::{Extremes, Statistics};
+use crate::counter::RainWindow;
+use crate::error::Result;
+use crate::reading::{Quality, Reading};
+use crate::sink::Sink;
+use crate::units::{PressureUnit, RainUnit, TemperatureUnit, Units, WindUnit};
+use crate::util::{
+    compass_point, local_time, utc_from_millis, write_atomic, BEAUFORT_SCALE, CLIENTRAW_FIELDS,
+    CLIENTRAW_VERSION, CUMULUS_BUILD, CUMULUS_VERSION, RAIN_HOUR_WINDOW, REALTIME_STATION,
+    REALTIME_TREND_WINDOW,
+};
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, NaiveDate, NaiveTime, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::path::Path;
+
+/// Weather site file rewritten after every good reading of `node`. `Cumulus` writes the
+/// fields of `realtime.txt` in the configured units, `WeatherDisplay` those of
+/// `clientraw.txt`, which are always metric with the wind in knots.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RealtimeFile {
+    pub format: RealtimeFormat,
+    pub path: String,
+    #[serde(default)]
+    pub node: Option<u8>,
+    #[serde(default = "default_realtime_station")]
+    pub station: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum RealtimeFormat {
+    Cumulus,
+    WeatherDisplay,
+}
+
+fn default_realtime_station() -> String {
+    REALTIME_STATION.to_string()
+}
+
+/// Rewrites the realtime file read by weather site templates after every good reading.
+///
+/// The temperature and pressure extremes of the day and the rainfall of the month come
+/// from the climate statistics, the yearly rainfall is their all-time total. The wind
+/// extremes and the trends are kept in memory from the start of the proxy, as are all
+/// the extremes without the climate store, whose rainfall is then written as zero. Days
+/// start at midnight in the timezone of the climate store. Values the station does not
+/// measure, such as the solar radiation or the indoor conditions, are written as zero,
+/// the wind direction also without a wind vane.
+pub struct RealtimeFileWriter {
+    name: String,
+    conf: RealtimeFile,
+    units: Units,
+    timezone: Option<Tz>,
+    today: Option<Today>,
+    yesterday_rain: f64,
+    trend: VecDeque<(DateTime<Utc>, f64, f64)>,
+    rain_hour: RainWindow,
+    last: Option<DateTime<Utc>>,
+}
+
+#[derive(Clone, Copy)]
+struct Extreme {
+    value: f64,
+    time: NaiveTime,
+}
+
+/// Extremes since local midnight, the wind run is in km.
+struct Today {
+    date: NaiveDate,
+    high_temperature: Extreme,
+    low_temperature: Extreme,
+    high_pressure: Extreme,
+    low_pressure: Extreme,
+    high_wind: Extreme,
+    high_gust: Extreme,
+    high_rain_rate: Extreme,
+    wind_run: f64,
+    rain: f64,
+}
+
+impl RealtimeFileWriter {
+    pub fn new(name: String, conf: &RealtimeFile, units: Units, timezone: Option<Tz>) -> Self {
+        RealtimeFileWriter {
+            name,
+            conf: conf.clone(),
+            units,
+            timezone,
+            today: None,
+            yesterday_rain: 0.,
+            trend: VecDeque::new(),
+            rain_hour: RainWindow::new(RAIN_HOUR_WINDOW),
+            last: None,
+        }
+    }
+
+    /// Changes of the pressure and the temperature per hour over the trend window, zero
+    /// until the window spans an hour.
+    fn trends(&self, reading: &Reading) -> (f64, f64) {
+        match self.trend.front() {
+            Some((time, pressure, temperature)) => {
+                let hours = (reading.timestamp - *time).num_seconds() as f64 / 3600.;
+                if hours < 1. {
+                    return (0., 0.);
+                }
+                (
+                    (reading.pressure - pressure) / hours,
+                    (reading.temperature - temperature) / hours,
+                )
+            }
+            None => (0., 0.),
+        }
+    }
+
+    /// Fields of Cumulus `realtime.txt`.
+    fn cumulus(&self, reading: &Reading, today: &Today, rain_hour: f64) -> String {
+        let units = self.units;
+        let local = local_time(reading.timestamp, self.timezone);
+        let temperature = |value: f64| format!("{:.1}", units.convert("temperature", value));
+        let pressure = |value: f64| match units.pressure {
+            PressureUnit::InchMercury => format!("{:.3}", units.convert("pressure", value)),
+            _ => format!("{:.1}", units.convert("pressure", value)),
+        };
+        let rain = |value: f64| match units.rain {
+            RainUnit::Inch => format!("{:.2}", units.convert("rain", value)),
+            RainUnit::Millimeter => format!("{:.1}", value),
+        };
+        let wind = |value: f64| format!("{:.1}", units.convert("wind_speed", value));
+        let time = |extreme: &Extreme| extreme.time.format("%H:%M").to_string();
+        let (wind_unit, wind_run) = match units.wind {
+            WindUnit::MeterPerSecond => ("m/s", 1.),
+            WindUnit::KilometerPerHour => ("km/h", 1.),
+            WindUnit::MilePerHour => ("mph", 0.621_371),
+            WindUnit::Knot => ("kts", 0.539_957),
+        };
+        let dew_point = reading.dew_point();
+        // The cloud base rises about 125 m per °C of the dew point spread.
+        let (temperature_unit, cloud_base, cloud_base_unit) = match units.temperature {
+            TemperatureUnit::Celsius => ("C", (reading.temperature - dew_point) * 125., "m"),
+            TemperatureUnit::Fahrenheit => ("F", (reading.temperature - dew_point) * 410., "ft"),
+        };
+        let pressure_unit = match units.pressure {
+            PressureUnit::Hectopascal => "hPa",
+            PressureUnit::InchMercury => "in",
+            PressureUnit::MillimeterMercury => "mmHg",
+        };
+        let rain_unit = match units.rain {
+            RainUnit::Millimeter => "mm",
+            RainUnit::Inch => "in",
+        };
+        let (speed, gust) = wind_speeds(reading);
+        let (pressure_trend, temperature_trend) = self.trends(reading);
+        let (bearing, compass) = match reading.wind_direction {
+            Some(direction) => (format!("{:.0}", direction), compass_point(direction)),
+            None => ("0".to_string(), "---"),
+        };
+        let fields = vec![
+            local.format("%d/%m/%y").to_string(),
+            local.format("%H:%M:%S").to_string(),
+            temperature(reading.temperature),
+            format!("{:.0}", reading.humidity),
+            temperature(dew_point),
+            wind(speed),
+            wind(speed),
+            bearing.clone(),
+            rain(rain_rate(reading)),
+            rain(today.rain),
+            pressure(reading.pressure),
+            compass.to_string(),
+            beaufort(speed).to_string(),
+            wind_unit.to_string(),
+            temperature_unit.to_string(),
+            pressure_unit.to_string(),
+            rain_unit.to_string(),
+            format!("{:.1}", today.wind_run * wind_run),
+            pressure(pressure_trend),
+            rain(statistics_rain(reading, |statistics| statistics.month.rain)),
+            rain(statistics_rain(reading, |statistics| {
+                statistics.all_time.rain
+            })),
+            rain(self.yesterday_rain),
+            "0.0".to_string(),
+            "0".to_string(),
+            temperature(reading.wind_chill()),
+            format!(
+                "{:.1}",
+                units.convert("temperature", temperature_trend) - units.convert("temperature", 0.)
+            ),
+            temperature(today.high_temperature.value),
+            time(&today.high_temperature),
+            temperature(today.low_temperature.value),
+            time(&today.low_temperature),
+            wind(today.high_wind.value),
+            time(&today.high_wind),
+            wind(today.high_gust.value),
+            time(&today.high_gust),
+            pressure(today.high_pressure.value),
+            time(&today.high_pressure),
+            pressure(today.low_pressure.value),
+            time(&today.low_pressure),
+            CUMULUS_VERSION.to_string(),
+            CUMULUS_BUILD.to_string(),
+            wind(gust),
+            temperature(reading.heat_index()),
+            temperature(reading.humidex()),
+            "0".to_string(),
+            "0.0".to_string(),
+            "0".to_string(),
+            bearing,
+            rain(rain_hour),
+            "0".to_string(),
+            "0".to_string(),
+            "0".to_string(),
+            compass.to_string(),
+            format!("{:.0}", cloud_base),
+            cloud_base_unit.to_string(),
+            temperature(reading.apparent_temperature()),
+            "0.0".to_string(),
+            "0".to_string(),
+            "0".to_string(),
+        ];
+        fields.join(" ")
+    }
+
+    /// Fields of Weather Display `clientraw.txt`, the fields not listed stay zero.
+    fn clientraw(&self, reading: &Reading, today: &Today) -> String {
+        let units = Units {
+            wind: WindUnit::Knot,
+            ..Units::default()
+        };
+        let local = local_time(reading.timestamp, self.timezone);
+        let number = |value: f64| format!("{:.1}", value);
+        let knots = |value: f64| number(units.convert("wind_speed", value));
+        let dew_point = reading.dew_point();
+        let (speed, gust) = wind_speeds(reading);
+        let (pressure_trend, _) = self.trends(reading);
+        let mut fields = vec!["0".to_string(); CLIENTRAW_FIELDS];
+        let mut set = |index: usize, value: String| fields[index] = value;
+        set(0, "12345".to_string());
+        set(1, knots(speed));
+        set(2, knots(gust));
+        if let Some(direction) = reading.wind_direction {
+            set(3, format!("{:.0}", direction));
+            set(117, format!("{:.0}", direction));
+        }
+        set(4, number(reading.temperature));
+        set(5, format!("{:.0}", reading.humidity));
+        set(6, number(reading.pressure));
+        set(7, number(today.rain));
+        // The rain rates are in mm per minute.
+        set(10, format!("{:.2}", rain_rate(reading) / 60.));
+        set(11, format!("{:.2}", today.high_rain_rate.value / 60.));
+        set(19, number(self.yesterday_rain));
+        set(29, local.format("%H").to_string());
+        set(30, local.format("%M").to_string());
+        set(31, local.format("%S").to_string());
+        set(
+            32,
+            format!(
+                "{}-{}",
+                self.conf.station.replace(' ', "_"),
+                local.format("%H:%M:%S")
+            ),
+        );
+        set(35, local.format("%d").to_string());
+        set(36, local.format("%m").to_string());
+        set(44, number(reading.wind_chill()));
+        set(45, number(reading.humidex()));
+        set(46, number(today.high_temperature.value));
+        set(47, number(today.low_temperature.value));
+        set(49, "---".to_string());
+        set(50, number(pressure_trend));
+        set(71, knots(today.high_gust.value));
+        set(72, number(dew_point));
+        set(
+            73,
+            format!("{:.0}", (reading.temperature - dew_point) * 410.),
+        );
+        set(74, local.format("%d/%m/%Y").to_string());
+        set(112, number(reading.heat_index()));
+        set(113, knots(today.high_wind.value));
+        set(CLIENTRAW_FIELDS - 1, CLIENTRAW_VERSION.to_string());
+        fields.join(" ")
+    }
+}
+
+#[async_trait]
+impl Sink for RealtimeFileWriter {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn start(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn publish(&mut self, reading: &Reading) -> Result<()> {
+        match self.conf.node {
+            Some(node) if node != reading.node => return Ok(()),
+            _ => (),
+        }
+        let rain_hour = self.rain_hour.update(reading.rain.as_ref()).unwrap_or(0.);
+        if reading.quality != Quality::Good {
+            return Ok(());
+        }
+        let elapsed = self.last.replace(reading.timestamp).map_or(0., |last| {
+            (reading.timestamp - last).num_milliseconds() as f64 / 1000.
+        });
+        let local = local_time(reading.timestamp, self.timezone);
+        let (date, time) = (local.date(), local.time());
+        match &mut self.today {
+            Some(today) if today.date == date => today.update(time, reading, elapsed),
+            today => {
+                self.yesterday_rain = match today {
+                    Some(today) if date.signed_duration_since(today.date) == Duration::days(1) => {
+                        today.rain
+                    }
+                    _ => 0.,
+                };
+                *today = Some(Today::new(date, time, reading));
+            }
+        }
+        if let (Some(today), Some(statistics)) = (&mut self.today, &reading.statistics) {
+            today.apply(&statistics.today, self.timezone);
+        }
+
+        self.trend
+            .push_back((reading.timestamp, reading.pressure, reading.temperature));
+        while let Some((time, ..)) = self.trend.front() {
+            if (reading.timestamp - *time).num_seconds() <= REALTIME_TREND_WINDOW {
+                break;
+            }
+            self.trend.pop_front();
+        }
+
+        if let Some(today) = &self.today {
+            let content = match self.conf.format {
+                RealtimeFormat::Cumulus => self.cumulus(reading, today, rain_hour),
+                RealtimeFormat::WeatherDisplay => self.clientraw(reading, today),
+            };
+            write_atomic(Path::new(&self.conf.path), content.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    async fn shutdown(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl Extreme {
+    fn new(value: f64, time: NaiveTime) -> Self {
+        Extreme { value, time }
+    }
+
+    fn max(&mut self, value: f64, time: NaiveTime) {
+        if value > self.value {
+            *self = Extreme::new(value, time);
+        }
+    }
+
+    fn min(&mut self, value: f64, time: NaiveTime) {
+        if value < self.value {
+            *self = Extreme::new(value, time);
+        }
+    }
+}
+
+impl Today {
+    fn new(date: NaiveDate, time: NaiveTime, reading: &Reading) -> Self {
+        let temperature = Extreme::new(reading.temperature, time);
+        let pressure = Extreme::new(reading.pressure, time);
+        let (speed, gust) = wind_speeds(reading);
+        Today {
+            date,
+            high_temperature: temperature,
+            low_temperature: temperature,
+            high_pressure: pressure,
+            low_pressure: pressure,
+            high_wind: Extreme::new(speed, time),
+            high_gust: Extreme::new(gust, time),
+            high_rain_rate: Extreme::new(rain_rate(reading), time),
+            wind_run: 0.,
+            rain: reading.rain.as_ref().map_or(0., |rain| rain.daily),
+        }
+    }
+
+    /// Takes the temperature and pressure extremes of the climate statistics of the day,
+    /// which survive a restart of the proxy.
+    fn apply(&mut self, extremes: &Extremes, timezone: Option<Tz>) {
+        let time = |millis| local_time(utc_from_millis(millis), timezone).time();
+        let (temperature, pressure) = (&extremes.temperature, &extremes.pressure);
+        self.high_temperature = Extreme::new(temperature.max, time(temperature.max_time));
+        self.low_temperature = Extreme::new(temperature.min, time(temperature.min_time));
+        self.high_pressure = Extreme::new(pressure.max, time(pressure.max_time));
+        self.low_pressure = Extreme::new(pressure.min, time(pressure.min_time));
+    }
+
+    /// `elapsed` is the time since the previous reading in seconds.
+    fn update(&mut self, time: NaiveTime, reading: &Reading, elapsed: f64) {
+        let (speed, gust) = wind_speeds(reading);
+        self.high_temperature.max(reading.temperature, time);
+        self.low_temperature.min(reading.temperature, time);
+        self.high_pressure.max(reading.pressure, time);
+        self.low_pressure.min(reading.pressure, time);
+        self.high_wind.max(speed, time);
+        self.high_gust.max(gust, time);
+        self.high_rain_rate.max(rain_rate(reading), time);
+        self.wind_run += speed * elapsed / 1000.;
+        if let Some(rain) = &reading.rain {
+            self.rain = rain.daily;
+        }
+    }
+}
+
+/// Average speed and gust in m/s, zero without an anemometer.
+fn wind_speeds(reading: &Reading) -> (f64, f64) {
+    reading
+        .wind
+        .as_ref()
+        .map_or((0., 0.), |wind| (wind.speed, wind.gust))
+}
+
+/// Rainfall of a period of the climate statistics, zero without them.
+fn statistics_rain(reading: &Reading, period: impl Fn(&Statistics) -> Option<f64>) -> f64 {
+    reading.statistics.as_ref().and_then(period).unwrap_or(0.)
+}
+
+fn rain_rate(reading: &Reading) -> f64 {
+    reading.rain.as_ref().map_or(0., |rain| rain.rate)
+}
+
+fn beaufort(speed: f64) -> usize {
+    BEAUFORT_SCALE
+        .iter()
+        .take_while(|limit| speed >= **limit)
+        .count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::climate::Stat;
+    use crate::util::test_dir;
+
+    fn stat(min: f64, min_time: &str, max: f64, max_time: &str) -> Stat {
+        let millis = |time: &str| time.parse::<DateTime<Utc>>().unwrap().timestamp_millis();
+        let mut stat = Stat::new(min, millis(min_time));
+        stat.add(max, millis(max_time));
+        stat
+    }
+
+    async fn cumulus(name: &str, reading: &Reading) -> Vec<String> {
+        let path = test_dir(name).join("realtime.txt");
+        let conf: RealtimeFile =
+            serde_yaml::from_str(&format!("format: Cumulus\npath: {}", path.display())).unwrap();
+        let timezone = Some(chrono_tz::Europe::Prague);
+        let mut writer =
+            RealtimeFileWriter::new(name.to_string(), &conf, Units::default(), timezone);
+        writer.publish(reading).await.unwrap();
+        let content = std::fs::read_to_string(&path).unwrap();
+        content.split(' ').map(String::from).collect()
+    }
+
+    #[async_std::test]
+    async fn extremes_and_rain_come_from_the_statistics() {
+        let mut reading = Reading::sample();
+        let extremes = Extremes {
+            temperature: stat(15., "2024-05-01T04:10:00Z", 23., "2024-05-01T11:30:00Z"),
+            pressure: stat(1009., "2024-05-01T01:00:00Z", 1014., "2024-05-01T09:45:00Z"),
+            humidity: stat(40., "2024-05-01T11:30:00Z", 90., "2024-05-01T04:10:00Z"),
+            rain: Some(5.),
+        };
+        reading.statistics = Some(Statistics {
+            today: extremes,
+            month: Extremes {
+                rain: Some(42.),
+                ..extremes
+            },
+            all_time: Extremes {
+                rain: Some(420.5),
+                ..extremes
+            },
+        });
+        let fields = cumulus("realtime-statistics", &reading).await;
+        // Local time of Prague in summer.
+        assert_eq!(fields[1], "14:00:00");
+        assert_eq!(
+            (fields[19].as_str(), fields[20].as_str()),
+            ("42.0", "420.5")
+        );
+        assert_eq!(fields[26..30], ["23.0", "13:30", "15.0", "06:10"]);
+        assert_eq!(fields[34..38], ["1014.0", "11:45", "1009.0", "03:00"]);
+    }
+
+    #[async_std::test]
+    async fn extremes_start_with_the_reading_without_statistics() {
+        let fields = cumulus("realtime-memory", &Reading::sample()).await;
+        assert_eq!((fields[19].as_str(), fields[20].as_str()), ("0.0", "0.0"));
+        assert_eq!(fields[26..30], ["21.5", "14:00", "21.5", "14:00"]);
+    }
+}
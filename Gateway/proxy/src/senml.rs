@@ -0,0 +1,191 @@
+use crate::error::Result;
+use crate::reading::Reading;
+use crate::util::SENML_BASE_NAME;
+use ciborium::value::Value as CborValue;
+use serde_json::{Map, Value as JsonValue};
+
+/// SenML record (RFC 8428), the base fields are set only on the first record of a pack.
+#[derive(Debug, Default)]
+pub struct Record {
+    pub base_name: Option<String>,
+    pub base_time: Option<f64>,
+    pub name: String,
+    pub unit: Option<&'static str>,
+    pub value: Option<SenmlValue>,
+}
+
+#[derive(Debug)]
+pub enum SenmlValue {
+    Number(f64),
+    String(String),
+    Bool(bool),
+}
+
+impl Record {
+    pub fn new(name: &str, value: SenmlValue) -> Self {
+        Record {
+            name: name.to_string(),
+            value: Some(value),
+            ..Record::default()
+        }
+    }
+
+    fn to_json(&self) -> JsonValue {
+        let mut map = Map::new();
+        if let Some(base_name) = &self.base_name {
+            map.insert("bn".to_string(), JsonValue::from(base_name.as_str()));
+        }
+        if let Some(base_time) = self.base_time {
+            map.insert("bt".to_string(), JsonValue::from(base_time));
+        }
+        map.insert("n".to_string(), JsonValue::from(self.name.as_str()));
+        if let Some(unit) = self.unit {
+            map.insert("u".to_string(), JsonValue::from(unit));
+        }
+        match &self.value {
+            Some(SenmlValue::Number(value)) => map.insert("v".to_string(), JsonValue::from(*value)),
+            Some(SenmlValue::String(value)) => {
+                map.insert("vs".to_string(), JsonValue::from(value.as_str()))
+            }
+            Some(SenmlValue::Bool(value)) => map.insert("vb".to_string(), JsonValue::from(*value)),
+            None => None,
+        };
+        JsonValue::Object(map)
+    }
+
+    /// CBOR records use the integer labels of RFC 8428 section 6.
+    fn to_cbor(&self) -> CborValue {
+        let mut map = Vec::new();
+        let mut insert = |label: i8, value: CborValue| {
+            map.push((CborValue::Integer(label.into()), value));
+        };
+        if let Some(base_name) = &self.base_name {
+            insert(-2, CborValue::Text(base_name.clone()));
+        }
+        if let Some(base_time) = self.base_time {
+            insert(-3, CborValue::Float(base_time));
+        }
+        insert(0, CborValue::Text(self.name.clone()));
+        if let Some(unit) = self.unit {
+            insert(1, CborValue::Text(unit.to_string()));
+        }
+        match &self.value {
+            Some(SenmlValue::Number(value)) => insert(2, CborValue::Float(*value)),
+            Some(SenmlValue::String(value)) => insert(3, CborValue::Text(value.clone())),
+            Some(SenmlValue::Bool(value)) => insert(4, CborValue::Bool(*value)),
+            None => (),
+        }
+        CborValue::Map(map)
+    }
+}
+
+/// Pack of all values of the reading, the base name identifies the node and the base
+/// time is the time of the reading.
+pub fn records(reading: &Reading) -> Vec<Record> {
+    let mut records = Vec::with_capacity(16);
+    for (name, value) in reading.measurements() {
+        let (unit, value) = unit(&name, value);
+        records.push(Record {
+            unit,
+            ..Record::new(&name, SenmlValue::Number(value))
+        });
+    }
+    records.push(Record::new(
+        "battery_low",
+        SenmlValue::Bool(reading.battery.is_low()),
+    ));
+    records.push(Record::new(
+        "battery_critical",
+        SenmlValue::Bool(reading.battery.is_critical()),
+    ));
+    records.push(Record::new(
+        "quality",
+        SenmlValue::String(format!("{:?}", reading.quality).to_lowercase()),
+    ));
+    if let Some(first) = records.first_mut() {
+        first.base_name = Some(format!("{}{}/", SENML_BASE_NAME, reading.node));
+        first.base_time = Some(reading.timestamp.timestamp_millis() as f64 / 1000.);
+    }
+    records
+}
+
+pub fn to_json(records: &[Record]) -> Vec<u8> {
+    let pack: Vec<JsonValue> = records.iter().map(Record::to_json).collect();
+    JsonValue::Array(pack).to_string().into_bytes()
+}
+
+pub fn to_cbor(records: &[Record]) -> Result<Vec<u8>> {
+    let pack: Vec<CborValue> = records.iter().map(Record::to_cbor).collect();
+    let mut buffer = Vec::new();
+    ciborium::ser::into_writer(&CborValue::Array(pack), &mut buffer)?;
+    Ok(buffer)
+}
+
+/// SenML unit of the measurement and the value converted to it, analog pins have free
+/// form units and are sent without one.
+fn unit(name: &str, value: f64) -> (Option<&'static str>, f64) {
+    match name {
+        "temperature" => (Some("Cel"), value),
+        "pressure" => (Some("Pa"), value * 100.),
+        "humidity" => (Some("%RH"), value),
+        "battery_voltage" => (Some("V"), value),
+        "battery_level" => (Some("%"), value),
+        "rain" | "rain_daily" => (Some("mm"), value),
+        "evapotranspiration" | "evapotranspiration_season" => (Some("mm"), value),
+        // Degree days are temperature differences accumulated over days, the closest
+        // SenML unit is the temperature.
+        "growing_degree_days"
+        | "growing_degree_days_season"
+        | "heating_degree_days"
+        | "heating_degree_days_season"
+        | "cooling_degree_days"
+        | "cooling_degree_days_season" => (Some("Cel"), value),
+        "rain_rate" => (Some("mm/h"), value),
+        "wind_speed" | "wind_gust" => (Some("m/s"), value),
+        _ => (None, value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cbor_pack_uses_integer_labels() {
+        let records = records(&Reading::sample());
+        let pack: CborValue = ciborium::de::from_reader(&to_cbor(&records).unwrap()[..]).unwrap();
+        let pack = pack.into_array().unwrap();
+        assert_eq!(pack.len(), records.len());
+        let label = |record: &CborValue, label: i8| {
+            let map = record.as_map().unwrap();
+            map.iter()
+                .find(|(key, _)| *key == CborValue::Integer(label.into()))
+                .map(|(_, value)| value.clone())
+        };
+        let first = &pack[0];
+        assert_eq!(
+            label(first, -2),
+            Some(CborValue::Text("weather-station/node-10/".into()))
+        );
+        assert_eq!(label(first, -3), Some(CborValue::Float(1_714_564_800.)));
+        assert_eq!(label(first, 0), Some(CborValue::Text("temperature".into())));
+        assert_eq!(label(first, 1), Some(CborValue::Text("Cel".into())));
+        assert_eq!(label(first, 2), Some(CborValue::Float(21.5)));
+        assert_eq!(label(&pack[1], 2), Some(CborValue::Float(101_325.)));
+        assert_eq!(label(&pack[1], -2), None);
+        let last = pack.last().unwrap();
+        assert_eq!(label(last, 3), Some(CborValue::Text("good".into())));
+    }
+
+    #[test]
+    fn indices_have_units() {
+        assert_eq!(unit("evapotranspiration", 1.5), (Some("mm"), 1.5));
+        assert_eq!(unit("evapotranspiration_season", 80.), (Some("mm"), 80.));
+        assert_eq!(unit("growing_degree_days", 12.), (Some("Cel"), 12.));
+        assert_eq!(
+            unit("heating_degree_days_season", 300.),
+            (Some("Cel"), 300.)
+        );
+        assert_eq!(unit("analog_0", 2.), (None, 2.));
+    }
+}
@@ -0,0 +1,157 @@
+use crate::aprs::AprsReporter;
+use crate::config::{Config, SinkType};
+use crate::dashboard::DashboardServer;
+use crate::domoticz::DomoticzSink;
+use crate::error::Result;
+use crate::home_assistant::HomeAssistant;
+use crate::homie::HomieDevice;
+use crate::influxdb::InfluxDb;
+use crate::metrics::MetricsRegistry;
+use crate::modbus::ModbusServer;
+use crate::outbox::OutboxQueue;
+use crate::pws::PwsUploader;
+use crate::reading::Reading;
+use crate::realtime::RealtimeFileWriter;
+use crate::util::{Receiver, Sender, Shared, VUTBR_URI_ENV};
+use crate::vutbr::VutBr;
+use crate::webhook::WebhookSender;
+use async_std::sync::Arc;
+use async_std::task::{self, JoinHandle};
+use async_trait::async_trait;
+use futures::channel::mpsc;
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use paho_mqtt::Message;
+use std::result::Result as StdResult;
+
+pub type CommandStream = BoxStream<'static, StdResult<Option<Message>, ()>>;
+
+/// Output receiving every decoded reading.
+///
+/// `start` is called once before the first reading and `shutdown` once when the proxy
+/// exits, a sink that cannot reach its destination should buffer instead of failing.
+/// Readings are published from a `SinkWorker` task, a slow destination delays only its
+/// own sink.
+#[async_trait]
+pub trait Sink: Send {
+    fn name(&self) -> &str;
+
+    async fn start(&mut self) -> Result<()>;
+
+    async fn publish(&mut self, reading: &Reading) -> Result<()>;
+
+    async fn shutdown(&mut self) -> Result<()>;
+
+    /// Messages controlling outputs and calibration, see `Config::update_calibration`
+    /// and `Node::update_output`.
+    fn commands(&mut self) -> Option<CommandStream> {
+        None
+    }
+}
+
+/// Task publishing the readings to one sink in the order they were received.
+pub struct SinkWorker {
+    name: String,
+    sender: Sender<Arc<Reading>>,
+    task: JoinHandle<Box<dyn Sink>>,
+}
+
+impl SinkWorker {
+    pub fn spawn(mut sink: Box<dyn Sink>) -> Self {
+        let name = sink.name().to_string();
+        let (sender, mut receiver): (_, Receiver<Arc<Reading>>) = mpsc::unbounded();
+        let task = task::spawn(async move {
+            while let Some(reading) = receiver.next().await {
+                if let Err(err) = sink.publish(&reading).await {
+                    eprintln!("{}: {}", sink.name(), err);
+                    error!("{}: {:?}", sink.name(), err);
+                }
+            }
+            sink
+        });
+        SinkWorker { name, sender, task }
+    }
+
+    pub fn publish(&self, reading: &Arc<Reading>) {
+        if self.sender.unbounded_send(reading.clone()).is_err() {
+            error!("{}: sink task has stopped", self.name);
+        }
+    }
+
+    /// Waits for the queued readings to be published and hands the sink back.
+    pub async fn stop(self) -> Box<dyn Sink> {
+        self.sender.close_channel();
+        self.task.await
+    }
+}
+
+pub async fn create_sinks(
+    shared_conf: Shared<Config>,
+    metrics: Shared<MetricsRegistry>,
+) -> Result<Vec<Box<dyn Sink>>> {
+    let (sinks, outbox, units, timezone) = {
+        let conf = shared_conf.lock().await;
+        let timezone = conf.climate().and_then(|climate| climate.timezone);
+        (
+            conf.sinks().to_vec(),
+            conf.outbox().clone(),
+            conf.units(),
+            timezone,
+        )
+    };
+    let mut result: Vec<Box<dyn Sink>> = Vec::with_capacity(sinks.len());
+    for sink in &sinks {
+        let name = sink.name();
+        info!("Creating sink {}", name);
+        match &sink.sink {
+            SinkType::HomeAssistant {
+                uri,
+                json_topic,
+                json_discovery,
+                format,
+            } => {
+                let queue = OutboxQueue::open(&outbox, &name, metrics.clone())?;
+                result.push(Box::new(HomeAssistant::new(
+                    name,
+                    uri,
+                    json_topic.clone(),
+                    *json_discovery,
+                    *format,
+                    shared_conf.clone(),
+                    queue,
+                )))
+            }
+            SinkType::VutBr { uri, topic, format } => {
+                let uri = match uri {
+                    Some(uri) => uri.clone(),
+                    None => std::env::var(VUTBR_URI_ENV)?,
+                };
+                let queue = OutboxQueue::open(&outbox, &name, metrics.clone())?;
+                result.push(Box::new(VutBr::new(name, &uri, topic, *format, queue)))
+            }
+            SinkType::InfluxDb(conf) => result.push(Box::new(InfluxDb::new(name, conf)?)),
+            SinkType::Pws(conf) => result.push(Box::new(PwsUploader::new(name, conf)?)),
+            SinkType::Aprs(conf) => result.push(Box::new(AprsReporter::new(name, conf))),
+            SinkType::Webhook(conf) => result.push(Box::new(WebhookSender::new(name, conf)?)),
+            SinkType::Homie(conf) => {
+                result.push(Box::new(HomieDevice::new(name, conf, shared_conf.clone())))
+            }
+            SinkType::Domoticz(conf) => {
+                result.push(Box::new(DomoticzSink::new(name, conf, shared_conf.clone())))
+            }
+            SinkType::Modbus(conf) => {
+                result.push(Box::new(ModbusServer::new(name, conf, shared_conf.clone())))
+            }
+            SinkType::RealtimeFile(conf) => result.push(Box::new(RealtimeFileWriter::new(
+                name, conf, units, timezone,
+            ))),
+            SinkType::Dashboard(conf) => result.push(Box::new(DashboardServer::new(
+                name,
+                conf,
+                shared_conf.clone(),
+                metrics.clone(),
+            ))),
+        }
+    }
+    Ok(result)
+}
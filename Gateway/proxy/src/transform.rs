@@ -0,0 +1,226 @@
+use crate::error::{Error, Result};
+use crate::util::{interpolate, ADC_MAX_VALUE, KELVIN_OFFSET, WIND_VANE_POSITIONS};
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+
+/// Non-linear calibration applied to the result of the analog pin expression.
+///
+/// `Thermistor` and `WindVane` expect ADC counts of a voltage divider with the sensor
+/// between the pin and ground and `series_resistor` between the pin and the reference.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Transform {
+    Table {
+        points: Vec<TablePoint>,
+    },
+    Polynomial {
+        coefficients: Vec<f64>,
+    },
+    Thermistor {
+        series_resistor: f64,
+        a: f64,
+        b: f64,
+        c: f64,
+    },
+    WindVane {
+        series_resistor: f64,
+        #[serde(default = "default_positions")]
+        positions: Vec<VanePosition>,
+    },
+}
+
+impl Transform {
+    pub fn apply(&self, value: f64) -> Option<f64> {
+        match self {
+            Transform::Table { points } => {
+                interpolate(points, value, |point| (point.input, point.output))
+            }
+            Transform::Polynomial { coefficients } => Some(
+                coefficients
+                    .iter()
+                    .rev()
+                    .fold(0., |result, coefficient| result * value + coefficient),
+            ),
+            Transform::Thermistor {
+                series_resistor,
+                a,
+                b,
+                c,
+            } => {
+                let ln_r = divider_resistance(*series_resistor, value)?.ln();
+                Some(1. / (a + b * ln_r + c * ln_r.powi(3)) - KELVIN_OFFSET)
+            }
+            Transform::WindVane {
+                series_resistor,
+                positions,
+            } => {
+                let resistance = divider_resistance(*series_resistor, value)?;
+                positions
+                    .iter()
+                    .map(|position| {
+                        let distance = (resistance / position.resistance).ln().abs();
+                        (distance, position.direction)
+                    })
+                    .min_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(Ordering::Equal))
+                    .map(|(_, direction)| direction)
+            }
+        }
+    }
+
+    pub fn init(&mut self, name: &str) -> Result<()> {
+        match self {
+            Transform::Table { points } => {
+                if points.len() < 2 {
+                    return Err(Error::new_config(format!(
+                        "Table of analog pin {} needs at least two points",
+                        name
+                    )));
+                }
+                points.sort_by(|a, b| a.input.partial_cmp(&b.input).unwrap_or(Ordering::Equal));
+            }
+            Transform::Polynomial { coefficients } if coefficients.is_empty() => {
+                return Err(Error::new_config(format!(
+                    "Polynomial of analog pin {} has no coefficients",
+                    name
+                )));
+            }
+            Transform::WindVane { positions, .. } if positions.is_empty() => {
+                return Err(Error::new_config(format!(
+                    "Wind vane of analog pin {} has no positions",
+                    name
+                )));
+            }
+            Transform::Thermistor {
+                series_resistor, ..
+            }
+            | Transform::WindVane {
+                series_resistor, ..
+            } if *series_resistor <= 0. => {
+                return Err(Error::new_config(format!(
+                    "Series resistor of analog pin {} must be positive",
+                    name
+                )));
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TablePoint {
+    pub input: f64,
+    pub output: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VanePosition {
+    pub resistance: f64,
+    pub direction: f64,
+}
+
+impl VanePosition {
+    pub const fn new(resistance: f64, direction: f64) -> Self {
+        VanePosition {
+            resistance,
+            direction,
+        }
+    }
+}
+
+fn default_positions() -> Vec<VanePosition> {
+    WIND_VANE_POSITIONS
+        .iter()
+        .map(|position| VanePosition::new(position.resistance, position.direction))
+        .collect()
+}
+
+fn divider_resistance(series_resistor: f64, value: f64) -> Option<f64> {
+    if value <= 0. || value >= ADC_MAX_VALUE {
+        return None;
+    }
+    Some(series_resistor * value / (ADC_MAX_VALUE - value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transform(yaml: &str) -> Transform {
+        let mut transform: Transform = serde_yaml::from_str(yaml).unwrap();
+        transform.init("A0").unwrap();
+        transform
+    }
+
+    /// ADC counts of a divider with `resistance` to ground and `series` to the reference.
+    fn counts(series: f64, resistance: f64) -> f64 {
+        ADC_MAX_VALUE * resistance / (resistance + series)
+    }
+
+    #[test]
+    fn table_interpolates_sorted_points() {
+        let table = transform(
+            "type: Table\npoints:\n\
+             - {input: 200, output: 40}\n\
+             - {input: 0, output: 0}\n\
+             - {input: 100, output: 10}",
+        );
+        assert_eq!(table.apply(50.), Some(5.));
+        assert_eq!(table.apply(100.), Some(10.));
+        assert_eq!(table.apply(150.), Some(25.));
+        assert_eq!(table.apply(-10.), Some(0.));
+        assert_eq!(table.apply(500.), Some(40.));
+    }
+
+    #[test]
+    fn polynomial_starts_with_the_constant() {
+        let polynomial = transform("type: Polynomial\ncoefficients: [1, 2, 3]");
+        assert_eq!(polynomial.apply(0.), Some(1.));
+        assert_eq!(polynomial.apply(2.), Some(17.));
+        assert_eq!(polynomial.apply(-1.), Some(2.));
+    }
+
+    #[test]
+    fn thermistor_of_10k_ntc_at_25_celsius() {
+        let thermistor = transform(
+            "type: Thermistor\nseries_resistor: 10000\n\
+             a: 0.001129148\nb: 0.000234125\nc: 0.0000000876741",
+        );
+        let celsius = thermistor.apply(counts(10_000., 10_000.)).unwrap();
+        assert!((celsius - 25.).abs() < 0.01, "{}", celsius);
+        let celsius = thermistor.apply(counts(10_000., 32_650.)).unwrap();
+        assert!(celsius.abs() < 0.1, "{}", celsius);
+        assert_eq!(thermistor.apply(0.), None);
+        assert_eq!(thermistor.apply(ADC_MAX_VALUE), None);
+    }
+
+    #[test]
+    fn wind_vane_resolves_all_positions() {
+        let vane = transform("type: WindVane\nseries_resistor: 10000");
+        assert_eq!(WIND_VANE_POSITIONS.len(), 16);
+        for (num, position) in WIND_VANE_POSITIONS.iter().enumerate() {
+            assert_eq!(position.direction, num as f64 * 22.5);
+            let value = counts(10_000., position.resistance).round();
+            assert_eq!(
+                vane.apply(value),
+                Some(position.direction),
+                "{:?}",
+                position
+            );
+        }
+        assert_eq!(vane.apply(0.), None);
+    }
+
+    #[test]
+    fn invalid_transforms_are_rejected() {
+        for yaml in &[
+            "type: Table\npoints: [{input: 0, output: 0}]",
+            "type: Polynomial\ncoefficients: []",
+            "type: WindVane\nseries_resistor: 10000\npositions: []",
+            "type: Thermistor\nseries_resistor: 0\na: 1\nb: 1\nc: 1",
+        ] {
+            let mut transform: Transform = serde_yaml::from_str(yaml).unwrap();
+            assert!(transform.init("A0").is_err(), "{}", yaml);
+        }
+    }
+}
@@ -0,0 +1,170 @@
+use crate::error::{Error, Result};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum TemperatureUnit {
+    #[serde(rename = "C")]
+    Celsius,
+    #[serde(rename = "F")]
+    Fahrenheit,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum PressureUnit {
+    #[serde(rename = "hPa")]
+    Hectopascal,
+    #[serde(rename = "inHg")]
+    InchMercury,
+    #[serde(rename = "mmHg")]
+    MillimeterMercury,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum RainUnit {
+    #[serde(rename = "mm")]
+    Millimeter,
+    #[serde(rename = "in")]
+    Inch,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum WindUnit {
+    #[serde(rename = "m/s")]
+    MeterPerSecond,
+    #[serde(rename = "km/h")]
+    KilometerPerHour,
+    #[serde(rename = "mph")]
+    MilePerHour,
+    #[serde(rename = "kn")]
+    Knot,
+}
+
+/// Units of the exported values, measurements are kept in the metric units internally.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Units {
+    pub temperature: TemperatureUnit,
+    pub pressure: PressureUnit,
+    pub rain: RainUnit,
+    pub wind: WindUnit,
+}
+
+impl Default for Units {
+    fn default() -> Self {
+        Units {
+            temperature: TemperatureUnit::Celsius,
+            pressure: PressureUnit::Hectopascal,
+            rain: RainUnit::Millimeter,
+            wind: WindUnit::MeterPerSecond,
+        }
+    }
+}
+
+impl Units {
+    pub fn imperial() -> Self {
+        Units {
+            temperature: TemperatureUnit::Fahrenheit,
+            pressure: PressureUnit::InchMercury,
+            rain: RainUnit::Inch,
+            wind: WindUnit::MilePerHour,
+        }
+    }
+
+    /// Applies `metric`, `imperial` or a single `quantity=unit` override.
+    pub fn update(&mut self, spec: &str) -> Result<()> {
+        let invalid = || Error::new_argument(format!("Invalid unit '{}'", spec));
+        match spec.split('=').collect::<Vec<_>>().as_slice() {
+            ["metric"] => *self = Units::default(),
+            ["imperial"] => *self = Units::imperial(),
+            ["temperature", unit] => {
+                self.temperature = serde_yaml::from_str(unit).map_err(|_| invalid())?
+            }
+            ["pressure", unit] => {
+                self.pressure = serde_yaml::from_str(unit).map_err(|_| invalid())?
+            }
+            ["rain", unit] => self.rain = serde_yaml::from_str(unit).map_err(|_| invalid())?,
+            ["wind", unit] => self.wind = serde_yaml::from_str(unit).map_err(|_| invalid())?,
+            _ => return Err(invalid()),
+        }
+        Ok(())
+    }
+
+    /// Unit symbol of the named measurement, empty for measurements without a unit
+    /// setting.
+    pub fn label(&self, measurement: &str) -> &'static str {
+        match measurement {
+            "temperature"
+            | "growing_degree_days"
+            | "growing_degree_days_season"
+            | "heating_degree_days"
+            | "heating_degree_days_season"
+            | "cooling_degree_days"
+            | "cooling_degree_days_season" => match self.temperature {
+                TemperatureUnit::Celsius => "C",
+                TemperatureUnit::Fahrenheit => "F",
+            },
+            "pressure" => match self.pressure {
+                PressureUnit::Hectopascal => "hPa",
+                PressureUnit::InchMercury => "inHg",
+                PressureUnit::MillimeterMercury => "mmHg",
+            },
+            "rain" | "rain_daily" | "evapotranspiration" | "evapotranspiration_season" => {
+                match self.rain {
+                    RainUnit::Millimeter => "mm",
+                    RainUnit::Inch => "in",
+                }
+            }
+            "rain_rate" => match self.rain {
+                RainUnit::Millimeter => "mm/h",
+                RainUnit::Inch => "in/h",
+            },
+            "wind_speed" | "wind_gust" => match self.wind {
+                WindUnit::MeterPerSecond => "m/s",
+                WindUnit::KilometerPerHour => "km/h",
+                WindUnit::MilePerHour => "mph",
+                WindUnit::Knot => "kn",
+            },
+            _ => "",
+        }
+    }
+
+    /// Converts a value of the named measurement, see `Reading::measurements`.
+    pub fn convert(&self, measurement: &str, value: f64) -> f64 {
+        match measurement {
+            "temperature" => match self.temperature {
+                TemperatureUnit::Celsius => value,
+                TemperatureUnit::Fahrenheit => value * 9. / 5. + 32.,
+            },
+            // Degree days are temperature differences, without the offset.
+            "growing_degree_days"
+            | "growing_degree_days_season"
+            | "heating_degree_days"
+            | "heating_degree_days_season"
+            | "cooling_degree_days"
+            | "cooling_degree_days_season" => match self.temperature {
+                TemperatureUnit::Celsius => value,
+                TemperatureUnit::Fahrenheit => value * 9. / 5.,
+            },
+            "pressure" => match self.pressure {
+                PressureUnit::Hectopascal => value,
+                PressureUnit::InchMercury => value * 0.029_529_983,
+                PressureUnit::MillimeterMercury => value * 0.750_061_68,
+            },
+            "rain"
+            | "rain_rate"
+            | "rain_daily"
+            | "evapotranspiration"
+            | "evapotranspiration_season" => match self.rain {
+                RainUnit::Millimeter => value,
+                RainUnit::Inch => value / 25.4,
+            },
+            "wind_speed" | "wind_gust" => match self.wind {
+                WindUnit::MeterPerSecond => value,
+                WindUnit::KilometerPerHour => value * 3.6,
+                WindUnit::MilePerHour => value * 2.236_936_3,
+                WindUnit::Knot => value * 1.943_844_5,
+            },
+            _ => value,
+        }
+    }
+}
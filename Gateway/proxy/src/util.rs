@@ -1,6 +1,14 @@
-use crate::config::Discovery;
+use crate::config::{CurvePoint, Discovery};
+use crate::error::Result;
+use crate::transform::VanePosition;
 use async_std::sync::{Arc, Mutex};
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc};
+use chrono_tz::Tz;
 use futures::channel::mpsc;
+use std::fs::{self, File};
+use std::io::Write;
+use std::ops::RangeInclusive;
+use std::path::Path;
 
 pub const SPI_DEV: &str = "/dev/spidev0.0";
 pub const GPIO_CHIP: &str = "/dev/gpiochip0";
@@ -19,36 +27,316 @@ pub const LOG_TIME_FORMAT: &str = "%d.%m.%Y %H:%M:%S.%f";
 pub const LOG_MODULE_IGNORE: &str = "paho_mqtt";
 pub const PACKET_CONFIG: u8 = 0x02;
 pub const PACKET_DATA: u8 = 0x08;
+pub const PACKET_DATA_LENGTH: usize = 34;
+pub const ADC_REFERENCE: f64 = 3.3;
+pub const ADC_MAX_VALUE: f64 = (1 << 12) as f64 - 1.;
+pub const BATTERY_DIVIDER: f64 = 0.8;
+pub const BATTERY_TREND_WINDOW: usize = 12;
+pub const LI_ION_CURVE: &[CurvePoint] = &[
+    CurvePoint::new(3.27, 0.),
+    CurvePoint::new(3.61, 5.),
+    CurvePoint::new(3.69, 10.),
+    CurvePoint::new(3.73, 20.),
+    CurvePoint::new(3.77, 30.),
+    CurvePoint::new(3.80, 40.),
+    CurvePoint::new(3.84, 50.),
+    CurvePoint::new(3.87, 60.),
+    CurvePoint::new(3.95, 70.),
+    CurvePoint::new(4.02, 80.),
+    CurvePoint::new(4.11, 90.),
+    CurvePoint::new(4.20, 100.),
+];
+pub const NI_MH_CURVE: &[CurvePoint] = &[
+    CurvePoint::new(1.00, 0.),
+    CurvePoint::new(1.10, 5.),
+    CurvePoint::new(1.15, 15.),
+    CurvePoint::new(1.20, 30.),
+    CurvePoint::new(1.22, 50.),
+    CurvePoint::new(1.25, 70.),
+    CurvePoint::new(1.30, 90.),
+    CurvePoint::new(1.40, 100.),
+];
+pub const CALIBRATION_PATH: &str = "/proxy/data/calibration.json";
+pub const CALIBRATION_TOPIC_PREFIX: &str = "node/calibration/";
+pub const CALIBRATION_TOPIC_SUFFIX: &str = "/set";
+pub const CALIBRATION_SENSORS: [&str; 4] = ["temperature", "pressure", "humidity", "battery"];
+pub const KELVIN_OFFSET: f64 = 273.15;
+pub const WIND_VANE_POSITIONS: &[VanePosition] = &[
+    VanePosition::new(33_000., 0.),
+    VanePosition::new(6_570., 22.5),
+    VanePosition::new(8_200., 45.),
+    VanePosition::new(891., 67.5),
+    VanePosition::new(1_000., 90.),
+    VanePosition::new(688., 112.5),
+    VanePosition::new(2_200., 135.),
+    VanePosition::new(1_410., 157.5),
+    VanePosition::new(3_900., 180.),
+    VanePosition::new(3_140., 202.5),
+    VanePosition::new(16_000., 225.),
+    VanePosition::new(14_120., 247.5),
+    VanePosition::new(120_000., 270.),
+    VanePosition::new(42_120., 292.5),
+    VanePosition::new(64_900., 315.),
+    VanePosition::new(21_880., 337.5),
+];
+pub const NATIVE_VALUE_TEMPLATE: &str = "{{ value }}";
+pub const ANALOG_PRECISION: u8 = 3;
 pub const BATTERY_CONFIG_TOPIC: &str = "homeassistant/sensor/node/analog_bat/config";
 pub const BATTERY_SENSOR: Discovery = Discovery::Sensor {
     name: "Battery",
     state_topic: "node/analog/bat/state",
     unit_of_measurement: "V",
-    value_template: "{{ ((float(value) * 3.3 / (2**12 - 1)) / 0.8) | round(3) }}",
+    value_template: NATIVE_VALUE_TEMPLATE,
+    device_class: None,
+};
+pub const BATTERY_LEVEL_CONFIG_TOPIC: &str = "homeassistant/sensor/node/battery_level/config";
+pub const BATTERY_LEVEL_SENSOR: Discovery = Discovery::Sensor {
+    name: "Battery level",
+    state_topic: "node/battery/level/state",
+    unit_of_measurement: "%",
+    value_template: NATIVE_VALUE_TEMPLATE,
+    device_class: Some("battery"),
+};
+pub const BATTERY_LOW_CONFIG_TOPIC: &str = "homeassistant/binary_sensor/node/battery_low/config";
+pub const BATTERY_LOW_SENSOR: Discovery = Discovery::BinarySensor {
+    name: "Battery low",
+    state_topic: "node/battery/low/state",
+    payload_on: PAYLOAD_ON,
+    payload_off: PAYLOAD_OFF,
+    device_class: Some("battery"),
+};
+pub const BATTERY_CRITICAL_CONFIG_TOPIC: &str =
+    "homeassistant/binary_sensor/node/battery_critical/config";
+pub const BATTERY_CRITICAL_SENSOR: Discovery = Discovery::BinarySensor {
+    name: "Battery critical",
+    state_topic: "node/battery/critical/state",
+    payload_on: PAYLOAD_ON,
+    payload_off: PAYLOAD_OFF,
+    device_class: Some("battery"),
+};
+pub const BATTERY_CHARGING_CONFIG_TOPIC: &str =
+    "homeassistant/binary_sensor/node/battery_charging/config";
+pub const BATTERY_CHARGING_SENSOR: Discovery = Discovery::BinarySensor {
+    name: "Battery charging",
+    state_topic: "node/battery/charging/state",
+    payload_on: PAYLOAD_ON,
+    payload_off: PAYLOAD_OFF,
+    device_class: Some("battery_charging"),
+};
+pub const READING_TIMESTAMP_CONFIG_TOPIC: &str =
+    "homeassistant/sensor/node/reading_timestamp/config";
+pub const READING_TIMESTAMP_SENSOR: Discovery = Discovery::Sensor {
+    name: "Last reading",
+    state_topic: "node/reading/timestamp/state",
+    unit_of_measurement: "",
+    value_template: NATIVE_VALUE_TEMPLATE,
+    device_class: Some("timestamp"),
+};
+pub const RSSI_CONFIG_TOPIC: &str = "homeassistant/sensor/node/rssi/config";
+pub const RSSI_SENSOR: Discovery = Discovery::Sensor {
+    name: "Signal strength",
+    state_topic: "node/radio/rssi/state",
+    unit_of_measurement: "dBm",
+    value_template: NATIVE_VALUE_TEMPLATE,
+    device_class: Some("signal_strength"),
 };
-pub const WEATHER_SENSOR_TEMPLATE: &str = "{{ (float(value) / 100) | round(2) }}";
 pub const TEMPERATURE_CONFIG_TOPIC: &str = "homeassistant/sensor/node/temperature/config";
 pub const TEMPERATURE_SENSOR: Discovery = Discovery::Sensor {
     name: "Temperature",
     state_topic: "node/analog/temperature/state",
     unit_of_measurement: "°C",
-    value_template: WEATHER_SENSOR_TEMPLATE,
+    value_template: NATIVE_VALUE_TEMPLATE,
+    device_class: None,
 };
 pub const PRESSURE_CONFIG_TOPIC: &str = "homeassistant/sensor/node/pressure/config";
 pub const PRESSURE_SENSOR: Discovery = Discovery::Sensor {
     name: "Pressure",
     state_topic: "node/analog/pressure/state",
     unit_of_measurement: "hPa",
-    value_template: WEATHER_SENSOR_TEMPLATE,
+    value_template: NATIVE_VALUE_TEMPLATE,
+    device_class: None,
 };
 pub const HUMIDITY_CONFIG_TOPIC: &str = "homeassistant/sensor/node/humidity/config";
 pub const HUMIDITY_SENSOR: Discovery = Discovery::Sensor {
     name: "Humidity",
     state_topic: "node/analog/humidity/state",
     unit_of_measurement: "%",
-    value_template: WEATHER_SENSOR_TEMPLATE,
+    value_template: NATIVE_VALUE_TEMPLATE,
+    device_class: None,
 };
 
+pub const HISTORY_TIERS: [(&str, u32, u32); 3] =
+    [("raw", 0, 7), ("5min", 300, 365), ("1h", 3600, 3650)];
+pub const HISTORY_SEGMENT_FORMAT: &str = "%Y-%m-%d";
+pub const HISTORY_SEGMENT_EXTENSION: &str = "seg";
+pub const CLIMATE_PATH: &str = "/proxy/data/climate";
+pub const CLIMATE_REPORTS: &str = "/proxy/data/reports";
+pub const CLIMATE_EXTENSION: &str = "day";
+pub const CLIMATE_TODAY: &str = "today";
+pub const STATISTICS_TOPIC_PREFIX: &str = "node/statistics";
+pub const STATISTICS_PERIODS: [(&str, &str); 3] = [
+    ("today", "Today"),
+    ("month", "Month"),
+    ("all_time", "All-time"),
+];
+pub const STATISTICS_QUANTITIES: [(&str, &str, &str); 3] = [
+    ("temperature", "temperature", "°C"),
+    ("pressure", "pressure", "hPa"),
+    ("humidity", "humidity", "%"),
+];
+pub const STATISTICS_EXTREMES: [(&str, &str); 2] = [("min", "low"), ("max", "high")];
+pub const INDICES_GROWING_BASE: f64 = 10.;
+pub const INDICES_GROWING_CAP: f64 = 30.;
+pub const INDICES_SEASON_START: &str = "01-01";
+pub const INDICES_PERIODS: [(&str, &str); 2] = [("today", "Today"), ("season", "Season")];
+pub const INDICES: [(&str, &str, &str); 4] = [
+    ("growing_degree_days", "growing degree days", "°C·d"),
+    ("heating_degree_days", "heating degree days", "°C·d"),
+    ("cooling_degree_days", "cooling degree days", "°C·d"),
+    ("evapotranspiration", "evapotranspiration", "mm"),
+];
+pub const NOAA_STATION: &str = "weather-station";
+pub const NOAA_DEGREE_DAY_BASE: f64 = 18.333;
+pub const NOAA_TEMPERATURE_THRESHOLDS_CELSIUS: [f64; 4] = [30., 0., 0., -18.];
+pub const NOAA_TEMPERATURE_THRESHOLDS_FAHRENHEIT: [f64; 4] = [90., 32., 32., 0.];
+pub const NOAA_RAIN_THRESHOLDS_MILLIMETER: [f64; 3] = [0.2, 2., 20.];
+pub const NOAA_RAIN_THRESHOLDS_INCH: [f64; 3] = [0.01, 0.1, 1.];
+pub const OUTBOX_PATH: &str = "/proxy/data/outbox";
+pub const OUTBOX_MAX_MESSAGES: usize = 50_000;
+pub const OUTBOX_TRIM_PERCENT: usize = 90;
+pub const OUTBOX_HOME_ASSISTANT: &str = "home_assistant";
+pub const OUTBOX_VUTBR: &str = "vutbr";
+pub const HTTP_TIMEOUT: u64 = 10;
+pub const INFLUXDB_SINK: &str = "influxdb";
+pub const INFLUXDB_MEASUREMENT: &str = "weather";
+pub const INFLUXDB_TOKEN_ENV: &str = "INFLUXDB_TOKEN";
+pub const INFLUXDB_BATCH_SIZE: usize = 10;
+pub const INFLUXDB_FLUSH_INTERVAL: u64 = 60;
+pub const INFLUXDB_MAX_LINES: usize = 10_000;
+pub const INFLUXDB_UDP_PAYLOAD: usize = 1400;
+pub const PWS_INTERVAL: u64 = 300;
+pub const WEBHOOK_SINK: &str = "webhook";
+pub const WEBHOOK_CONTENT_TYPE: &str = "application/json";
+pub const WEBHOOK_RETRIES: u32 = 3;
+pub const WEBHOOK_RETRY_DELAY: u64 = 5;
+pub const WEBHOOK_SIGNATURE_HEADER: &str = "X-Signature-256";
+pub const HOMIE_SINK: &str = "homie";
+pub const HOMIE_BASE_TOPIC: &str = "homie";
+pub const HOMIE_VERSION: &str = "4.0";
+pub const DOMOTICZ_SINK: &str = "domoticz";
+pub const DOMOTICZ_IN_TOPIC: &str = "domoticz/in";
+pub const DOMOTICZ_OUT_TOPIC: &str = "domoticz/out";
+pub const SENML_BASE_NAME: &str = "weather-station/node-";
+pub const SENML_JSON_CONTENT_TYPE: &str = "application/senml+json";
+pub const SENML_CBOR_CONTENT_TYPE: &str = "application/senml+cbor";
+pub const MODBUS_SINK: &str = "modbus";
+pub const MODBUS_ADDR: &str = "0.0.0.0:502";
+pub const MODBUS_MAX_READ_BITS: u16 = 2000;
+pub const MODBUS_MAX_READ_REGISTERS: u16 = 125;
+pub const MODBUS_MAX_WRITE_COILS: u16 = 1968;
+pub const MODBUS_ILLEGAL_FUNCTION: u8 = 1;
+pub const MODBUS_ILLEGAL_DATA_ADDRESS: u8 = 2;
+pub const MODBUS_ILLEGAL_DATA_VALUE: u8 = 3;
+pub const MODBUS_DEVICE_FAILURE: u8 = 4;
+pub const REALTIME_FILE_SINK: &str = "realtime-file";
+pub const REALTIME_STATION: &str = "weather-station";
+pub const REALTIME_TREND_WINDOW: i64 = 10_800;
+pub const CUMULUS_VERSION: &str = "1.9.4";
+pub const CUMULUS_BUILD: &str = "1099";
+pub const CLIENTRAW_FIELDS: usize = 178;
+pub const CLIENTRAW_VERSION: &str = "!!C10.37S!!";
+pub const BEAUFORT_SCALE: [f64; 12] = [
+    0.3, 1.6, 3.4, 5.5, 8.0, 10.8, 13.9, 17.2, 20.8, 24.5, 28.5, 32.7,
+];
+pub const COMPASS_POINTS: [&str; 16] = [
+    "N", "NNE", "NE", "ENE", "E", "ESE", "SE", "SSE", "S", "SSW", "SW", "WSW", "W", "WNW", "NW",
+    "NNW",
+];
+pub const DASHBOARD_SINK: &str = "dashboard";
+pub const DASHBOARD_ADDR: &str = "0.0.0.0:8080";
+pub const DASHBOARD_WINDOW: i64 = 10_800;
+pub const DASHBOARD_MAX_POINTS: usize = 720;
+pub const APRS_SINK: &str = "aprs";
+pub const APRS_SERVER: &str = "cwop.aprs.net:14580";
+pub const APRS_INTERVAL: u64 = 300;
+pub const APRS_DESTINATION: &str = "APRS";
+pub const APRS_SOFTWARE: &str = "weather-station-proxy";
+pub const PWS_RETRY_DELAY: u64 = 60;
+pub const PWS_SOFTWARE_TYPE: &str = "weather-station-proxy";
+pub const PWS_WEATHER_UNDERGROUND_SINK: &str = "wunderground";
+pub const PWS_WEATHER_UNDERGROUND_URL: &str =
+    "https://weatherstation.wunderground.com/weatherstation/updateweatherstation.php";
+pub const PWS_WINDY_SINK: &str = "windy";
+pub const PWS_WINDY_URL: &str = "https://stations.windy.com/pws/update";
+pub const PWS_OPENWEATHERMAP_SINK: &str = "openweathermap";
+pub const PWS_OPENWEATHERMAP_URL: &str = "https://api.openweathermap.org/data/3.0/measurements";
+pub const COUNTER_UNIT: &str = "pulses";
+/// Pins the node counts pulses on, pins 0 and 1 are reserved.
+pub const COUNTER_PINS: RangeInclusive<u8> = 2..=7;
+pub const RAIN_RATE_WINDOW: u64 = 600;
+pub const RAIN_HOUR_WINDOW: u64 = 3600;
+pub const RAIN_DAY_WINDOW: u64 = 86_400;
+pub const WIND_GUST_WINDOW: u64 = 600;
+pub const RAIN_CONFIG_TOPIC: &str = "homeassistant/sensor/node/rain/config";
+pub const RAIN_SENSOR: Discovery = Discovery::Sensor {
+    name: "Rain",
+    state_topic: "node/rain/amount/state",
+    unit_of_measurement: "mm",
+    value_template: NATIVE_VALUE_TEMPLATE,
+    device_class: None,
+};
+pub const RAIN_RATE_CONFIG_TOPIC: &str = "homeassistant/sensor/node/rain_rate/config";
+pub const RAIN_RATE_SENSOR: Discovery = Discovery::Sensor {
+    name: "Rain rate",
+    state_topic: "node/rain/rate/state",
+    unit_of_measurement: "mm/h",
+    value_template: NATIVE_VALUE_TEMPLATE,
+    device_class: None,
+};
+pub const RAIN_DAILY_CONFIG_TOPIC: &str = "homeassistant/sensor/node/rain_daily/config";
+pub const RAIN_DAILY_SENSOR: Discovery = Discovery::Sensor {
+    name: "Rain today",
+    state_topic: "node/rain/daily/state",
+    unit_of_measurement: "mm",
+    value_template: NATIVE_VALUE_TEMPLATE,
+    device_class: None,
+};
+pub const WIND_SPEED_CONFIG_TOPIC: &str = "homeassistant/sensor/node/wind_speed/config";
+pub const WIND_SPEED_SENSOR: Discovery = Discovery::Sensor {
+    name: "Wind speed",
+    state_topic: "node/wind/speed/state",
+    unit_of_measurement: "m/s",
+    value_template: NATIVE_VALUE_TEMPLATE,
+    device_class: None,
+};
+pub const WIND_GUST_CONFIG_TOPIC: &str = "homeassistant/sensor/node/wind_gust/config";
+pub const WIND_GUST_SENSOR: Discovery = Discovery::Sensor {
+    name: "Wind gust",
+    state_topic: "node/wind/gust/state",
+    unit_of_measurement: "m/s",
+    value_template: NATIVE_VALUE_TEMPLATE,
+    device_class: None,
+};
+
+/// Keys of the sensors in the JSON state document, HA discovery can read them from there.
+pub const JSON_STATE_KEYS: [(Discovery, &str); 15] = [
+    (READING_TIMESTAMP_SENSOR, "timestamp"),
+    (RSSI_SENSOR, "rssi"),
+    (TEMPERATURE_SENSOR, "temperature"),
+    (PRESSURE_SENSOR, "pressure"),
+    (HUMIDITY_SENSOR, "humidity"),
+    (BATTERY_SENSOR, "battery_voltage"),
+    (BATTERY_LEVEL_SENSOR, "battery_level"),
+    (BATTERY_LOW_SENSOR, "battery_low"),
+    (BATTERY_CRITICAL_SENSOR, "battery_critical"),
+    (BATTERY_CHARGING_SENSOR, "battery_charging"),
+    (RAIN_SENSOR, "rain"),
+    (RAIN_RATE_SENSOR, "rain_rate"),
+    (RAIN_DAILY_SENSOR, "rain_daily"),
+    (WIND_SPEED_SENSOR, "wind_speed"),
+    (WIND_GUST_SENSOR, "wind_gust"),
+];
+
 pub type Shared<T> = Arc<Mutex<T>>;
 pub type Receiver<T> = mpsc::UnboundedReceiver<T>;
 pub type Sender<T> = mpsc::UnboundedSender<T>;
@@ -60,6 +348,73 @@ macro_rules! new_shared {
     };
 }
 
+/// Piecewise-linear interpolation over points sorted by x, clamped at both ends.
+pub fn interpolate<T>(points: &[T], x: f64, xy: impl Fn(&T) -> (f64, f64)) -> Option<f64> {
+    let (first, last) = (xy(points.first()?), xy(points.last()?));
+    if x <= first.0 {
+        return Some(first.1);
+    }
+    if x >= last.0 {
+        return Some(last.1);
+    }
+    points
+        .windows(2)
+        .map(|pair| (xy(&pair[0]), xy(&pair[1])))
+        .find(|(_, high)| x <= high.0)
+        .map(|(low, high)| low.1 + (x - low.0) / (high.0 - low.0) * (high.1 - low.1))
+}
+
+pub fn utc_from_millis(millis: i64) -> DateTime<Utc> {
+    Utc.timestamp_millis_opt(millis)
+        .single()
+        .unwrap_or(DateTime::<Utc>::MIN_UTC)
+}
+
+pub fn utc_day_start(date: NaiveDate) -> DateTime<Utc> {
+    Utc.from_utc_datetime(&date.and_time(NaiveTime::MIN))
+}
+
+/// Wall clock time of `time` in `timezone`, the system timezone when unset.
+pub fn local_time(time: DateTime<Utc>, timezone: Option<Tz>) -> NaiveDateTime {
+    match timezone {
+        Some(timezone) => time.with_timezone(&timezone).naive_local(),
+        None => time.with_timezone(&Local).naive_local(),
+    }
+}
+
+/// 16 point compass name of a direction in degrees.
+pub fn compass_point(direction: f64) -> &'static str {
+    let index = (direction.rem_euclid(360.) / 22.5).round() as usize % COMPASS_POINTS.len();
+    COMPASS_POINTS[index]
+}
+
+/// Replaces the file at once, so a reader never sees it half written.
+pub fn write_atomic(path: &Path, content: &[u8]) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(content)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Empty directory for the files of a test, unique to the test process.
+#[cfg(test)]
+pub fn test_dir(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("proxy-{}-{}", std::process::id(), name));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+pub fn convert_bool(value: bool) -> &'static str {
+    if value {
+        PAYLOAD_ON
+    } else {
+        PAYLOAD_OFF
+    }
+}
+
 #[macro_export]
 macro_rules! convert_digital {
     ($num:ident,$digital:ident) => {
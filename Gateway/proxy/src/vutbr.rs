@@ -1,8 +1,13 @@
-use crate::data::Data;
+use crate::config::PayloadFormat;
 use crate::error::Result;
-use crate::util::{PAYLOAD_OFF, PAYLOAD_ON, VUTBR_TOPIC, VUTBR_URI_ENV};
+use crate::outbox::{reconnect, OutboxQueue, QueuedMessage};
+use crate::reading::Reading;
+use crate::senml;
+use crate::sink::Sink;
+use crate::util::{PAYLOAD_OFF, PAYLOAD_ON};
+use async_trait::async_trait;
 use futures::compat::Future01CompatExt;
-use paho_mqtt::{AsyncClient, AsyncClientBuilder, ConnectOptions, Message};
+use paho_mqtt::{AsyncClient, AsyncClientBuilder, ConnectOptions};
 use serde::Serialize;
 
 macro_rules! create_sensor {
@@ -21,48 +26,103 @@ struct Sensor {
 }
 
 pub struct VutBr {
+    name: String,
+    topic: String,
+    format: PayloadFormat,
     mqtt: AsyncClient,
+    outbox: OutboxQueue,
 }
 
 impl VutBr {
-    pub async fn new() -> Result<Self> {
-        let server = std::env::var(VUTBR_URI_ENV)?;
-        let mqtt = AsyncClientBuilder::new().server_uri(&server).finalize();
-        mqtt.connect(ConnectOptions::new()).compat().await?;
-        let result = VutBr { mqtt };
-        Ok(result)
+    pub fn new(
+        name: String,
+        uri: &str,
+        topic: &str,
+        format: PayloadFormat,
+        outbox: OutboxQueue,
+    ) -> Self {
+        let mqtt = AsyncClientBuilder::new().server_uri(uri).finalize();
+        VutBr {
+            name,
+            topic: topic.to_string(),
+            format,
+            mqtt,
+            outbox,
+        }
+    }
+}
+
+#[async_trait]
+impl Sink for VutBr {
+    fn name(&self) -> &str {
+        &self.name
     }
 
-    pub async fn update_state(&self, data: &Data) -> Result<()> {
-        let mut vec = Vec::<Sensor>::with_capacity(15);
-        let digital = data.gpio_value;
-        for num in 0..8 {
-            vec.push(create_sensor!(
-                format!("Digital{}", num),
-                convert_digital!(num, digital)
-            ));
+    async fn start(&mut self) -> Result<()> {
+        if let Err(err) = self.mqtt.connect(ConnectOptions::new()).compat().await {
+            warn!("VutBr broker unreachable, buffering: {:?}", err);
         }
-        for num in 0usize..3 {
-            vec.push(create_sensor!(
-                format!("Analog{}", num),
-                data.adc_value[num]
-            ));
+        Ok(())
+    }
+
+    async fn publish(&mut self, reading: &Reading) -> Result<()> {
+        let payload = match self.format {
+            PayloadFormat::Native => serde_json::to_vec(&sensors(reading))?,
+            PayloadFormat::SenmlJson => senml::to_json(&senml::records(reading)),
+            PayloadFormat::SenmlCbor => senml::to_cbor(&senml::records(reading))?,
+        };
+        let timestamp = reading.timestamp.timestamp_millis();
+        let messages = vec![QueuedMessage::new(timestamp, &self.topic, payload)];
+        let connection = reconnect(&self.mqtt, &self.name).await;
+        self.outbox.publish(&self.mqtt, connection, messages).await
+    }
+
+    async fn shutdown(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+fn sensors(reading: &Reading) -> Vec<Sensor> {
+    let data = &reading.data;
+    let mut vec = Vec::<Sensor>::with_capacity(22);
+    vec.push(create_sensor!(
+        "Cas".to_string(),
+        reading.timestamp.to_rfc3339()
+    ));
+    let digital = data.gpio_value;
+    for num in 0..8 {
+        vec.push(create_sensor!(
+            format!("Digital{}", num),
+            convert_digital!(num, digital)
+        ));
+    }
+    for num in 0usize..3 {
+        vec.push(create_sensor!(
+            format!("Analog{}", num),
+            data.adc_value[num]
+        ));
+        if let Some(value) = reading.analog[num] {
+            vec.push(create_sensor!(format!("Analog{}Value", num), value));
         }
-        let battery = (data.bat_value as f64 * 3.3) / (2u32.pow(12) - 1) as f64 / 0.8;
-        vec.push(create_sensor!("Baterie".to_string(), battery));
+    }
+    vec.push(create_sensor!(
+        "Baterie".to_string(),
+        reading.battery.voltage
+    ));
 
-        let temperature = data.temperature as f64 / 100.;
-        vec.push(create_sensor!("Teplota".to_string(), temperature));
+    vec.push(create_sensor!("Teplota".to_string(), reading.temperature));
 
-        let pressure = data.pressure as f64 / 100.;
-        vec.push(create_sensor!("Tlak".to_string(), pressure));
+    vec.push(create_sensor!("Tlak".to_string(), reading.pressure));
 
-        let humidity = data.humidity as f64 / 100.;
-        vec.push(create_sensor!("Vlhkost".to_string(), humidity));
+    vec.push(create_sensor!("Vlhkost".to_string(), reading.humidity));
 
-        let payload = serde_json::to_string(&vec)?;
-        let msg = Message::new(VUTBR_TOPIC, payload, 0);
-        self.mqtt.publish(msg).compat().await?;
-        Ok(())
+    if let Some(rain) = &reading.rain {
+        vec.push(create_sensor!("Srazky".to_string(), rain.daily));
+        vec.push(create_sensor!("SrazkyIntenzita".to_string(), rain.rate));
+    }
+    if let Some(wind) = &reading.wind {
+        vec.push(create_sensor!("Vitr".to_string(), wind.speed));
+        vec.push(create_sensor!("VitrNarazy".to_string(), wind.gust));
     }
+    vec
 }
@@ -0,0 +1,315 @@
+use crate::config::PayloadFormat;
+use crate::error::{Error, Result};
+use crate::http::{self, read_body};
+use crate::reading::{Quality, Reading};
+use crate::senml::{self, Record, SenmlValue};
+use crate::sink::Sink;
+use crate::util::{
+    HTTP_TIMEOUT, WEBHOOK_CONTENT_TYPE, WEBHOOK_RETRIES, WEBHOOK_RETRY_DELAY,
+    WEBHOOK_SIGNATURE_HEADER,
+};
+use async_std::task;
+use async_trait::async_trait;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sha2::Sha256;
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+use surf::Client;
+
+/// HTTP POST of readings and events to every URL in `urls`.
+///
+/// `template` is the request body with `{{ name }}` placeholders for the measurements,
+/// `node`, `quality`, `event`, `timestamp` and `timestamp_ms`, a missing measurement is
+/// rendered as `null`. Without a template the body is a JSON object of all of them, the
+/// SenML formats replace both. `content_type` defaults to the type of the format. With
+/// a `secret` the body is signed by HMAC-SHA256 in the `signature_header`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Webhook {
+    pub urls: Vec<String>,
+    #[serde(default = "default_webhook_events")]
+    pub events: Vec<WebhookEvent>,
+    #[serde(default)]
+    pub template: Option<String>,
+    #[serde(default)]
+    pub format: PayloadFormat,
+    #[serde(default)]
+    pub content_type: Option<String>,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    #[serde(default = "default_webhook_timeout")]
+    pub timeout: u64,
+    #[serde(default = "default_webhook_retries")]
+    pub retries: u32,
+    #[serde(default = "default_webhook_retry_delay")]
+    pub retry_delay: u64,
+    #[serde(default)]
+    pub secret: Option<String>,
+    #[serde(default = "default_webhook_signature_header")]
+    pub signature_header: String,
+}
+
+/// `Reading` fires for every reading, the others once when the state is entered.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum WebhookEvent {
+    Reading,
+    BatteryLow,
+    BatteryCritical,
+    QualityChanged,
+}
+
+fn default_webhook_events() -> Vec<WebhookEvent> {
+    vec![WebhookEvent::Reading]
+}
+
+fn default_webhook_timeout() -> u64 {
+    HTTP_TIMEOUT
+}
+
+fn default_webhook_retries() -> u32 {
+    WEBHOOK_RETRIES
+}
+
+fn default_webhook_retry_delay() -> u64 {
+    WEBHOOK_RETRY_DELAY
+}
+
+fn default_webhook_signature_header() -> String {
+    WEBHOOK_SIGNATURE_HEADER.to_string()
+}
+
+/// Posts readings and node events to the configured URLs.
+///
+/// Every request is delivered by its own task, so a slow endpoint does not hold back
+/// the radio. Failed requests are retried `retries` times, `retry_delay` seconds apart.
+pub struct WebhookSender {
+    name: String,
+    conf: Webhook,
+    client: Client,
+    content_type: String,
+    nodes: HashMap<u8, NodeState>,
+}
+
+struct NodeState {
+    battery_low: bool,
+    battery_critical: bool,
+    quality: Quality,
+}
+
+struct Delivery {
+    sink: String,
+    client: Client,
+    url: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    retries: u32,
+    retry_delay: Duration,
+}
+
+impl WebhookSender {
+    pub fn new(name: String, conf: &Webhook) -> Result<Self> {
+        for url in &conf.urls {
+            http::parse_url(url)?;
+        }
+        if conf.template.is_some() && conf.format != PayloadFormat::Native {
+            return Err(Error::new_config(format!(
+                "Webhook '{}' template needs the Native format",
+                name
+            )));
+        }
+        let content_type = match (&conf.content_type, conf.format.content_type()) {
+            (Some(content_type), _) => content_type.clone(),
+            (None, Some(content_type)) => content_type.to_string(),
+            (None, None) => WEBHOOK_CONTENT_TYPE.to_string(),
+        };
+        Ok(WebhookSender {
+            name,
+            conf: conf.clone(),
+            client: http::client_with_timeout(conf.timeout),
+            content_type,
+            nodes: HashMap::new(),
+        })
+    }
+
+    /// Events of the reading, state changes are compared with the previous reading.
+    fn events(&mut self, reading: &Reading) -> Vec<WebhookEvent> {
+        let state = NodeState {
+            battery_low: reading.battery.is_low(),
+            battery_critical: reading.battery.is_critical(),
+            quality: reading.quality,
+        };
+        let previous = self.nodes.insert(reading.node, state);
+        let mut events = vec![WebhookEvent::Reading];
+        let (was_low, was_critical) = match &previous {
+            Some(previous) => (previous.battery_low, previous.battery_critical),
+            None => (false, false),
+        };
+        if reading.battery.is_low() && !was_low {
+            events.push(WebhookEvent::BatteryLow);
+        }
+        if reading.battery.is_critical() && !was_critical {
+            events.push(WebhookEvent::BatteryCritical);
+        }
+        match &previous {
+            Some(previous) if previous.quality != reading.quality => {
+                events.push(WebhookEvent::QualityChanged)
+            }
+            _ => (),
+        }
+        events.retain(|event| self.conf.events.contains(event));
+        events
+    }
+
+    fn body(&self, reading: &Reading, event: WebhookEvent) -> Result<Vec<u8>> {
+        let mut records = match self.conf.format {
+            PayloadFormat::Native => return Ok(self.native_body(reading, event).into_bytes()),
+            _ => senml::records(reading),
+        };
+        records.push(Record::new(
+            "event",
+            SenmlValue::String(format!("{:?}", event)),
+        ));
+        match self.conf.format {
+            PayloadFormat::SenmlCbor => senml::to_cbor(&records),
+            _ => Ok(senml::to_json(&records)),
+        }
+    }
+
+    fn native_body(&self, reading: &Reading, event: WebhookEvent) -> String {
+        let mut values = Map::new();
+        values.insert("event".to_string(), Value::from(format!("{:?}", event)));
+        values.insert("node".to_string(), Value::from(reading.node));
+        values.insert(
+            "quality".to_string(),
+            Value::from(format!("{:?}", reading.quality).to_lowercase()),
+        );
+        values.insert(
+            "timestamp".to_string(),
+            Value::from(reading.timestamp.to_rfc3339()),
+        );
+        values.insert(
+            "timestamp_ms".to_string(),
+            Value::from(reading.timestamp.timestamp_millis()),
+        );
+        for (name, value) in reading.measurements() {
+            values.insert(name, Value::from(value));
+        }
+        match &self.conf.template {
+            Some(template) => render(template, &values),
+            None => Value::Object(values).to_string(),
+        }
+    }
+
+    fn signature(&self, body: &[u8]) -> Result<Option<String>> {
+        let secret = match &self.conf.secret {
+            Some(secret) => secret,
+            None => return Ok(None),
+        };
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+            .map_err(|_| Error::new_option("Invalid webhook secret"))?;
+        mac.update(body);
+        Ok(Some(format!(
+            "sha256={}",
+            hex::encode(mac.finalize().into_bytes())
+        )))
+    }
+}
+
+#[async_trait]
+impl Sink for WebhookSender {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn start(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn publish(&mut self, reading: &Reading) -> Result<()> {
+        for event in self.events(reading) {
+            let body = self.body(reading, event)?;
+            let mut headers: Vec<(String, String)> = self
+                .conf
+                .headers
+                .iter()
+                .map(|(name, value)| (name.clone(), value.clone()))
+                .collect();
+            headers.push(("Content-Type".to_string(), self.content_type.clone()));
+            if let Some(signature) = self.signature(&body)? {
+                headers.push((self.conf.signature_header.clone(), signature));
+            }
+            for url in &self.conf.urls {
+                let delivery = Delivery {
+                    sink: self.name.clone(),
+                    client: self.client.clone(),
+                    url: url.clone(),
+                    headers: headers.clone(),
+                    body: body.clone(),
+                    retries: self.conf.retries,
+                    retry_delay: Duration::from_secs(self.conf.retry_delay),
+                };
+                task::spawn(delivery.run());
+            }
+        }
+        Ok(())
+    }
+
+    async fn shutdown(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl Delivery {
+    async fn run(self) {
+        let mut attempt = 0;
+        loop {
+            match self.post().await {
+                Ok(_) => break,
+                Err(err) if attempt < self.retries => {
+                    attempt += 1;
+                    warn!(
+                        "Webhook {} to {} failed, retry {} of {}: {}",
+                        self.sink, self.url, attempt, self.retries, err
+                    );
+                    task::sleep(self.retry_delay).await;
+                }
+                Err(err) => {
+                    eprintln!("{}: {}", self.sink, err);
+                    error!("{}: {:?}", self.sink, err);
+                    break;
+                }
+            }
+        }
+    }
+
+    async fn post(&self) -> Result<()> {
+        let mut request = self.client.post(&self.url);
+        for (name, value) in &self.headers {
+            request = request.header(name.as_str(), value.as_str());
+        }
+        read_body(request.body_bytes(&self.body).await?).await?;
+        Ok(())
+    }
+}
+
+/// Replaces `{{ name }}` placeholders, strings are inserted without quotes.
+fn render(template: &str, values: &Map<String, Value>) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let end = match rest[start..].find("}}") {
+            Some(end) => start + end,
+            None => break,
+        };
+        out.push_str(&rest[..start]);
+        match values.get(rest[start + 2..end].trim()) {
+            Some(Value::String(value)) => out.push_str(value),
+            Some(value) => out.push_str(&value.to_string()),
+            None => out.push_str("null"),
+        }
+        rest = &rest[end + 2..];
+    }
+    out.push_str(rest);
+    out
+}
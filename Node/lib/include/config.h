@@ -5,9 +5,10 @@
 #define PACKET_DATA 0x08
 #define GATEWAY_ADDR 1
 #define CONFIG_TIMEOUT 1000
-#define CONFIG_LENGTH 5
+#define CONFIG_LENGTH 6
 #define ACK_TIMEOUT 100
 #define ACK_RETRY_COUNT 5
+#define COUNTER_COUNT 8
 
 #define SW_PORT GPIOA
 #define SW_PIN GPIO8
@@ -17,6 +18,7 @@ struct config {
     uint8_t dio_direction;
     uint8_t dio_value;
     uint8_t analog;
+    uint8_t dio_counter;
 };
 
 
@@ -27,6 +29,7 @@ struct __attribute__((__packed__)) data_t {
     int16_t temperature;
     uint32_t pressure;
     uint16_t humidity;
+    uint16_t counter_value[COUNTER_COUNT];
 };
 
 union packet_t {
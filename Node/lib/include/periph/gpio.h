@@ -1,4 +1,7 @@
 #include <libopencm3/stm32/gpio.h>
+#include <libopencm3/stm32/exti.h>
+#include <libopencm3/cm3/cortex.h>
+#include <libopencm3/cm3/nvic.h>
 
 #ifndef WEATHER_STATION_GPIO_H
 #define WEATHER_STATION_GPIO_H
@@ -17,8 +20,12 @@
 #define D6 GPIO6
 #define D7 GPIO7
 
-void gpio_dio_setup(uint8_t direction, uint8_t value);
+void gpio_dio_setup(uint8_t direction, uint8_t value, uint8_t counter);
 
 uint8_t read_gpio_value(void);
 
+void read_gpio_counters(uint16_t *counters);
+
+void gpio_counter_isr(void);
+
 #endif //WEATHER_STATION_GPIO_H
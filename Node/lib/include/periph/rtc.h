@@ -4,6 +4,7 @@
 #include <libopencm3/stm32/exti.h>
 #include <libopencm3/cm3/nvic.h>
 #include <stdint.h>
+#include <stdbool.h>
 
 #ifndef WEATHER_STATION_RTC_H
 #define WEATHER_STATION_RTC_H
@@ -14,4 +15,6 @@ void rtc_wakeup_setup(uint16_t seconds);
 
 void rtc_disable(void);
 
+bool rtc_wakeup_elapsed(void);
+
 #endif //WEATHER_STATION_RTC_H
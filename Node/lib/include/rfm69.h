@@ -51,4 +51,6 @@ bool rfm69_ack_received(uint8_t from_addr);
 
 void rfm69_get_data(struct rfm69_packet *packet);
 
+void rfm69_isr(void);
+
 #endif //WEATHER_STATION_RFM69_H
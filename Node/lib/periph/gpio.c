@@ -4,8 +4,15 @@ static int pin_mapping[] = {D0, D1, D2, D3, D4, D5, D6, D7};
 static unsigned int port_mapping[] = {GPIO_PORT1, GPIO_PORT1, GPIO_PORT1, GPIO_PORT2, GPIO_PORT2, GPIO_PORT2,
                                       GPIO_PORT2, GPIO_PORT2};
 
-void gpio_dio_setup(uint8_t direction, uint8_t value) {
+// Free-running, the gateway computes the pulses since the previous packet and handles
+// the wraparound, so a packet resent after a lost ACK is not counted twice.
+static volatile uint16_t pulse_count[GPIO_COUNT];
+static uint8_t counter_mask = 0;
+
+void gpio_dio_setup(uint8_t direction, uint8_t value, uint8_t counter) {
+    counter_mask = counter & ~direction;
     for (uint8_t i = 2; i < GPIO_COUNT; i++) {
+        exti_disable_request(pin_mapping[i]);
         if (direction & (1 << i)) {
             gpio_mode_setup(port_mapping[i], GPIO_MODE_OUTPUT, GPIO_PUPD_NONE, pin_mapping[i]);
             if (value & (1 << i)) {
@@ -17,7 +24,17 @@ void gpio_dio_setup(uint8_t direction, uint8_t value) {
             gpio_mode_setup(port_mapping[i], GPIO_MODE_INPUT, GPIO_PUPD_PULLDOWN, pin_mapping[i]);
         }
 
+        if (counter_mask & (1 << i)) {
+            exti_select_source(pin_mapping[i], port_mapping[i]);
+            exti_set_trigger(pin_mapping[i], EXTI_TRIGGER_RISING);
+            exti_reset_request(pin_mapping[i]);
+            exti_enable_request(pin_mapping[i]);
+        } else {
+            pulse_count[i] = 0;
+        }
     }
+    nvic_enable_irq(NVIC_EXTI2_3_IRQ);
+    nvic_enable_irq(NVIC_EXTI4_15_IRQ);
 }
 
 uint8_t read_gpio_value() {
@@ -30,3 +47,19 @@ uint8_t read_gpio_value() {
     return value;
 }
 
+void read_gpio_counters(uint16_t *counters) {
+    cm_disable_interrupts();
+    for (uint8_t i = 0; i < GPIO_COUNT; i++) {
+        counters[i] = pulse_count[i];
+    }
+    cm_enable_interrupts();
+}
+
+void gpio_counter_isr() {
+    for (uint8_t i = 2; i < GPIO_COUNT; i++) {
+        if ((counter_mask & (1 << i)) && exti_get_flag_status(pin_mapping[i])) {
+            exti_reset_request(pin_mapping[i]);
+            pulse_count[i]++;
+        }
+    }
+}
@@ -1,5 +1,7 @@
 #include "../include/periph/rtc.h"
 
+static volatile bool wakeup_elapsed = false;
+
 static void rtc_interrupt_setup(void);
 
 void rtc_setup() {
@@ -25,6 +27,7 @@ void rtc_setup() {
 }
 
 void rtc_wakeup_setup(uint16_t seconds) {
+    wakeup_elapsed = false;
     rtc_unlock();
     rtc_set_wakeup_time(seconds, RTC_CR_WUCLKSEL_SPRE);
     rtc_interrupt_setup();
@@ -50,7 +53,12 @@ static void rtc_interrupt_setup() {
     exti_enable_request(EXTI20);
 }
 
+bool rtc_wakeup_elapsed() {
+    return wakeup_elapsed;
+}
+
 void rtc_isr() {
     rtc_clear_wakeup_flag();
     exti_reset_request(EXTI20);
+    wakeup_elapsed = true;
 }
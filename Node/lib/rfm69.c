@@ -369,7 +369,7 @@ static void rfm69_interrupt_setup() {
 }
 
 static void rfm69_interrupt_disable() {
-    nvic_disable_irq(RFM69_NVIC_IRQ);
+    // NVIC line is shared with GPIO pulse counters, mask only the EXTI line
     exti_disable_request(RFM69_EXTI_LINE);
 }
 
@@ -382,7 +382,9 @@ static void spi_chip_unselect() {
     gpio_set(RFM69_PORT, RFM69_NSS);
 }
 
-void exti4_15_isr() {
-    exti_reset_request(RFM69_EXTI_LINE);
-    current_state.data_available = true;
+void rfm69_isr() {
+    if (exti_get_flag_status(RFM69_EXTI_LINE)) {
+        exti_reset_request(RFM69_EXTI_LINE);
+        current_state.data_available = true;
+    }
 }
@@ -18,7 +18,8 @@ static struct config conf = {
         .sleep_time = 10,
         .dio_direction = 0x00,
         .dio_value = 0x00,
-        .analog = BAT_CHANNEL
+        .analog = BAT_CHANNEL,
+        .dio_counter = 0x00
 };
 
 static void clock_setup(void);
@@ -58,6 +59,7 @@ static void clock_setup() {
     rcc_periph_clock_enable(USART_CLOCK);
     rcc_periph_clock_enable(ADC_CLOCK);
     rcc_periph_clock_enable(I2C_CLOCK);
+    rcc_periph_clock_enable(RCC_SYSCFG);
 }
 
 
@@ -104,12 +106,15 @@ static void setup() {
     adc_setup();
     i2c_setup();
     bme_setup();
-    gpio_dio_setup(conf.dio_direction, conf.dio_value);
+    gpio_dio_setup(conf.dio_direction, conf.dio_value, conf.dio_counter);
 }
 
 static void deep_sleep() {
     before_sleep();
-    enter_stop_mode();
+    // Pulse counters wake the MCU as well, go back to sleep until RTC wakeup
+    do {
+        enter_stop_mode();
+    } while (!rtc_wakeup_elapsed());
     after_wakeup();
 }
 
@@ -155,9 +160,10 @@ static void update_config() {
         conf.dio_direction = packet.data_buffer[3];
         conf.dio_value = packet.data_buffer[4];
         conf.analog = (packet.data_buffer[5] & 0x07) | BAT_CHANNEL;
+        conf.dio_counter = packet.data_buffer[6];
 
         // Call gpio_setup
-        gpio_dio_setup(conf.dio_direction, conf.dio_value);
+        gpio_dio_setup(conf.dio_direction, conf.dio_value, conf.dio_counter);
         printf("Got new config!\n");
     }
 }
@@ -166,6 +172,7 @@ static void send_measured_data() {
     printf("Sending measured data!\n");
     union packet_t packet = {0};
     uint16_t adc[4] = {0};
+    uint16_t counters[COUNTER_COUNT] = {0};
 
     bme_start_measurement();
     gpio_set(SW_PORT, SW_PIN);
@@ -193,6 +200,11 @@ static void send_measured_data() {
 
     packet.data.humidity = bme_get_humidity();
 
+    read_gpio_counters(counters);
+    for (uint8_t i = 0; i < COUNTER_COUNT; i++) {
+        packet.data.counter_value[i] = counters[i];
+    }
+
 
     for (uint8_t i = 0; i < ACK_RETRY_COUNT; i++) {
         rfm69_send(GATEWAY_ADDR, &packet.bytes, sizeof(union packet_t), true);
@@ -208,6 +220,15 @@ static void send_measured_data() {
     }
 }
 
+void exti2_3_isr() {
+    gpio_counter_isr();
+}
+
+void exti4_15_isr() {
+    rfm69_isr();
+    gpio_counter_isr();
+}
+
 int main(void) {
     setup();
     delay(1000);
@@ -31,4 +31,4 @@ echo "Sleep for 10 secs to give home assistant chance to properly start"
 sleep 10
 
 echo "Starting proxy container..."
-PROXY_ID="$($CONTAINER_CMD run -d --rm --name="proxy" --net=container:$MQTT_ID --device /dev/spidev0.0 --device /dev/gpiochip0 -v $PROJECT_PATH/$SOURCE/proxy/conf:/proxy/conf -v $PROJECT_PATH/$SOURCE/proxy/log:/proxy/log $PROXY_IMAGE)"
+PROXY_ID="$($CONTAINER_CMD run -d --rm --name="proxy" --net=container:$MQTT_ID --device /dev/spidev0.0 --device /dev/gpiochip0 -v $PROJECT_PATH/$SOURCE/proxy/conf:/proxy/conf -v $PROJECT_PATH/$SOURCE/proxy/log:/proxy/log -v $PROJECT_PATH/$SOURCE/proxy/data:/proxy/data $PROXY_IMAGE)"